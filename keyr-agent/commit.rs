@@ -18,34 +18,28 @@
  */
 
 use anyhow::Result;
-use chrono::{Local, TimeZone, Utc};
+use chrono::{TimeZone, Utc};
 use reqwest::blocking::Client;
 
-use kas::SqliteConnection;
+use kas::{Calendar, SqliteConnection};
 use keyr_agentstorage as kas;
 use keyr_types::{KeystrokesStats, Summary, SynchronizeRequest};
 
 use crate::config::HubConfig;
+use crate::hub;
 
 fn commit_inner(
     conn : &SqliteConnection,
-    url : &str,
-    token : &str,
+    client : &Client,
+    hub : &HubConfig,
     sa : KeystrokesStats,
-) -> Result<()> {
-    let client = Client::new();
-
-    let today = Local::today().and_hms(0, 0, 0).naive_utc();
-
-    let req = SynchronizeRequest {
-        staging_area : sa,
-        today : today.timestamp(),
-    };
+) -> Result<i64> {
+    let req = SynchronizeRequest { staging_area : sa };
 
     let resp = client
-        .post(&format!("{}/commit", url))
+        .post(&hub::endpoint(hub, "/commit"))
         .json(&req)
-        .header("Keyr-Token", token)
+        .header("Keyr-Token", &hub.api_token)
         .send()?;
 
     if resp.status().is_success() {
@@ -59,16 +53,38 @@ fn commit_inner(
             resp.today_count,
         )?;
 
-        Ok(())
+        Ok(resp.today_timestamp)
     } else {
         panic!() // FIXME
     }
 }
 
-pub fn run(conn : &SqliteConnection, hub : &HubConfig) -> Result<()> {
-    kas::commit(&conn, |sa| {
-        commit_inner(&conn, &hub.hub_url, &hub.api_token, sa)
-    })?;
+pub fn run(
+    conn : &SqliteConnection,
+    hub : &HubConfig,
+    calendar : &Calendar,
+) -> Result<()> {
+    let client = Client::new();
+
+    hub::check_compatibility(&client, hub)?;
+    hub::sync_calendar(&client, hub, calendar)?;
+
+    let before = calendar.today(Utc::now());
+    let today = kas::commit(&conn, |sa| commit_inner(&conn, &client, hub, sa))?;
+    let expected = calendar.today(Utc::now());
+
+    // The keystrokes have been committed at this point, but the count of the
+    // day sent by the hub cannot be used by the agent if they do not agree on
+    // when the day began (the day may have changed during the commit).
+    if today != before.timestamp() && today != expected.timestamp() {
+        bail!(
+            "keyr-hub started the day at {} but the agent at {}, so the \
+             count of the day cannot be trusted until both use the same \
+             calendar",
+            Utc.timestamp(today, 0),
+            expected,
+        );
+    }
 
     Ok(())
 }
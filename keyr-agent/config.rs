@@ -18,9 +18,12 @@
  */
 
 use anyhow::Result;
+use chrono_tz::Tz;
 use serde::Deserialize;
 use std::path::{Path, PathBuf};
 
+use keyr_agentstorage::Calendar;
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct LocalConfig {
     pub database_path : PathBuf,
@@ -34,6 +37,13 @@ pub struct HubConfig {
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct AgentConfig {
+    // The timezone days are computed in (e.g., `Europe/Paris'), the one of
+    // the system by default
+    timezone : Option<String>,
+    // The hour days begin at, midnight by default. The calendar of the user on
+    // the hub (see `/me/calendar') is updated to match on each commit.
+    #[serde(default)]
+    day_start : u32,
     local : Option<LocalConfig>,
     hub : Option<HubConfig>,
 }
@@ -41,6 +51,8 @@ pub struct AgentConfig {
 impl AgentConfig {
     pub fn default() -> AgentConfig {
         AgentConfig {
+            timezone : None,
+            day_start : 0,
             local : None,
             hub : None,
         }
@@ -86,6 +98,25 @@ impl AgentConfig {
         }
     }
 
+    pub fn calendar(&self) -> Result<Calendar> {
+        let tz = match &self.timezone {
+            Some(tz) => match tz.parse::<Tz>() {
+                Ok(tz) => Some(tz),
+                Err(_) => bail!("Unknown timezone {}.", tz),
+            },
+            None => None,
+        };
+
+        if self.day_start >= 24 {
+            bail!("Days cannot begin at {} o'clock.", self.day_start);
+        }
+
+        Ok(Calendar {
+            tz,
+            day_start : self.day_start,
+        })
+    }
+
     pub fn hub_config(&self) -> Result<HubConfig> {
         match &self.hub {
             Some(hub) => Ok(hub.clone()),
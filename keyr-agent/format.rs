@@ -3,7 +3,7 @@ use num_format::{SystemLocale, ToFormattedString};
 use serde_json::Value;
 use tinytemplate::TinyTemplate;
 
-use kas::SqliteConnection;
+use kas::{Calendar, SqliteConnection};
 use keyr_agentstorage as kas;
 
 use crate::cli::Output;
@@ -28,10 +28,14 @@ fn num_format_formatter(
     }
 }
 
-pub fn run(conn : &SqliteConnection, output : &Output) -> Result<()> {
+pub fn run(
+    conn : &SqliteConnection,
+    calendar : &Calendar,
+    output : &Output,
+) -> Result<()> {
     let res = json!({
         "global_count": kas::get_global_count(&conn)?,
-        "today_count": kas::get_today_count(&conn)?,
+        "today_count": kas::get_today_count(&conn, calendar)?,
     });
 
     match output {
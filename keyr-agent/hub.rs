@@ -0,0 +1,109 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use anyhow::Result;
+use chrono_tz::Tz;
+use reqwest::blocking::Client;
+use reqwest::StatusCode;
+
+use keyr_agentstorage::Calendar;
+use keyr_types::{UserCalendar, VersionInfo, PROTOCOL_VERSION};
+
+use crate::config::HubConfig;
+
+// The URL of `route' in the version of the hub API implemented by the agent.
+pub fn endpoint(hub : &HubConfig, route : &str) -> String {
+    format!("{}/v{}{}", hub.hub_url, PROTOCOL_VERSION, route)
+}
+
+// Refuse to talk to a hub which does not implement the version of the API
+// the agent has been built against.
+pub fn check_compatibility(client : &Client, hub : &HubConfig) -> Result<()> {
+    let resp = client.get(&format!("{}/version", hub.hub_url)).send()?;
+
+    if resp.status() == StatusCode::NOT_FOUND {
+        bail!(
+            "keyr-hub at {} is too old to be used by this agent",
+            hub.hub_url
+        );
+    }
+
+    let info : VersionInfo = resp.error_for_status()?.json()?;
+
+    if !info.protocols.contains(&PROTOCOL_VERSION) {
+        bail!(
+            "keyr-hub {} at {} does not support the protocol version {} \
+             (supported versions: {:?})",
+            info.version,
+            hub.hub_url,
+            PROTOCOL_VERSION,
+            info.protocols,
+        );
+    }
+
+    Ok(())
+}
+
+// The calendar of the agent as the hub describes it, which requires to name
+// the timezone of the system when none has been configured.
+fn user_calendar(calendar : &Calendar) -> Result<UserCalendar> {
+    let timezone = match calendar.tz {
+        Some(tz) => tz.name().to_owned(),
+        None => match iana_time_zone::get_timezone() {
+            Ok(tz) if tz.parse::<Tz>().is_ok() => tz,
+            _ => bail!(
+                "Cannot name the timezone of the system, please set \
+                 `timezone' in the configuration of the agent."
+            ),
+        },
+    };
+
+    Ok(UserCalendar {
+        timezone,
+        day_start : calendar.day_start,
+    })
+}
+
+// Make the hub compute days the way the agent does, since it is the hub
+// which tells the agent how many keystrokes have been recorded today.
+pub fn sync_calendar(
+    client : &Client,
+    hub : &HubConfig,
+    calendar : &Calendar,
+) -> Result<()> {
+    let calendar = user_calendar(calendar)?;
+
+    let current : UserCalendar = client
+        .get(&endpoint(hub, "/me/calendar"))
+        .header("Keyr-Token", &hub.api_token)
+        .send()?
+        .error_for_status()?
+        .json()?;
+
+    if current != calendar {
+        client
+            .post(&endpoint(hub, "/me/calendar"))
+            .json(&calendar)
+            .header("Keyr-Token", &hub.api_token)
+            .send()?
+            .error_for_status()?;
+    }
+
+    Ok(())
+}
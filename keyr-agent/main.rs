@@ -30,6 +30,7 @@ pub mod cli;
 pub mod commit;
 pub mod config;
 pub mod format;
+pub mod hub;
 pub mod revert;
 pub mod stage;
 
@@ -46,9 +47,13 @@ fn main() -> Result<()> {
 
     match matches.subcommand() {
         ("stage", _) => stage::run(&conn)?,
-        ("commit", Some(_)) => commit::run(&conn, &conf.hub_config()?)?,
+        ("commit", Some(_)) => {
+            commit::run(&conn, &conf.hub_config()?, &conf.calendar()?)?
+        }
         ("revert", Some(_)) => revert::run(&conn, &conf.hub_config()?)?,
-        ("format", Some(m)) => format::run(&conn, &Output::from_matches(m))?,
+        ("format", Some(m)) => {
+            format::run(&conn, &conf.calendar()?, &Output::from_matches(m))?
+        }
         _ => println!("nothing to do"),
     }
 
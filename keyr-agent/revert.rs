@@ -18,51 +18,128 @@
  */
 
 use anyhow::Result;
-use chrono::{TimeZone, Timelike, Utc};
-use reqwest::blocking::Client;
+use chrono::{TimeZone, Utc};
+use reqwest::blocking::{Client, Response};
+use reqwest::StatusCode;
 
-use kas::SqliteConnection;
+use kas::{RevertProgress, SqliteConnection};
 use keyr_agentstorage as kas;
-use keyr_types::KeystrokesStats;
+use keyr_types::{RevertPage, RevertRequest, RevertSession};
 
 use crate::config::HubConfig;
+use crate::hub;
 
+fn initiate(
+    conn : &SqliteConnection,
+    client : &Client,
+    hub : &HubConfig,
+) -> Result<RevertProgress> {
+    let resp = client
+        .post(&hub::endpoint(hub, "/revert/initiate"))
+        .header("Keyr-Token", &hub.api_token)
+        .send()?;
+
+    if !resp.status().is_success() {
+        bail!("the hub refused to initiate a revert: {}", resp.text()?)
+    }
+
+    let session : RevertSession = resp.json()?;
+
+    Ok(kas::start_revert(conn, &session.session)?)
+}
+
+fn fetch_page(
+    client : &Client,
+    hub : &HubConfig,
+    progress : &RevertProgress,
+) -> Result<Response> {
+    let mut query = vec![("session", progress.session.clone())];
+
+    if let Some(cursor) = progress.cursor {
+        query.push(("after", cursor.timestamp().to_string()));
+    }
+
+    Ok(client
+        .get(&hub::endpoint(hub, "/revert/statistics"))
+        .header("Keyr-Token", &hub.api_token)
+        .query(&query)
+        .send()?)
+}
+
+// The body of the `404' answered by the hub when a session has expired, i.e.,
+// the message of `KeyrHubstorageError::UnknownRevertSession'. A `404' with
+// another body (e.g., a hub without the `/revert/statistics' route) is not
+// something starting the revert over can solve.
+const UNKNOWN_REVERT_SESSION : &str = "Unknown or expired revert session";
+
+// How many times a revert whose session has expired is started over before
+// giving up. A new session is renewed by every page fetched, so it is not
+// expected to expire as well.
+const MAX_RESTARTS : u32 = 1;
+
+// Fetch the statistics of the user page by page. Every page is saved locally
+// as soon as it is received, so that an interrupted revert is resumed where
+// it stopped, as long as its session has not expired in the meantime.
 pub fn run(conn : &SqliteConnection, hub : &HubConfig) -> Result<()> {
     let client = Client::new();
 
-    kas::transaction_retry(&conn, &|| {
-        let resp = client
-            .post(&format!("{}/revert/initiate", &hub.hub_url))
-            .header("Keyr-Token", &hub.api_token)
-            .send()?;
+    hub::check_compatibility(&client, hub)?;
 
-        if resp.status().is_success() {
-            let resp : KeystrokesStats = resp.json()?;
-
-            for (t, v) in resp {
-                let d = Utc.timestamp(t, 0);
-                kas::upsert_hour_count_in_transaction(
-                    conn,
-                    d.date(),
-                    d.hour(),
-                    v,
-                )?;
-            }
+    let mut progress = match kas::get_revert_progress(conn)? {
+        Some(progress) => progress,
+        None => initiate(conn, &client, hub)?,
+    };
 
-            kas::drop_summary(&conn)?;
+    let mut restarts = 0;
 
-            let resp = client
-                .post(&format!("{}/revert/terminate", &hub.hub_url))
-                .header("Keyr-Token", &hub.api_token)
-                .send()?;
+    loop {
+        let resp = fetch_page(&client, hub, &progress)?;
 
-            if resp.status().is_success() {
-                Ok(())
-            } else {
-                panic!() // FIXME
+        if resp.status() == StatusCode::NOT_FOUND {
+            let reason = resp.text()?;
+
+            // The session of an interrupted revert has expired, and the
+            // pages received so far may be outdated
+            if reason == UNKNOWN_REVERT_SESSION && restarts < MAX_RESTARTS {
+                restarts += 1;
+                kas::abort_revert(conn)?;
+                progress = initiate(conn, &client, hub)?;
+                continue;
             }
+
+            bail!("the hub refused to send the statistics: {}", reason)
+        }
+
+        if !resp.status().is_success() {
+            bail!("the hub refused to send the statistics: {}", resp.text()?)
+        }
+
+        let page : RevertPage = resp.json()?;
+        let next = page.next.map(|next| Utc.timestamp(next, 0));
+
+        kas::save_revert_page(conn, &page.statistics, next)?;
+
+        match next {
+            Some(next) => progress.cursor = Some(next),
+            None => break,
+        }
+    }
+
+    kas::transaction_retry(conn, &|| {
+        kas::finish_revert_in_transaction(conn)?;
+
+        let resp = client
+            .post(&hub::endpoint(hub, "/revert/terminate"))
+            .header("Keyr-Token", &hub.api_token)
+            .json(&RevertRequest {
+                session : progress.session.clone(),
+            })
+            .send()?;
+
+        if resp.status().is_success() {
+            Ok(())
         } else {
-            panic!() // FIXME
+            bail!("the hub refused to terminate the revert: {}", resp.text()?)
         }
     })
 }
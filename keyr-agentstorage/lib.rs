@@ -22,7 +22,12 @@ extern crate diesel;
 #[macro_use]
 extern crate diesel_migrations;
 
-use chrono::{Date, DateTime, Local, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono::{
+    Date, DateTime, Duration as ChronoDuration, Local, NaiveDateTime, TimeZone,
+    Timelike, Utc,
+};
+use chrono_tz::Tz;
+use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
 use diesel::result::Error;
 pub use diesel::sqlite::SqliteConnection;
@@ -36,6 +41,8 @@ use std::time::Duration;
 mod migrations;
 mod schema;
 
+use schema::revert_progress as rp;
+use schema::revert_staging as rs;
 use schema::staging_area as sa;
 use schema::summary;
 
@@ -70,8 +77,40 @@ where
     }
 }
 
-pub fn get_today_count(conn : &SqliteConnection) -> Result<u64, Error> {
-    let today = Local::today().and_hms(0, 0, 0).naive_utc();
+// How the user splits time into days: they begin at `day_start' o'clock in
+// the timezone `tz', or in the timezone of the system if `tz' is `None'.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Calendar {
+    pub tz : Option<Tz>,
+    pub day_start : u32,
+}
+
+impl Calendar {
+    // Compute the beginning of the day `now' belongs to
+    pub fn today(&self, now : DateTime<Utc>) -> DateTime<Utc> {
+        match self.tz {
+            Some(tz) => self.today_in(&tz, now),
+            None => self.today_in(&Local, now),
+        }
+    }
+
+    fn today_in<T : TimeZone>(
+        &self,
+        tz : &T,
+        now : DateTime<Utc>,
+    ) -> DateTime<Utc> {
+        let shift = ChronoDuration::hours(self.day_start as i64);
+        let day = (now.with_timezone(tz).naive_local() - shift).date();
+
+        keyr_types::to_utc(tz, day.and_hms(0, 0, 0) + shift)
+    }
+}
+
+pub fn get_today_count(
+    conn : &SqliteConnection,
+    calendar : &Calendar,
+) -> Result<u64, Error> {
+    let today = calendar.today(Utc::now()).naive_utc();
 
     transaction_retry(conn, &|| {
         let staging_count = sa::table
@@ -141,6 +180,9 @@ pub fn set_summary_in_transaction(
     Ok(())
 }
 
+// Hours are UTC hours, as on the hubs. With a timezone whose offset is not a
+// whole number of hours, the keystrokes of the hour a day begins in are
+// counted in the previous day.
 pub fn upsert_current_hour_count(
     conn : &SqliteConnection,
     count : u32,
@@ -244,6 +286,117 @@ fn drop_staging_area_in_transaction(
     Ok(())
 }
 
+// A revert whose pages are being received from a hub
+pub struct RevertProgress {
+    pub session : String,
+    // The cursor of the last page received, if any
+    pub cursor : Option<DateTime<Utc>>,
+}
+
+pub fn get_revert_progress(
+    conn : &SqliteConnection,
+) -> Result<Option<RevertProgress>, Error> {
+    transaction_retry(conn, &|| {
+        let progress = rp::table
+            .select((rp::session, rp::cursor))
+            .get_result::<(String, Option<NaiveDateTime>)>(conn)
+            .optional()?;
+
+        Ok(progress.map(|(session, cursor)| RevertProgress {
+            session,
+            cursor : cursor.map(|cursor| DateTime::from_utc(cursor, Utc)),
+        }))
+    })
+}
+
+fn clear_revert_in_transaction(conn : &SqliteConnection) -> Result<(), Error> {
+    diesel::delete(rs::table).execute(conn)?;
+    diesel::delete(rp::table).execute(conn)?;
+
+    Ok(())
+}
+
+// Forget about any previous revert, and start receiving the pages of
+// `session'.
+pub fn start_revert(
+    conn : &SqliteConnection,
+    session : &str,
+) -> Result<RevertProgress, Error> {
+    transaction_retry(conn, &|| {
+        clear_revert_in_transaction(conn)?;
+
+        diesel::insert_into(rp::table)
+            .values(vec![(
+                rp::session.eq(session),
+                rp::cursor.eq(None::<NaiveDateTime>),
+            )])
+            .execute(conn)?;
+
+        Ok(RevertProgress {
+            session : session.to_owned(),
+            cursor : None,
+        })
+    })
+}
+
+// Keep a page received from a hub aside, along with the cursor of the next
+// one, so that an interrupted revert can be resumed from there. Receiving
+// the same page twice is harmless.
+pub fn save_revert_page(
+    conn : &SqliteConnection,
+    page : &KeystrokesStats,
+    next : Option<DateTime<Utc>>,
+) -> Result<(), Error> {
+    let rows = page
+        .iter()
+        .map(|(t, v)| {
+            (
+                rs::timestamp.eq(Utc.timestamp(*t, 0).naive_utc()),
+                rs::count.eq(*v as i32),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    transaction_retry(conn, &|| {
+        if !rows.is_empty() {
+            diesel::replace_into(rs::table)
+                .values(&rows)
+                .execute(conn)?;
+        }
+
+        diesel::update(rp::table)
+            .set(rp::cursor.eq(next.map(|next| next.naive_utc())))
+            .execute(conn)?;
+
+        Ok(())
+    })
+}
+
+// Add the statistics received from a hub to the staging area, once every
+// page has been received. Needs to be called from within a transaction.
+pub fn finish_revert_in_transaction(
+    conn : &SqliteConnection,
+) -> Result<(), Error> {
+    conn.batch_execute(
+        "UPDATE staging_area \
+         SET count = count + (SELECT r.count FROM revert_staging r \
+                              WHERE r.timestamp = staging_area.timestamp) \
+         WHERE timestamp IN (SELECT timestamp FROM revert_staging); \
+         INSERT INTO staging_area (timestamp, count) \
+         SELECT timestamp, count FROM revert_staging \
+         WHERE timestamp NOT IN (SELECT timestamp FROM staging_area);",
+    )?;
+
+    clear_revert_in_transaction(conn)?;
+    drop_summary(conn)?;
+
+    Ok(())
+}
+
+pub fn abort_revert(conn : &SqliteConnection) -> Result<(), Error> {
+    transaction_retry(conn, &|| clear_revert_in_transaction(conn))
+}
+
 pub fn commit<A, E, K>(conn : &SqliteConnection, k : K) -> Result<A, Error>
 where
     K : Fn(KeystrokesStats) -> Result<A, E>,
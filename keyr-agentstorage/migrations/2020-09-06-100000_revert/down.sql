@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE revert_staging;
+DROP TABLE revert_progress;
@@ -0,0 +1,11 @@
+-- The revert being applied, and the cursor of the last page received
+CREATE TABLE revert_progress (
+    session TEXT PRIMARY KEY NOT NULL,
+    cursor DATETIME
+);
+
+-- The statistics received from the hub, until every page has been received
+CREATE TABLE revert_staging (
+    timestamp DATETIME PRIMARY KEY NOT NULL,
+    count INTEGER UNSIGNED NOT NULL
+);
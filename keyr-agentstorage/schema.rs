@@ -1,3 +1,17 @@
+table! {
+    revert_progress (session) {
+        session -> Text,
+        cursor -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    revert_staging (timestamp) {
+        timestamp -> Timestamp,
+        count -> Integer,
+    }
+}
+
 table! {
     staging_area (timestamp) {
         timestamp -> Timestamp,
@@ -12,4 +26,9 @@ table! {
     }
 }
 
-allow_tables_to_appear_in_same_query!(staging_area, summary,);
+allow_tables_to_appear_in_same_query!(
+    revert_progress,
+    revert_staging,
+    staging_area,
+    summary,
+);
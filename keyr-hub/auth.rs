@@ -18,14 +18,17 @@
  */
 
 use actix_web::dev::Payload;
+use actix_web::http::header::{HeaderMap, AUTHORIZATION};
 use actix_web::{FromRequest, HttpRequest};
-use futures::future::{err, ok, Ready};
+use futures::future::{ready, Ready};
 
 use kbs::users::Token;
 use keyr_hubstorage as kbs;
 
 use crate::error::KeyrHubError;
 
+const KEYR_TOKEN_HEADER : &str = "keyr-token";
+
 pub struct TokenHeader(Token);
 
 impl TokenHeader {
@@ -34,18 +37,60 @@ impl TokenHeader {
     }
 }
 
+// Extract the token carried by an `Authorization: Bearer <token>' header, if
+// any.
+fn bearer_token(headers : &HeaderMap) -> Result<Option<String>, KeyrHubError> {
+    let value = match headers.get(AUTHORIZATION) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    let malformed = || KeyrHubError::MalformedTokenHeader("Authorization");
+
+    let value = value.to_str().map_err(|_| malformed())?.trim();
+    let mut parts = value.splitn(2, ' ');
+
+    match (parts.next(), parts.next().map(str::trim)) {
+        (Some(scheme), Some(token))
+            if scheme.eq_ignore_ascii_case("bearer")
+                && !token.is_empty()
+                && !token.contains(char::is_whitespace) =>
+        {
+            Ok(Some(token.to_owned()))
+        }
+        _ => Err(malformed()),
+    }
+}
+
+// Extract the token carried by the legacy `keyr-token' header, if any.
+fn keyr_token(headers : &HeaderMap) -> Result<Option<String>, KeyrHubError> {
+    let value = match headers.get(KEYR_TOKEN_HEADER) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    match value.to_str().map(str::trim) {
+        Ok(token) if !token.is_empty() => Ok(Some(token.to_owned())),
+        _ => Err(KeyrHubError::MalformedTokenHeader("Keyr-Token")),
+    }
+}
+
+pub fn token_from_headers(headers : &HeaderMap) -> Result<Token, KeyrHubError> {
+    match (bearer_token(headers)?, keyr_token(headers)?) {
+        (Some(bearer), Some(legacy)) if bearer != legacy => {
+            Err(KeyrHubError::ConflictingTokenHeaders)
+        }
+        (Some(token), _) | (None, Some(token)) => Ok(Token(token)),
+        (None, None) => Err(KeyrHubError::MissingTokenHeader),
+    }
+}
+
 impl FromRequest for TokenHeader {
     type Config = ();
     type Error = KeyrHubError;
     type Future = Ready<Result<TokenHeader, KeyrHubError>>;
 
     fn from_request(req : &HttpRequest, _pl : &mut Payload) -> Self::Future {
-        if let Some(token) = req.headers().get("keyr-token") {
-            let token = token.to_str().unwrap_or("").to_owned();
-
-            ok(TokenHeader(Token(token)))
-        } else {
-            err(KeyrHubError::MissingKeyrTokenHeader)
-        }
+        ready(token_from_headers(req.headers()).map(TokenHeader))
     }
 }
@@ -0,0 +1,116 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tinytemplate::TinyTemplate;
+
+use keyr_types::Period;
+
+static TEMPLATE : &str = include_str!("templates/badge.svg");
+
+// Rough average width of a character of Verdana 11px, and horizontal padding
+// of each half of the badge
+const CHAR_WIDTH : u32 = 7;
+const PADDING : u32 = 10;
+
+#[derive(Deserialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    Total,
+    Today,
+    Week,
+}
+
+impl Metric {
+    pub fn period(&self) -> Period {
+        match self {
+            Metric::Total => Period::All,
+            Metric::Today => Period::Day,
+            Metric::Week => Period::Week,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Metric::Total => "keystrokes",
+            Metric::Today => "keystrokes today",
+            Metric::Week => "keystrokes this week",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Badge<'a> {
+    label : &'a str,
+    value : String,
+    width : u32,
+    label_width : u32,
+    value_width : u32,
+    label_x : u32,
+    value_x : u32,
+}
+
+// Format a count the way shields.io does, e.g., 1234567 becomes `1.2M'.
+fn format_count(count : u64) -> String {
+    let suffixes = [(1_000_000_000, "G"), (1_000_000, "M"), (1_000, "k")];
+
+    for (unit, suffix) in suffixes.iter() {
+        if count >= *unit {
+            let (int, frac) = (count / unit, count * 10 / unit % 10);
+
+            return if frac == 0 || int >= 100 {
+                format!("{}{}", int, suffix)
+            } else {
+                format!("{}.{}{}", int, frac, suffix)
+            };
+        }
+    }
+
+    count.to_string()
+}
+
+fn text_width(text : &str) -> u32 {
+    text.chars().count() as u32 * CHAR_WIDTH + PADDING
+}
+
+pub fn render(
+    metric : Metric,
+    count : u64,
+) -> Result<String, tinytemplate::error::Error> {
+    let label = metric.label();
+    let value = format_count(count);
+    let label_width = text_width(label);
+    let value_width = text_width(&value);
+
+    let badge = Badge {
+        label,
+        width : label_width + value_width,
+        label_x : label_width / 2,
+        value_x : label_width + value_width / 2,
+        label_width,
+        value_width,
+        value,
+    };
+
+    let mut tt = TinyTemplate::new();
+    tt.add_template("badge", TEMPLATE)?;
+
+    tt.render("badge", &badge)
+}
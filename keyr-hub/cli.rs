@@ -17,7 +17,30 @@
  * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use clap::{App, Arg};
+use anyhow::{anyhow, bail, Result};
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::config::{HttpConfig, MetricsConfig, TlsConfig};
+
+// An option overriding a key of the configuration file, which can also be
+// set with an environment variable
+fn config_option(
+    name : &'static str,
+    long : &'static str,
+    env : &'static str,
+    value_name : &'static str,
+    help : &'static str,
+) -> Arg<'static, 'static> {
+    Arg::with_name(name)
+        .long(long)
+        .env(env)
+        .value_name(value_name)
+        .help(help)
+        .takes_value(true)
+}
 
 pub fn get_app() -> App<'static, 'static> {
     App::new("keyr-hub")
@@ -31,4 +54,187 @@ pub fn get_app() -> App<'static, 'static> {
                 .value_name("FILE")
                 .required(true),
         )
+        .arg(
+            config_option(
+                "bind",
+                "bind",
+                "KEYR_HUB_BIND",
+                "ADDRESS",
+                "An address to listen to, replacing the ones of the \
+                 configuration file",
+            )
+            .multiple(true)
+            .number_of_values(1)
+            .use_delimiter(true),
+        )
+        .arg(config_option(
+            "unix_socket",
+            "unix-socket",
+            "KEYR_HUB_UNIX_SOCKET",
+            "PATH",
+            "A Unix domain socket to listen to",
+        ))
+        .arg(config_option(
+            "tls_certificate",
+            "tls-certificate",
+            "KEYR_HUB_TLS_CERTIFICATE",
+            "FILE",
+            "The PEM certificate chain to serve HTTPS with",
+        ))
+        .arg(config_option(
+            "tls_key",
+            "tls-key",
+            "KEYR_HUB_TLS_KEY",
+            "FILE",
+            "The PEM private key to serve HTTPS with",
+        ))
+        .arg(config_option(
+            "workers",
+            "workers",
+            "KEYR_HUB_WORKERS",
+            "COUNT",
+            "The number of workers",
+        ))
+        .arg(config_option(
+            "keep_alive",
+            "keep-alive",
+            "KEYR_HUB_KEEP_ALIVE",
+            "SECONDS",
+            "How long an idle connection is kept open (0 disables keep-alive)",
+        ))
+        .arg(config_option(
+            "max_request_size",
+            "max-request-size",
+            "KEYR_HUB_MAX_REQUEST_SIZE",
+            "BYTES",
+            "The maximum size of the body of a request",
+        ))
+        .arg(config_option(
+            "shutdown_timeout",
+            "shutdown-timeout",
+            "KEYR_HUB_SHUTDOWN_TIMEOUT",
+            "SECONDS",
+            "How long the workers have to finish their requests on shutdown",
+        ))
+        .arg(config_option(
+            "metrics_url",
+            "metrics-url",
+            "KEYR_HUB_METRICS_URL",
+            "HOST",
+            "The host the metrics listener listens to",
+        ))
+        .arg(config_option(
+            "metrics_port",
+            "metrics-port",
+            "KEYR_HUB_METRICS_PORT",
+            "PORT",
+            "The port the metrics listener listens to",
+        ))
+        .subcommand(SubCommand::with_name("compact").about(
+            "Apply the retention policy of the `[retention]' section once, \
+             then exit",
+        ))
+}
+
+fn number<T : FromStr>(
+    matches : &ArgMatches,
+    name : &str,
+) -> Result<Option<T>> {
+    match matches.value_of(name) {
+        Some(value) => value.parse::<T>().map(Some).map_err(|_| {
+            anyhow!("{} is not a valid value for `{}'", value, name)
+        }),
+        None => Ok(None),
+    }
+}
+
+// Override the `[http]' section of the configuration file with the command
+// line flags and the environment variables.
+pub fn override_http(
+    matches : &ArgMatches,
+    conf : &mut HttpConfig,
+) -> Result<()> {
+    if let Some(bind) = matches.values_of("bind") {
+        conf.bind = bind.map(String::from).collect();
+    }
+
+    if let Some(path) = matches.value_of("unix_socket") {
+        conf.unix_socket = Some(PathBuf::from(path));
+    }
+
+    let certificate = matches.value_of("tls_certificate").map(PathBuf::from);
+    let key = matches.value_of("tls_key").map(PathBuf::from);
+
+    match (&mut conf.tls, certificate, key) {
+        (_, Some(certificate), Some(key)) => {
+            conf.tls = Some(TlsConfig { certificate, key })
+        }
+        (Some(tls), certificate, key) => {
+            if let Some(certificate) = certificate {
+                tls.certificate = certificate;
+            }
+
+            if let Some(key) = key {
+                tls.key = key;
+            }
+        }
+        (None, None, None) => (),
+        (None, _, _) => {
+            bail!("A TLS certificate and its private key are both needed")
+        }
+    }
+
+    if let Some(workers) = number(matches, "workers")? {
+        conf.workers = Some(workers);
+    }
+
+    if let Some(keep_alive) = number(matches, "keep_alive")? {
+        conf.keep_alive = Some(keep_alive);
+    }
+
+    if let Some(max_request_size) = number(matches, "max_request_size")? {
+        conf.max_request_size = max_request_size;
+    }
+
+    if let Some(shutdown_timeout) = number(matches, "shutdown_timeout")? {
+        conf.shutdown_timeout = Some(shutdown_timeout);
+    }
+
+    Ok(())
+}
+
+// Override the `[metrics]' section of the configuration file with the
+// command line flags and the environment variables. Without this section,
+// both the host and the port are needed to enable the metrics listener.
+pub fn override_metrics(
+    matches : &ArgMatches,
+    conf : &mut Option<MetricsConfig>,
+) -> Result<()> {
+    let url = matches.value_of("metrics_url").map(String::from);
+    let port = number(matches, "metrics_port")?;
+
+    match (conf, url, port) {
+        (Some(metrics), url, port) => {
+            if let Some(url) = url {
+                metrics.url = url;
+            }
+
+            if let Some(port) = port {
+                metrics.port = port;
+            }
+        }
+        (conf, Some(url), Some(port)) => {
+            *conf = Some(MetricsConfig {
+                url,
+                port,
+                user_gauges : false,
+            })
+        }
+        (None, None, None) => (),
+        (None, _, _) => bail!(
+            "A host and a port are both needed to enable the metrics listener"
+        ),
+    }
+
+    Ok(())
 }
@@ -17,27 +17,470 @@
  * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use anyhow::Result;
-use serde::Deserialize;
-use std::path::Path;
+use anyhow::{bail, Context, Result};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use keyr_hubstorage::retention::{Retention, RetentionPolicy};
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseBackend {
+    Postgres,
+    Sqlite,
+}
+
+fn default_database_backend() -> DatabaseBackend {
+    DatabaseBackend::Postgres
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum SslMode {
+    Disable,
+    Allow,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+impl SslMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SslMode::Disable => "disable",
+            SslMode::Allow => "allow",
+            SslMode::Prefer => "prefer",
+            SslMode::Require => "require",
+            SslMode::VerifyCa => "verify-ca",
+            SslMode::VerifyFull => "verify-full",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct PoolConfig {
+    // The maximum number of connections (10 by default, 1 with SQLite)
+    pub max_size : Option<u32>,
+    // The number of idle connections the pool tries to keep open (`max_size'
+    // by default)
+    pub min_idle : Option<u32>,
+    // How long, in seconds, to wait for a connection before giving up (30 by
+    // default)
+    pub connection_timeout : Option<u64>,
+}
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct DatabaseConfig {
-    pub user : String,
+    #[serde(default = "default_database_backend")]
+    pub backend : DatabaseBackend,
+    // With PostgreSQL, either a complete connection URL (e.g.,
+    // `postgres://keyr@localhost/keyr?sslmode=require'), or the host and the
+    // name of the database (e.g., `localhost/keyr'); with SQLite, the path to
+    // the database file
+    pub url : Option<String>,
+    // The other keys are only used by PostgreSQL, and supersede what `url'
+    // says. `host' can be the directory of a Unix domain socket.
+    pub host : Option<String>,
+    pub port : Option<u16>,
+    pub dbname : Option<String>,
+    pub user : Option<String>,
     pub password : Option<String>,
-    pub url : String,
+    // A file whose first line is the password, in place of `password'
+    pub password_file : Option<PathBuf>,
+    pub sslmode : Option<SslMode>,
+    // Paths to PEM files: the certificate authorities the server certificate
+    // is checked against, and the certificate and key of the client
+    pub sslrootcert : Option<PathBuf>,
+    pub sslcert : Option<PathBuf>,
+    pub sslkey : Option<PathBuf>,
+    #[serde(default)]
+    pub pool : PoolConfig,
+}
+
+// Percent-encode everything but the unreserved characters of RFC 3986, so
+// that any value can be put in a URL
+fn percent_encode(value : &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl DatabaseConfig {
+    pub fn pool_size(&self) -> u32 {
+        match (self.pool.max_size, self.backend) {
+            (Some(max_size), _) => max_size,
+            // A SQLite database can only be written by one connection at a
+            // time, and every connection to `:memory:' opens a different
+            // database
+            (None, DatabaseBackend::Sqlite) => 1,
+            (None, DatabaseBackend::Postgres) => 10,
+        }
+    }
+
+    // r2d2 panics on these settings, rather than failing to build the pool
+    pub fn check_pool(&self) -> Result<()> {
+        if self.pool_size() == 0 {
+            bail!("`max_size' in the `[database.pool]' section cannot be 0");
+        }
+
+        if self.pool.min_idle.unwrap_or(0) > self.pool_size() {
+            bail!("`min_idle' cannot be greater than `max_size' in the `[database.pool]' section");
+        }
+
+        Ok(())
+    }
+
+    fn password(&self) -> Result<Option<String>> {
+        match (&self.password, &self.password_file) {
+            (Some(_), Some(_)) => {
+                bail!("`password' and `password_file' cannot be both set")
+            }
+            (Some(password), None) => Ok(Some(password.clone())),
+            (None, Some(path)) => {
+                let content =
+                    std::fs::read_to_string(path).with_context(|| {
+                        format!(
+                            "Cannot read the password from {}",
+                            path.display()
+                        )
+                    })?;
+
+                Ok(Some(content.lines().next().unwrap_or("").to_owned()))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    pub fn database_url(&self) -> Result<String> {
+        match self.backend {
+            DatabaseBackend::Postgres => self.postgres_url(),
+            DatabaseBackend::Sqlite => match &self.url {
+                Some(path) => Ok(format!("sqlite://{}", path)),
+                None => bail!("`url' is the path to the SQLite database"),
+            },
+        }
+    }
+
+    fn postgres_url(&self) -> Result<String> {
+        let mut url = match &self.url {
+            Some(url)
+                if url.starts_with("postgres://")
+                    || url.starts_with("postgresql://") =>
+            {
+                url.clone()
+            }
+            Some(url) => format!("postgres://{}", url),
+            None => "postgres://".to_owned(),
+        };
+
+        // Every other setting is passed as a parameter of the URL, where
+        // libpq expects it to be percent-encoded
+        let mut params = vec![];
+
+        let mut push = |key : &str, value : Option<String>| {
+            if let Some(value) = value {
+                params.push(format!("{}={}", key, percent_encode(&value)));
+            }
+        };
+
+        let path = |p : &Option<PathBuf>| {
+            p.as_ref().map(|p| p.to_string_lossy().into_owned())
+        };
+
+        push("host", self.host.clone());
+        push("port", self.port.map(|p| p.to_string()));
+        push("dbname", self.dbname.clone());
+        push("user", self.user.clone().filter(|u| !u.is_empty()));
+        push("password", self.password()?);
+        push("sslmode", self.sslmode.map(|m| m.as_str().to_owned()));
+        push("sslrootcert", path(&self.sslrootcert));
+        push("sslcert", path(&self.sslcert));
+        push("sslkey", path(&self.sslkey));
+
+        if !params.is_empty() {
+            url.push(if url.contains('?') { '&' } else { '?' });
+            url.push_str(&params.join("&"));
+        }
+
+        Ok(url)
+    }
+}
+
+fn default_cors_methods() -> Vec<String> {
+    vec!["GET".to_owned()]
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CorsConfig {
+    // Either a list of origins (e.g., `https://example.com'), or `*' to
+    // allow any origin
+    pub allowed_origins : Vec<String>,
+    #[serde(default = "default_cors_methods")]
+    pub allowed_methods : Vec<String>,
+    #[serde(default)]
+    pub allowed_headers : Vec<String>,
+    // How long, in seconds, browsers can cache the answer to a preflight
+    // request
+    pub max_age : Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TlsConfig {
+    // Paths to PEM files. The certificate file can contain the whole chain.
+    pub certificate : PathBuf,
+    pub key : PathBuf,
+}
+
+fn default_max_request_size() -> usize {
+    262_144
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct HttpConfig {
-    pub port : isize,
+    // The addresses to listen to, e.g., `127.0.0.1:8080' or `[::1]:8080'
+    #[serde(default)]
+    pub bind : Vec<String>,
+    // A single address to listen to, split into its host and its port. This
+    // is superseded by `bind', and only used when `bind' is empty.
+    pub url : Option<String>,
+    pub port : Option<u16>,
+    // The path of a Unix domain socket to listen to, alongside the addresses
+    pub unix_socket : Option<PathBuf>,
+    // Serve HTTPS instead of HTTP on the addresses (but not on the Unix
+    // domain socket)
+    pub tls : Option<TlsConfig>,
+    // The number of workers, one per CPU by default
+    pub workers : Option<usize>,
+    // How long, in seconds, an idle connection is kept open (`0' disables
+    // keep-alive)
+    pub keep_alive : Option<u64>,
+    // The maximum size, in bytes, of the body of a request
+    #[serde(default = "default_max_request_size")]
+    pub max_request_size : usize,
+    // How long, in seconds, the workers have to finish their requests when
+    // the hub is stopped
+    pub shutdown_timeout : Option<u64>,
+    pub cors : Option<CorsConfig>,
+}
+
+// IPv6 addresses need to be bracketed to be followed by a port
+fn socket_address(host : &str, port : u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+impl HttpConfig {
+    pub fn addresses(&self) -> Vec<String> {
+        if !self.bind.is_empty() {
+            return self.bind.clone();
+        }
+
+        match (&self.url, self.port) {
+            (Some(url), Some(port)) => vec![socket_address(url, port)],
+            _ => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MetricsConfig {
     pub url : String,
+    pub port : u16,
+    // Exposing the keystrokes count of every visible user is opt-in, since
+    // it grows the size of the metrics with the number of users
+    #[serde(default)]
+    pub user_gauges : bool,
+}
+
+impl MetricsConfig {
+    pub fn address(&self) -> String {
+        socket_address(&self.url, self.port)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled : bool,
+    // The length, in seconds, of the windows during which requests are
+    // counted
+    pub window : u64,
+    pub requests_per_ip : u32,
+    pub requests_per_token : u32,
+    // The number of invalid tokens a client can send during a window before
+    // being locked out for `lockout' seconds
+    pub failed_lookups : u32,
+    pub lockout : u64,
+    // The number of reverse proxies in front of the hub, each of them
+    // appending the address of its peer to the `X-Forwarded-For' header. The
+    // header is ignored when it is 0, since the clients set it otherwise.
+    pub trusted_proxies : u32,
+}
+
+// The limiter is disabled by default: behind a reverse proxy which is not
+// listed in `trusted_proxies', every client shares the address of the proxy,
+// and a single agent with a stale token would lock all of them out.
+impl Default for RateLimitConfig {
+    fn default() -> RateLimitConfig {
+        RateLimitConfig {
+            enabled : false,
+            window : 60,
+            requests_per_ip : 120,
+            requests_per_token : 60,
+            failed_lookups : 10,
+            lockout : 900,
+            trusted_proxies : 0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LogConfig {
+    // Either a level (e.g., `debug'), or a list of per-module directives
+    // (e.g., `info,keyr_hubstorage=debug')
+    pub level : String,
+    pub format : LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level : "info".to_owned(),
+            format : LogFormat::Text,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RevertConfig {
+    // How long, in seconds, an agent can keep a user frozen while reverting
+    // their statistics
+    pub lease : u64,
+}
+
+impl Default for RevertConfig {
+    fn default() -> RevertConfig {
+        RevertConfig { lease : 900 }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DaysOrNever {
+    Days(u32),
+    Keyword(String),
+}
+
+// Either a number of days, or `never' to lift the limit set by the
+// `[retention]' section
+fn days_or_never<'de, D>(de : D) -> Result<Option<Option<u32>>, D::Error>
+where
+    D : Deserializer<'de>,
+{
+    match DaysOrNever::deserialize(de)? {
+        DaysOrNever::Days(days) => Ok(Some(Some(days))),
+        DaysOrNever::Keyword(keyword) if keyword == "never" => Ok(Some(None)),
+        DaysOrNever::Keyword(keyword) => Err(D::Error::custom(format!(
+            "expected a number of days or `never', found `{}'",
+            keyword
+        ))),
+    }
+}
+
+// The keys of a user of the `[retention.users]' section, which default to
+// the ones of the `[retention]' section when they are missing
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(default)]
+pub struct RetentionPolicyConfig {
+    #[serde(deserialize_with = "days_or_never")]
+    pub hourly_days : Option<Option<u32>>,
+    #[serde(deserialize_with = "days_or_never")]
+    pub max_age_days : Option<Option<u32>>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RetentionConfig {
+    // How many days the hourly statistics are kept, before being rolled into
+    // daily statistics (forever by default)
+    pub hourly_days : Option<u32>,
+    // How many days the statistics are kept at all (forever by default)
+    pub max_age_days : Option<u32>,
+    // How often, in seconds, the hub applies the retention policy in the
+    // background (never by default, see `keyr-hub compact')
+    pub interval : Option<u64>,
+    // The users whose retention policy differs, keyed by their name
+    pub users : HashMap<String, RetentionPolicyConfig>,
+}
+
+impl RetentionConfig {
+    pub fn retention(&self) -> Retention {
+        let default = RetentionPolicy {
+            hourly_days : self.hourly_days,
+            max_age_days : self.max_age_days,
+        };
+
+        Retention {
+            default,
+            users : self
+                .users
+                .iter()
+                .map(|(name, policy)| {
+                    (
+                        name.clone(),
+                        RetentionPolicy {
+                            hourly_days : policy
+                                .hourly_days
+                                .unwrap_or(default.hourly_days),
+                            max_age_days : policy
+                                .max_age_days
+                                .unwrap_or(default.max_age_days),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct HubConfig {
     pub http : HttpConfig,
     pub database : DatabaseConfig,
+    pub metrics : Option<MetricsConfig>,
+    #[serde(default)]
+    pub rate_limit : RateLimitConfig,
+    #[serde(default)]
+    pub log : LogConfig,
+    #[serde(default)]
+    pub revert : RevertConfig,
+    #[serde(default)]
+    pub retention : RetentionConfig,
 }
 
 impl HubConfig {
@@ -46,17 +489,4 @@ impl HubConfig {
 
         Ok(res)
     }
-
-    pub fn database_url(&self) -> String {
-        format!(
-            "postgres://{}:{}@{}",
-            self.database.user,
-            self.database
-                .password
-                .as_ref()
-                .map(|x| x.clone())
-                .unwrap_or("".to_owned()),
-            self.database.url,
-        )
-    }
 }
@@ -0,0 +1,206 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+    ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use actix_web::http::Method;
+use actix_web::HttpResponse;
+use anyhow::Result;
+use futures::future::{ready, Either};
+use futures::{Future, FutureExt};
+
+use std::sync::Arc;
+
+use crate::config::CorsConfig;
+
+const ANY_ORIGIN : &str = "*";
+
+// The cross-origin policy of the public read routes. Without a `[http.cors]`
+// section, the hub does not send any CORS header, and browsers keep blocking
+// cross-origin calls.
+pub struct Cors {
+    any_origin : bool,
+    origins : Vec<String>,
+    methods : Vec<Method>,
+    headers : Vec<HeaderName>,
+    max_age : Option<u64>,
+}
+
+impl Cors {
+    pub fn new(conf : &CorsConfig) -> Result<Cors> {
+        let methods = conf
+            .allowed_methods
+            .iter()
+            .map(|m| Method::from_bytes(m.to_uppercase().as_bytes()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let headers = conf
+            .allowed_headers
+            .iter()
+            .map(|h| HeaderName::from_bytes(h.as_bytes()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        Ok(Cors {
+            any_origin : conf.allowed_origins.iter().any(|o| o == ANY_ORIGIN),
+            origins : conf.allowed_origins.clone(),
+            methods,
+            headers,
+            max_age : conf.max_age,
+        })
+    }
+
+    fn allows_origin(&self, origin : &str) -> bool {
+        self.any_origin || self.origins.iter().any(|o| o == origin)
+    }
+
+    // The origins are compared to the one of the request as is, so an
+    // invalid header value means the origin is not allowed.
+    fn allowed_origin(&self, headers : &HeaderMap) -> Option<HeaderValue> {
+        let origin = headers.get(ORIGIN)?;
+
+        if self.allows_origin(origin.to_str().ok()?) {
+            if self.any_origin {
+                Some(HeaderValue::from_static(ANY_ORIGIN))
+            } else {
+                Some(origin.clone())
+            }
+        } else {
+            None
+        }
+    }
+
+    fn allows_request_headers(&self, headers : &HeaderMap) -> bool {
+        let requested = match headers.get(ACCESS_CONTROL_REQUEST_HEADERS) {
+            Some(requested) => requested,
+            None => return true,
+        };
+
+        match requested.to_str() {
+            Ok(requested) => requested
+                .split(',')
+                .map(str::trim)
+                .filter(|h| !h.is_empty())
+                .all(|h| {
+                    self.headers
+                        .iter()
+                        .any(|a| a.as_str().eq_ignore_ascii_case(h))
+                }),
+            Err(_) => false,
+        }
+    }
+
+    fn allows_request_method(&self, headers : &HeaderMap) -> bool {
+        headers
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|m| Method::from_bytes(m.as_bytes()).ok())
+            .map(|m| self.methods.contains(&m))
+            .unwrap_or(false)
+    }
+
+    fn join<T : AsRef<str>>(items : &[T]) -> String {
+        items
+            .iter()
+            .map(|item| item.as_ref())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn preflight(&self, headers : &HeaderMap) -> HttpResponse {
+        let origin = match self.allowed_origin(headers) {
+            Some(origin) => origin,
+            None => return HttpResponse::Forbidden().finish(),
+        };
+
+        if !self.allows_request_method(headers)
+            || !self.allows_request_headers(headers)
+        {
+            return HttpResponse::Forbidden().finish();
+        }
+
+        let mut resp = HttpResponse::NoContent();
+
+        resp.header(ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+            .header(ACCESS_CONTROL_ALLOW_METHODS, Cors::join(&self.methods))
+            .header(VARY, "Origin");
+
+        if !self.headers.is_empty() {
+            resp.header(
+                ACCESS_CONTROL_ALLOW_HEADERS,
+                Cors::join(&self.headers),
+            );
+        }
+
+        if let Some(max_age) = self.max_age {
+            resp.header(ACCESS_CONTROL_MAX_AGE, max_age.to_string());
+        }
+
+        resp.finish()
+    }
+}
+
+fn is_preflight(req : &ServiceRequest) -> bool {
+    req.method() == Method::OPTIONS
+        && req.headers().contains_key(ORIGIN)
+        && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+// The middleware answering the preflight requests, and adding the CORS
+// headers to the responses of the cross-origin requests.
+pub fn apply<S>(
+    cors : Option<Arc<Cors>>,
+    req : ServiceRequest,
+    srv : &mut S,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>>
+where
+    S : Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+    >,
+{
+    let cors = match cors {
+        Some(cors) => cors,
+        None => return Either::Left(srv.call(req)),
+    };
+
+    if is_preflight(&req) {
+        let resp = cors.preflight(req.headers());
+
+        return Either::Right(Either::Left(ready(Ok(req.into_response(resp)))));
+    }
+
+    let origin = cors.allowed_origin(req.headers());
+
+    Either::Right(Either::Right(srv.call(req).map(move |res| {
+        res.map(|mut res| {
+            if let Some(origin) = origin {
+                let headers = res.headers_mut();
+
+                headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+                headers.append(VARY, HeaderValue::from_static("Origin"));
+            }
+
+            res
+        })
+    })))
+}
@@ -17,16 +17,25 @@
  * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use diesel::r2d2::{ConnectionManager, Pool};
-use diesel::PgConnection;
+use diesel::r2d2::Pool;
 
+use keyr_hubstorage::connection::{HubConnectionManager, HubPool};
+
+use std::time::Duration;
+
+use crate::config::DatabaseConfig;
 use crate::error::Result;
 
-pub type PgConnectionManager = ConnectionManager<PgConnection>;
-pub type PgPool = Pool<PgConnectionManager>;
+pub fn create_pool(conf : &DatabaseConfig, url : &str) -> Result<HubPool> {
+    let mut builder = Pool::builder()
+        .max_size(conf.pool_size())
+        .min_idle(conf.pool.min_idle);
+
+    if let Some(timeout) = conf.pool.connection_timeout {
+        builder = builder.connection_timeout(Duration::from_secs(timeout));
+    }
 
-pub fn create_pool(path : &str) -> Result<PgPool> {
-    let pool = Pool::builder().build(PgConnectionManager::new(path))?;
+    let pool = builder.build(HubConnectionManager::new(url))?;
 
     Ok(pool)
 }
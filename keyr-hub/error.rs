@@ -18,7 +18,8 @@
  */
 
 use actix_web::error::ResponseError;
-use actix_web::http::StatusCode;
+use actix_web::http::{header, StatusCode};
+use actix_web::HttpResponse;
 use thiserror::Error;
 
 use kbs::error::KeyrHubstorageError;
@@ -26,16 +27,38 @@ use keyr_hubstorage as kbs;
 
 #[derive(Error, Debug)]
 pub enum KeyrHubError {
-    #[error("Client trying to access a protected route without setting Keyr-Token header")]
-    MissingKeyrTokenHeader,
+    #[error("Client trying to access a protected route without setting either Authorization or Keyr-Token header")]
+    MissingTokenHeader,
+    #[error("Malformed {0} header")]
+    MalformedTokenHeader(&'static str),
+    #[error("Authorization and Keyr-Token headers carry different tokens")]
+    ConflictingTokenHeaders,
     #[error(transparent)]
     Storage(#[from] KeyrHubstorageError),
     #[error(transparent)]
     Pool(#[from] r2d2::Error),
     #[error(transparent)]
     IO(#[from] std::io::Error),
+    #[error(transparent)]
+    Template(#[from] tinytemplate::error::Error),
+    #[error(transparent)]
+    Metrics(#[from] prometheus::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
     #[error("The requested data are not public")]
     PrivateData,
+    #[error("Unknown timezone {0}")]
+    UnknownTimezone(String),
+    #[error("Days cannot begin at {0} o'clock")]
+    InvalidDayStart(u32),
+    #[error("Timestamp {0} is out of range")]
+    InvalidTimestamp(i64),
+    #[error("The beginning of the range is after its end")]
+    InvalidRange,
+    #[error("Too many requests, retry in {0} seconds")]
+    RateLimited(u64),
+    #[error("Too many invalid tokens, retry in {0} seconds")]
+    LockedOut(u64),
 }
 
 impl From<diesel::result::Error> for KeyrHubError {
@@ -49,8 +72,17 @@ pub type Result<R> = std::result::Result<R, KeyrHubError>;
 impl ResponseError for KeyrHubError {
     fn status_code(&self) -> StatusCode {
         match self {
-            KeyrHubError::PrivateData => StatusCode::UNAUTHORIZED,
-            KeyrHubError::MissingKeyrTokenHeader => StatusCode::UNAUTHORIZED,
+            // No token could unlock them
+            KeyrHubError::PrivateData => StatusCode::FORBIDDEN,
+            KeyrHubError::UnknownTimezone(_) => StatusCode::BAD_REQUEST,
+            KeyrHubError::InvalidDayStart(_) => StatusCode::BAD_REQUEST,
+            KeyrHubError::InvalidTimestamp(_) => StatusCode::BAD_REQUEST,
+            KeyrHubError::InvalidRange => StatusCode::BAD_REQUEST,
+            KeyrHubError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            KeyrHubError::LockedOut(_) => StatusCode::TOO_MANY_REQUESTS,
+            KeyrHubError::MissingTokenHeader => StatusCode::UNAUTHORIZED,
+            KeyrHubError::MalformedTokenHeader(_) => StatusCode::BAD_REQUEST,
+            KeyrHubError::ConflictingTokenHeaders => StatusCode::BAD_REQUEST,
             KeyrHubError::Storage(KeyrHubstorageError::InvalidToken) => {
                 StatusCode::UNAUTHORIZED
             }
@@ -60,9 +92,59 @@ impl ResponseError for KeyrHubError {
             KeyrHubError::Storage(
                 KeyrHubstorageError::AlreadyUsedNickname(_),
             ) => StatusCode::BAD_REQUEST,
+            KeyrHubError::Storage(KeyrHubstorageError::UnknownMachine) => {
+                StatusCode::BAD_REQUEST
+            }
+            KeyrHubError::Storage(
+                KeyrHubstorageError::AlreadyUsedMachineName(_),
+            ) => StatusCode::BAD_REQUEST,
+            KeyrHubError::Storage(KeyrHubstorageError::FrozenUser) => {
+                StatusCode::CONFLICT
+            }
+            KeyrHubError::Storage(
+                KeyrHubstorageError::UnknownRevertSession,
+            ) => StatusCode::NOT_FOUND,
+            KeyrHubError::Storage(KeyrHubstorageError::TooLongSeries(_)) => {
+                StatusCode::BAD_REQUEST
+            }
             KeyrHubError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
             KeyrHubError::Pool(_) => StatusCode::INTERNAL_SERVER_ERROR,
             KeyrHubError::IO(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            KeyrHubError::Template(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            KeyrHubError::Metrics(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            KeyrHubError::Json(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        let mut resp = HttpResponse::build(status);
+
+        // RFC 7235 requires a challenge to be sent along with every 401
+        // response, and only the token errors are answered with a 401
+        let challenge = match self {
+            KeyrHubError::Storage(KeyrHubstorageError::InvalidToken) => {
+                Some("Bearer realm=\"keyr-hub\", error=\"invalid_token\"")
+            }
+            KeyrHubError::MissingTokenHeader => {
+                Some("Bearer realm=\"keyr-hub\"")
+            }
+            _ => None,
+        };
+
+        if let Some(challenge) = challenge {
+            resp.header(header::WWW_AUTHENTICATE, challenge);
+        }
+
+        match self {
+            KeyrHubError::RateLimited(retry_after)
+            | KeyrHubError::LockedOut(retry_after) => {
+                resp.header(header::RETRY_AFTER, retry_after.to_string());
+            }
+            _ => (),
         }
+
+        resp.content_type("text/plain; charset=utf-8")
+            .body(self.to_string())
     }
 }
@@ -0,0 +1,114 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use keyr_types::UserExport;
+
+#[derive(Deserialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+        }
+    }
+}
+
+const CSV_HEADER : [&str; 6] =
+    ["record", "field", "value", "machine", "timestamp", "count"];
+
+// Quote a field if needed, as specified by RFC 4180
+fn csv_field(field : &str) -> String {
+    if field.contains(&[',', '"', '\r', '\n'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn csv_record(out : &mut String, fields : [&str; 6]) {
+    let fields = fields.iter().map(|f| csv_field(f)).collect::<Vec<_>>();
+
+    out.push_str(&fields.join(","));
+    out.push_str("\r\n");
+}
+
+// Flatten an export into a single CSV table, whose `record' column tells
+// what each row is about: a `profile' field, a `machine', a `token' or the
+// `statistics' of an hour.
+pub fn to_csv(export : &UserExport) -> String {
+    let mut out = String::new();
+
+    csv_record(&mut out, CSV_HEADER);
+
+    let visible = export.visible.to_string();
+    let day_start = export.calendar.day_start.to_string();
+    let profile = [
+        ("name", export.name.as_str()),
+        ("visible", visible.as_str()),
+        ("timezone", export.calendar.timezone.as_str()),
+        ("day_start", day_start.as_str()),
+    ];
+
+    for (field, value) in profile.iter() {
+        csv_record(&mut out, ["profile", field, value, "", "", ""]);
+    }
+
+    for machine in &export.machines {
+        csv_record(&mut out, ["machine", "", "", machine, "", ""]);
+    }
+
+    for token in &export.tokens {
+        csv_record(
+            &mut out,
+            ["token", "hint", &token.hint, &token.machine, "", ""],
+        );
+    }
+
+    for stat in &export.statistics {
+        csv_record(
+            &mut out,
+            [
+                "statistics",
+                "",
+                "",
+                &stat.machine,
+                &stat.timestamp.to_string(),
+                &stat.count.to_string(),
+            ],
+        );
+    }
+
+    out
+}
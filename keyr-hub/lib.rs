@@ -0,0 +1,753 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+pub mod auth;
+pub mod badge;
+pub mod cli;
+pub mod config;
+pub mod cors;
+pub mod database;
+pub mod error;
+pub mod export;
+pub mod logging;
+pub mod metrics;
+pub mod openapi;
+pub mod profile;
+pub mod ratelimit;
+pub mod retention;
+pub mod routes;
+pub mod tls;
+
+use actix_service::ServiceFactory;
+use actix_web::body::Body;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{
+    HeaderName, HeaderValue, CACHE_CONTROL, CONTENT_DISPOSITION, LINK,
+};
+use actix_web::web::{
+    scope, Data, Json, JsonConfig, Path, PayloadConfig, Query,
+};
+use actix_web::{App, HttpResponse};
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use futures::{Future, FutureExt};
+use prometheus::{Encoder, TextEncoder};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use keyr_hubstorage as khs;
+use khs::error::KeyrHubstorageError;
+use khs::reverts::RevertSessionId;
+use khs::store::HubStore;
+use khs::time::Calendar;
+use khs::users::MaybeUserId;
+
+use keyr_types::{
+    DatabaseCheck, Granularity, HealthStatus, KeystrokesSeries,
+    KeystrokesStats, Leaderboard, Liveness, MigrationsCheck, Period, Readiness,
+    RevertPage, RevertRequest, RevertSession, Summary, SynchronizeRequest,
+    Timestamp, UserCalendar, VersionInfo,
+};
+
+use crate::auth::TokenHeader;
+use crate::badge::Metric;
+use crate::config::RevertConfig;
+use crate::cors::Cors;
+use crate::error::KeyrHubError;
+use crate::metrics::Metrics;
+use crate::ratelimit::RateLimiter;
+
+fn parse_timezone(tz : &str) -> Result<Tz, KeyrHubError> {
+    tz.parse::<Tz>()
+        .map_err(|_| KeyrHubError::UnknownTimezone(tz.to_owned()))
+}
+
+// Turn a timestamp sent by a client into a date, which chrono cannot do for
+// every `i64'
+fn parse_timestamp(t : Timestamp) -> Result<DateTime<Utc>, KeyrHubError> {
+    Utc.timestamp_opt(t, 0)
+        .single()
+        .ok_or(KeyrHubError::InvalidTimestamp(t))
+}
+
+// The calendar of a user, whose timezone can be overridden by a `tz' query
+// parameter
+fn calendar_of<S : HubStore>(
+    store : &S,
+    id : MaybeUserId,
+    tz : &Option<String>,
+) -> Result<Calendar, KeyrHubError> {
+    let calendar = store.get_calendar(id)?;
+
+    match tz {
+        Some(tz) => Ok(Calendar {
+            tz : parse_timezone(tz)?,
+            ..calendar
+        }),
+        None => Ok(calendar),
+    }
+}
+
+async fn commit<S : HubStore>(
+    store : Data<S>,
+    metrics : Data<Metrics>,
+    tok : TokenHeader,
+    request : Json<SynchronizeRequest>,
+) -> Result<Json<Summary>, KeyrHubError> {
+    let (mid, machine) = store.identify_machine_by_token(tok.as_token())?;
+    logging::record_user(mid);
+
+    let res = store.commit(mid, machine, Utc::now(), &request.staging_area)?;
+
+    metrics.observe_commit();
+
+    Ok(Json(res))
+}
+
+async fn summary<S : HubStore>(
+    store : Data<S>,
+    tok : TokenHeader,
+) -> Result<Json<Summary>, KeyrHubError> {
+    let mid = store.identify_user_by_token(tok.as_token())?;
+    logging::record_user(mid);
+
+    Ok(Json(store.get_summary(mid, Utc::now())?))
+}
+
+async fn get_calendar<S : HubStore>(
+    store : Data<S>,
+    tok : TokenHeader,
+) -> Result<Json<UserCalendar>, KeyrHubError> {
+    let mid = store.identify_user_by_token(tok.as_token())?;
+    logging::record_user(mid);
+    let calendar = store.get_calendar(mid)?;
+
+    Ok(Json(UserCalendar {
+        timezone : calendar.tz.name().to_owned(),
+        day_start : calendar.day_start,
+    }))
+}
+
+async fn set_calendar<S : HubStore>(
+    store : Data<S>,
+    tok : TokenHeader,
+    request : Json<UserCalendar>,
+) -> Result<Json<UserCalendar>, KeyrHubError> {
+    let mid = store.identify_user_by_token(tok.as_token())?;
+    logging::record_user(mid);
+    let tz = parse_timezone(&request.timezone)?;
+
+    if request.day_start >= 24 {
+        return Err(KeyrHubError::InvalidDayStart(request.day_start));
+    }
+
+    store.set_calendar(
+        mid,
+        Calendar {
+            tz,
+            day_start : request.day_start,
+        },
+    )?;
+
+    Ok(Json(UserCalendar {
+        timezone : tz.name().to_owned(),
+        day_start : request.day_start,
+    }))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ExportQuery {
+    format : Option<export::Format>,
+}
+
+async fn export_user<S : HubStore>(
+    store : Data<S>,
+    tok : TokenHeader,
+    query : Query<ExportQuery>,
+) -> Result<HttpResponse, KeyrHubError> {
+    let mid = store.identify_user_by_token(tok.as_token())?;
+    logging::record_user(mid);
+    let format = query.format.unwrap_or(export::Format::Json);
+    let data = store.export_user(mid)?;
+
+    let body = match format {
+        export::Format::Json => serde_json::to_string(&data)?,
+        export::Format::Csv => export::to_csv(&data),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .header(
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"keyr.{}\"", format.extension()),
+        )
+        .header(CACHE_CONTROL, "no-store")
+        .body(body))
+}
+
+async fn delete_user<S : HubStore>(
+    store : Data<S>,
+    tok : TokenHeader,
+) -> Result<Json<()>, KeyrHubError> {
+    let mid = store.identify_user_by_token(tok.as_token())?;
+    logging::record_user(mid);
+    store.delete_user(mid)?;
+
+    Ok(Json(()))
+}
+
+async fn revert_initiate<S : HubStore>(
+    store : Data<S>,
+    metrics : Data<Metrics>,
+    revert : Data<RevertConfig>,
+    tok : TokenHeader,
+) -> Result<Json<RevertSession>, KeyrHubError> {
+    let mid = store.identify_user_by_token(tok.as_token())?;
+    logging::record_user(mid);
+    let session = store.initiate_revert(
+        mid,
+        tok.as_token(),
+        Duration::seconds(revert.lease as i64),
+    )?;
+
+    metrics.observe_revert("initiate");
+
+    Ok(Json(RevertSession {
+        session : session.id.0,
+        expires_at : session.expires_at.timestamp(),
+    }))
+}
+
+const DEFAULT_REVERT_PAGE_LIMIT : u32 = 2000;
+const MAX_REVERT_PAGE_LIMIT : u32 = 10000;
+
+#[derive(Deserialize, JsonSchema)]
+struct RevertPageQuery {
+    session : String,
+    after : Option<Timestamp>,
+    limit : Option<u32>,
+}
+
+async fn revert_statistics<S : HubStore>(
+    store : Data<S>,
+    revert : Data<RevertConfig>,
+    tok : TokenHeader,
+    query : Query<RevertPageQuery>,
+) -> Result<Json<RevertPage>, KeyrHubError> {
+    let mid = store.identify_user_by_token(tok.as_token())?;
+    logging::record_user(mid);
+    let query = query.into_inner();
+    let after = query.after.map(parse_timestamp).transpose()?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_REVERT_PAGE_LIMIT)
+        .clamp(1, MAX_REVERT_PAGE_LIMIT);
+    let page = store.get_revert_page(
+        mid,
+        tok.as_token(),
+        &RevertSessionId(query.session),
+        after,
+        limit,
+        Duration::seconds(revert.lease as i64),
+    )?;
+
+    Ok(Json(RevertPage {
+        statistics : page.statistics,
+        next : page.next.map(|next| next.timestamp()),
+        expires_at : page.session.expires_at.timestamp(),
+    }))
+}
+
+async fn revert_terminate<S : HubStore>(
+    store : Data<S>,
+    metrics : Data<Metrics>,
+    tok : TokenHeader,
+    request : Json<RevertRequest>,
+) -> Result<Json<()>, KeyrHubError> {
+    let mid = store.identify_user_by_token(tok.as_token())?;
+    logging::record_user(mid);
+    let session = RevertSessionId(request.into_inner().session);
+    store.terminate_revert(mid, tok.as_token(), &session)?;
+
+    metrics.observe_revert("terminate");
+
+    Ok(Json(()))
+}
+
+async fn revert_cancel<S : HubStore>(
+    store : Data<S>,
+    metrics : Data<Metrics>,
+    tok : TokenHeader,
+    request : Json<RevertRequest>,
+) -> Result<Json<()>, KeyrHubError> {
+    let mid = store.identify_user_by_token(tok.as_token())?;
+    logging::record_user(mid);
+    let session = RevertSessionId(request.into_inner().session);
+    store.cancel_revert(mid, tok.as_token(), &session)?;
+
+    metrics.observe_revert("cancel");
+
+    Ok(Json(()))
+}
+
+// The first version of the API handed out the whole statistics of the user
+// at once, and did not identify the revert sessions. The session opened here
+// is found again by the token of the agent.
+async fn revert_initiate_v1<S : HubStore>(
+    store : Data<S>,
+    metrics : Data<Metrics>,
+    revert : Data<RevertConfig>,
+    tok : TokenHeader,
+) -> Result<Json<KeystrokesStats>, KeyrHubError> {
+    let mid = store.identify_user_by_token(tok.as_token())?;
+    logging::record_user(mid);
+    let lease = Duration::seconds(revert.lease as i64);
+    let session = store.initiate_revert(mid, tok.as_token(), lease)?;
+
+    let mut statistics = KeystrokesStats::new();
+    let mut after = None;
+
+    loop {
+        let page = store.get_revert_page(
+            mid,
+            tok.as_token(),
+            &session.id,
+            after,
+            MAX_REVERT_PAGE_LIMIT,
+            lease,
+        )?;
+
+        statistics.extend(page.statistics);
+
+        match page.next {
+            Some(next) => after = Some(next),
+            None => break,
+        }
+    }
+
+    metrics.observe_revert("initiate");
+
+    Ok(Json(statistics))
+}
+
+async fn revert_terminate_v1<S : HubStore>(
+    store : Data<S>,
+    metrics : Data<Metrics>,
+    tok : TokenHeader,
+) -> Result<Json<()>, KeyrHubError> {
+    let mid = store.identify_user_by_token(tok.as_token())?;
+    logging::record_user(mid);
+    let session = store.find_revert_session(mid, tok.as_token())?;
+    store.terminate_revert(mid, tok.as_token(), &session)?;
+
+    metrics.observe_revert("terminate");
+
+    Ok(Json(()))
+}
+
+async fn revert_cancel_v1<S : HubStore>(
+    store : Data<S>,
+    metrics : Data<Metrics>,
+    tok : TokenHeader,
+) -> Result<Json<()>, KeyrHubError> {
+    let mid = store.identify_user_by_token(tok.as_token())?;
+    logging::record_user(mid);
+    let session = store.find_revert_session(mid, tok.as_token())?;
+    store.cancel_revert(mid, tok.as_token(), &session)?;
+
+    metrics.observe_revert("cancel");
+
+    Ok(Json(()))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ViewQuery {
+    from : Option<Timestamp>,
+    to : Option<Timestamp>,
+    granularity : Option<Granularity>,
+    tz : Option<String>,
+    machine : Option<String>,
+}
+
+// Find a user by their name, provided their statistics are public.
+fn find_visible_user<S : HubStore>(
+    store : &S,
+    name : &str,
+) -> Result<MaybeUserId, KeyrHubError> {
+    let id = store.find_user_by_name(name)?;
+
+    if !store.is_visible(id)? {
+        return Err(KeyrHubError::PrivateData);
+    }
+
+    Ok(id)
+}
+
+async fn view_stats<S : HubStore>(
+    store : Data<S>,
+    name : Path<String>,
+    query : Query<ViewQuery>,
+) -> Result<Json<KeystrokesSeries>, KeyrHubError> {
+    let granularity = query.granularity.unwrap_or(Granularity::Day);
+    let to = match query.to {
+        Some(to) => parse_timestamp(to)?,
+        None => Utc::now(),
+    };
+
+    let id = find_visible_user(store.get_ref(), &name)?;
+    let calendar = calendar_of(store.get_ref(), id, &query.tz)?;
+
+    let from = match query.from {
+        Some(from) => parse_timestamp(from)?,
+        None => store.get_oldest_entry(id)?.unwrap_or(to),
+    };
+
+    if from > to {
+        return Err(KeyrHubError::InvalidRange);
+    }
+
+    let machine = match &query.machine {
+        Some(name) => Some(store.find_machine_by_name(id, name)?),
+        None => None,
+    };
+
+    let res = store.get_keystrokes_series(
+        id,
+        machine,
+        from,
+        to,
+        granularity,
+        calendar,
+    )?;
+
+    Ok(Json(res))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ProfileQuery {
+    tz : Option<String>,
+}
+
+async fn view_profile<S : HubStore>(
+    store : Data<S>,
+    name : Path<String>,
+    query : Query<ProfileQuery>,
+) -> Result<HttpResponse, KeyrHubError> {
+    let id = find_visible_user(store.get_ref(), &name)?;
+    let calendar = calendar_of(store.get_ref(), id, &query.tz)?;
+    let tz = calendar.tz;
+
+    let now = Utc::now();
+    let today = khs::time::to_local(tz, calendar.today(now)).date();
+    let day_start = |day : NaiveDate| {
+        khs::time::to_utc(&tz, day.and_hms(calendar.day_start, 0, 0))
+    };
+    let from = day_start(profile::heatmap_start(today));
+    let to = day_start(today + Duration::days(1));
+
+    let totals = store.get_summary(id, now)?;
+    let daily = store.get_keystrokes_series(
+        id,
+        None,
+        from,
+        to,
+        Granularity::Day,
+        calendar,
+    )?;
+    let hourly = store.get_hourly_distribution(id, tz)?;
+
+    let page = profile::render(&name, tz, &totals, &daily, &hourly)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(page))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct BadgeQuery {
+    metric : Option<Metric>,
+}
+
+async fn view_badge<S : HubStore>(
+    store : Data<S>,
+    name : Path<String>,
+    query : Query<BadgeQuery>,
+) -> Result<HttpResponse, KeyrHubError> {
+    let metric = query.metric.unwrap_or(Metric::Total);
+
+    let id = find_visible_user(store.get_ref(), &name)?;
+    let since = store
+        .get_calendar(id)?
+        .period_start(metric.period(), Utc::now());
+    let count = store.get_count_since(id, since)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("image/svg+xml; charset=utf-8")
+        .header(CACHE_CONTROL, "public, max-age=300")
+        .body(badge::render(metric, count)?))
+}
+
+const DEFAULT_LEADERBOARD_LIMIT : u32 = 10;
+const MAX_LEADERBOARD_LIMIT : u32 = 100;
+
+#[derive(Deserialize, JsonSchema)]
+struct LeaderboardQuery {
+    period : Option<Period>,
+    limit : Option<u32>,
+}
+
+async fn leaderboard<S : HubStore>(
+    store : Data<S>,
+    query : Query<LeaderboardQuery>,
+) -> Result<Json<Leaderboard>, KeyrHubError> {
+    let period = query.period.unwrap_or(Period::Week);
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LEADERBOARD_LIMIT)
+        .clamp(1, MAX_LEADERBOARD_LIMIT);
+
+    Ok(Json(store.get_leaderboard(period, Utc::now(), limit)?))
+}
+
+async fn version() -> Json<VersionInfo> {
+    Json(VersionInfo {
+        version : env!("CARGO_PKG_VERSION").to_owned(),
+        protocols : routes::PROTOCOLS.to_vec(),
+    })
+}
+
+async fn healthz() -> Json<Liveness> {
+    Json(Liveness {
+        status : HealthStatus::Ok,
+        version : env!("CARGO_PKG_VERSION").to_owned(),
+    })
+}
+
+// Probes are expected to answer quickly, so we do not wait for the default
+// timeout of the pool.
+const READINESS_TIMEOUT : u64 = 2;
+
+async fn readyz<S : HubStore>(store : Data<S>) -> HttpResponse {
+    let pending = store
+        .pending_migrations(std::time::Duration::from_secs(READINESS_TIMEOUT));
+
+    let (database, migrations) = match pending {
+        Err(KeyrHubstorageError::Pool(err)) => {
+            let database = DatabaseCheck {
+                status : HealthStatus::Unavailable,
+                error : Some(err.to_string()),
+            };
+            let migrations = MigrationsCheck {
+                status : HealthStatus::Unavailable,
+                pending : vec![],
+                error : Some("Database is unavailable".to_owned()),
+            };
+
+            (database, migrations)
+        }
+        pending => {
+            let migrations = match pending {
+                Ok(pending) if pending.is_empty() => MigrationsCheck {
+                    status : HealthStatus::Ok,
+                    pending,
+                    error : None,
+                },
+                Ok(pending) => MigrationsCheck {
+                    status : HealthStatus::Unavailable,
+                    pending,
+                    error : None,
+                },
+                Err(err) => MigrationsCheck {
+                    status : HealthStatus::Unavailable,
+                    pending : vec![],
+                    error : Some(err.to_string()),
+                },
+            };
+
+            let database = DatabaseCheck {
+                status : HealthStatus::Ok,
+                error : None,
+            };
+
+            (database, migrations)
+        }
+    };
+
+    let status = if database.status == HealthStatus::Ok
+        && migrations.status == HealthStatus::Ok
+    {
+        HealthStatus::Ok
+    } else {
+        HealthStatus::Unavailable
+    };
+
+    let mut resp = match status {
+        HealthStatus::Ok => HttpResponse::Ok(),
+        HealthStatus::Unavailable => HttpResponse::ServiceUnavailable(),
+    };
+
+    resp.json(Readiness {
+        status,
+        database,
+        migrations,
+    })
+}
+
+async fn openapi_json<S : HubStore>() -> Json<serde_json::Value> {
+    Json(openapi::document::<S>())
+}
+
+pub async fn export_metrics<S : HubStore>(
+    store : Data<S>,
+    metrics : Data<Metrics>,
+) -> Result<HttpResponse, KeyrHubError> {
+    let body = metrics.render(store.get_ref())?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(TextEncoder::new().format_type())
+        .body(body))
+}
+
+// Flag the responses of the unversioned aliases of the routes, pointing to
+// their successor (see RFC 8594 and the `Deprecation' header draft).
+fn deprecated<S>(
+    req : ServiceRequest,
+    srv : &mut S,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>>
+where
+    S : Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+    >,
+{
+    let successor = format!("</v1{}>; rel=\"successor-version\"", req.path());
+
+    srv.call(req).map(move |res| {
+        res.map(|mut res| {
+            let headers = res.headers_mut();
+
+            headers.insert(
+                HeaderName::from_static("deprecation"),
+                HeaderValue::from_static("true"),
+            );
+
+            // The path of a request matching a route is valid in a header
+            if let Ok(link) = HeaderValue::from_str(&successor) {
+                headers.insert(LINK, link);
+            }
+
+            res
+        })
+    })
+}
+
+// The state shared by the workers of a hub
+pub struct Hub<S> {
+    pub store : Data<S>,
+    pub metrics : Data<Metrics>,
+    pub limiter : Arc<RateLimiter>,
+    pub cors : Option<Arc<Cors>>,
+    pub revert : RevertConfig,
+    // The maximum size, in bytes, of the body of a request
+    pub max_request_size : usize,
+}
+
+impl<S> Clone for Hub<S> {
+    fn clone(&self) -> Self {
+        Hub {
+            store : self.store.clone(),
+            metrics : self.metrics.clone(),
+            limiter : self.limiter.clone(),
+            cors : self.cors.clone(),
+            revert : self.revert.clone(),
+            max_request_size : self.max_request_size,
+        }
+    }
+}
+
+// The application serving the API of a hub, for one of its workers.
+pub fn app<S : HubStore>(
+    hub : Hub<S>,
+) -> App<
+    impl ServiceFactory<
+        Config = (),
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+    Body,
+> {
+    let observer = hub.metrics.clone();
+    let limiter = hub.limiter.clone();
+    let cors = hub.cors.clone();
+    let legacy_cors = hub.cors.clone();
+
+    App::new()
+        .app_data(hub.store.clone())
+        .data(hub.revert.clone())
+        .app_data(hub.metrics.clone())
+        .app_data(JsonConfig::default().limit(hub.max_request_size))
+        .app_data(PayloadConfig::new(hub.max_request_size))
+        .wrap_fn(move |req, srv| ratelimit::limit(limiter.clone(), req, srv))
+        .wrap_fn(move |req, srv| {
+            let start = Instant::now();
+            let route = req.match_pattern();
+            let method = req.method().to_string();
+            let observer = observer.clone();
+
+            srv.call(req).map(move |res| {
+                let status = match &res {
+                    Ok(res) => res.status().as_u16(),
+                    Err(err) => err.as_response_error().status_code().as_u16(),
+                };
+
+                observer.observe_request(
+                    route.as_deref(),
+                    &method,
+                    status,
+                    start.elapsed(),
+                );
+
+                res
+            })
+        })
+        .wrap_fn(logging::trace)
+        .configure(|cfg| routes::configure(cfg, routes::root::<S>(), &None))
+        .configure(move |cfg| {
+            for version in routes::PROTOCOLS.iter().copied() {
+                let cors = cors.clone();
+
+                cfg.service(scope(&format!("/v{}", version)).configure(
+                    move |cfg| {
+                        routes::configure(cfg, routes::api::<S>(version), &cors)
+                    },
+                ));
+            }
+        })
+        // The routes used to be served at the root, before the API was
+        // versioned
+        .service(scope("").wrap_fn(deprecated).configure(move |cfg| {
+            routes::configure(cfg, routes::api::<S>(1), &legacy_cors)
+        }))
+}
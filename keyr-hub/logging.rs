@@ -0,0 +1,120 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use anyhow::anyhow;
+use futures::{Future, FutureExt};
+use tracing::{error, field, info, info_span, Instrument, Span};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+use std::time::Instant;
+
+use keyr_hubstorage::users::MaybeUserId;
+
+use crate::config::{LogConfig, LogFormat};
+use crate::metrics::UNMATCHED_ROUTE;
+
+// Install the global subscriber. The `RUST_LOG' environment variable takes
+// precedence over the configured level.
+pub fn init(conf : &LogConfig) -> anyhow::Result<()> {
+    let filter = match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(directives) => EnvFilter::try_new(directives)?,
+        Err(_) => EnvFilter::try_new(&conf.level)?,
+    };
+
+    // Closing a span logs how long it lasted, which gives the latency of
+    // the requests and the timings of the database queries
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+
+    match conf.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+    }
+    .map_err(|err| anyhow!(err))
+}
+
+// Attach the user a request has been authenticated as to its span. Tokens
+// are never logged.
+pub fn record_user(id : MaybeUserId) {
+    Span::current().record("user_id", id.0);
+}
+
+// The middleware wrapping every request in a span.
+pub fn trace<S>(
+    req : ServiceRequest,
+    srv : &mut S,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>>
+where
+    S : Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+    >,
+{
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_owned());
+    let span = info_span!(
+        "request",
+        method = %req.method(),
+        route = %route,
+        user_id = field::Empty,
+        status = field::Empty,
+        latency_us = field::Empty,
+    );
+    let start = Instant::now();
+
+    let fut = span.in_scope(|| srv.call(req));
+
+    fut.map(move |res| {
+        let span = Span::current();
+
+        span.record("latency_us", start.elapsed().as_micros() as u64);
+
+        let (status, err) = match &res {
+            Ok(res) => (
+                res.status(),
+                res.response().error().map(|err| err.to_string()),
+            ),
+            Err(err) => {
+                (err.as_response_error().status_code(), Some(err.to_string()))
+            }
+        };
+
+        span.record("status", status.as_u16());
+
+        if let Some(err) = err {
+            if status.is_server_error() {
+                error!(error = %err, "request failed");
+            } else {
+                info!(error = %err, "request rejected");
+            }
+        }
+
+        res
+    })
+    .instrument(span)
+}
@@ -17,133 +17,146 @@
  * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-pub mod auth;
-pub mod cli;
-pub mod config;
-pub mod database;
-pub mod error;
-
-use diesel::prelude::*;
-
-use actix_web::web::{Data, Json, Path};
-use actix_web::{get, post, App, HttpServer};
-use chrono::{TimeZone, Utc};
+use actix_web::web::{get, Data};
+use actix_web::{App, HttpServer};
+use anyhow::{bail, Context};
 
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use keyr_hubstorage as khs;
-use khs::users;
-
-use keyr_types::{KeystrokesStats, Summary, SynchronizeRequest};
-
-use crate::auth::TokenHeader;
-use crate::config::HubConfig;
-use crate::database::{create_pool, PgPool};
-use crate::error::KeyrHubError;
-
-#[post("/commit")]
-async fn commit(
-    pool : Data<PgPool>,
-    tok : TokenHeader,
-    request : Json<SynchronizeRequest>,
-) -> Result<Json<Summary>, KeyrHubError> {
-    let conn = pool.into_inner().get()?;
-
-    let mid = users::identify_user_by_token(&conn, tok.as_token())?;
-    let today = Utc.timestamp(request.today, 0);
-
-    Ok(Json(khs::stats::commit(
-        &conn,
-        mid,
-        today,
-        &request.staging_area,
-    )?))
-}
-
-#[post("/revert/initiate")]
-async fn revert_initiate(
-    pool : Data<PgPool>,
-    tok : TokenHeader,
-) -> Result<Json<KeystrokesStats>, KeyrHubError> {
-    let conn = pool.into_inner().get()?;
-
-    let mid = users::identify_user_by_token(&conn, tok.as_token())?;
-    let res = khs::stats::initiate_revert(&conn, mid)?;
-
-    Ok(Json(res))
-}
-
-#[post("/revert/terminate")]
-async fn revert_terminate(
-    pool : Data<PgPool>,
-    tok : TokenHeader,
-) -> Result<Json<()>, KeyrHubError> {
-    let conn = pool.into_inner().get()?;
-
-    let mid = users::identify_user_by_token(&conn, tok.as_token())?;
-    let res = khs::stats::terminate_revert(&conn, mid)?;
-
-    Ok(Json(res))
-}
-
-#[post("/revert/cancel")]
-async fn revert_cancel(
-    pool : Data<PgPool>,
-    tok : TokenHeader,
-) -> Result<Json<()>, KeyrHubError> {
-    let conn = pool.into_inner().get()?;
-
-    let mid = users::identify_user_by_token(&conn, tok.as_token())?;
-    khs::users::unfreeze_user(&conn, mid)?;
-
-    Ok(Json(()))
-}
-
-#[get("/view/{name}")]
-async fn view_stats(
-    pool : Data<PgPool>,
-    name : Path<String>,
-) -> Result<Json<KeystrokesStats>, KeyrHubError> {
-    let conn = pool.into_inner().get()?;
-
-    let res = conn.transaction::<_, KeyrHubError, _>(|| {
-        let id = khs::users::find_by_name_in_transaction(&conn, name.clone())?;
-
-        if !khs::users::is_visible_in_transaction(&conn, id)? {
-            return Err(KeyrHubError::PrivateData);
-        }
-
-        let res = khs::stats::get_keystrokes_stats_in_transaction(&conn, id)?;
-
-        Ok(res)
-    })?;
+use khs::store::DieselStore;
 
-    Ok(Json(res))
-}
+use keyr_hub::config::HubConfig;
+use keyr_hub::cors::Cors;
+use keyr_hub::database::create_pool;
+use keyr_hub::metrics::Metrics;
+use keyr_hub::ratelimit::RateLimiter;
+use keyr_hub::{app, cli, export_metrics, logging, retention, tls, Hub};
 
 async fn run() -> anyhow::Result<()> {
     let matches = cli::get_app().get_matches();
 
     // unwrap is valid since `config_file' is required
     let conf_path = matches.value_of("config_file").unwrap();
-    let conf = HubConfig::from_file(&PathBuf::from(conf_path))?;
-
-    let pool = create_pool(&conf.database_url())?;
-
-    khs::migrations::run(&pool.get()?)?;
-
-    HttpServer::new(move || {
-        App::new()
-            .data(pool.clone())
-            .service(commit)
-            .service(revert_initiate)
-            .service(revert_terminate)
-            .service(revert_cancel)
-            .service(view_stats)
-    })
-    .bind(&format!("{}:{}", conf.http.url, conf.http.port))?
-    .run()
-    .await?;
+    let mut conf = HubConfig::from_file(&PathBuf::from(conf_path))?;
+
+    cli::override_http(&matches, &mut conf.http)?;
+    cli::override_metrics(&matches, &mut conf.metrics)?;
+
+    logging::init(&conf.log)?;
+
+    conf.database.check_pool()?;
+
+    let pool = create_pool(&conf.database, &conf.database.database_url()?)?;
+
+    khs::migrations::run(&*pool.get()?)?;
+
+    let store = Data::new(DieselStore::new(pool));
+    let policy = conf.retention.retention();
+
+    if matches.subcommand_matches("compact").is_some() {
+        let report = retention::compact(store.get_ref(), &policy)?;
+
+        println!(
+            "{} hourly statistics rolled up, {} statistics deleted, {} users \
+             skipped",
+            report.rolled_up, report.deleted, report.skipped
+        );
+
+        return Ok(());
+    }
+
+    if let Some(every) = conf.retention.interval {
+        retention::schedule(store.clone(), policy, every)?;
+    }
+
+    let metrics = Data::new(Metrics::new(
+        conf.metrics
+            .as_ref()
+            .map(|m| m.user_gauges)
+            .unwrap_or(false),
+    )?);
+
+    let hub = Hub {
+        store,
+        metrics,
+        limiter : Arc::new(RateLimiter::new(conf.rate_limit.clone())),
+        cors : match &conf.http.cors {
+            Some(cors) => Some(Arc::new(Cors::new(cors)?)),
+            None => None,
+        },
+        revert : conf.revert.clone(),
+        max_request_size : conf.http.max_request_size,
+    };
+    let app_hub = hub.clone();
+
+    let mut server = HttpServer::new(move || app(app_hub.clone()));
+
+    if let Some(workers) = conf.http.workers {
+        server = server.workers(workers);
+    }
+
+    if let Some(keep_alive) = conf.http.keep_alive {
+        // `None' disables keep-alive
+        server = server.keep_alive(match keep_alive {
+            0 => None,
+            secs => Some(secs as usize),
+        });
+    }
+
+    if let Some(timeout) = conf.http.shutdown_timeout {
+        server = server.shutdown_timeout(timeout);
+    }
+
+    let addresses = conf.http.addresses();
+
+    if addresses.is_empty() && conf.http.unix_socket.is_none() {
+        bail!("No address to listen to, see `bind' in the `[http]' section");
+    }
+
+    let tls = match &conf.http.tls {
+        Some(tls) => Some(tls::server_config(tls)?),
+        None => None,
+    };
+
+    for addr in addresses {
+        server = match &tls {
+            Some(tls) => server.bind_rustls(&addr, tls.clone()),
+            None => server.bind(&addr),
+        }
+        .with_context(|| format!("Cannot listen to {}", addr))?;
+    }
+
+    if let Some(path) = &conf.http.unix_socket {
+        server = server
+            .bind_uds(path)
+            .with_context(|| format!("Cannot listen to {}", path.display()))?;
+    }
+
+    let server = server.run();
+
+    match &conf.metrics {
+        Some(metrics_conf) => {
+            // Metrics are served on their own address, so that they are not
+            // exposed alongside the public API
+            let metrics_server = HttpServer::new(move || {
+                App::new()
+                    .app_data(hub.store.clone())
+                    .app_data(hub.metrics.clone())
+                    .route("/metrics", get().to(export_metrics::<DieselStore>))
+            })
+            .workers(1)
+            .bind(metrics_conf.address())
+            .with_context(|| {
+                format!("Cannot listen to {}", metrics_conf.address())
+            })?
+            .run();
+
+            futures::future::try_join(server, metrics_server).await?;
+        }
+        None => server.await?,
+    }
 
     Ok(())
 }
@@ -0,0 +1,172 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use chrono::Utc;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use std::time::Duration;
+
+use keyr_hubstorage::store::HubStore;
+use keyr_types::Period;
+
+use crate::error::Result;
+
+// The route label of the requests which do not match any route, so that
+// scanners cannot blow up the cardinality of the metrics
+pub const UNMATCHED_ROUTE : &str = "unmatched";
+
+pub struct Metrics {
+    registry : Registry,
+    http_requests : IntCounterVec,
+    http_duration : HistogramVec,
+    pool_connections : IntGauge,
+    pool_idle_connections : IntGauge,
+    pool_max_connections : IntGauge,
+    commits : IntCounter,
+    reverts : IntCounterVec,
+    user_keystrokes : Option<IntGaugeVec>,
+}
+
+impl Metrics {
+    pub fn new(user_gauges : bool) -> prometheus::Result<Metrics> {
+        let registry = Registry::new_custom(Some("keyr_hub".to_owned()), None)?;
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Number of HTTP requests"),
+            &["route", "method", "status"],
+        )?;
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Latency of HTTP requests",
+            ),
+            &["route", "method"],
+        )?;
+        let pool_connections = IntGauge::new(
+            "db_pool_connections",
+            "Number of connections of the database pool",
+        )?;
+        let pool_idle_connections = IntGauge::new(
+            "db_pool_idle_connections",
+            "Number of idle connections of the database pool",
+        )?;
+        let pool_max_connections = IntGauge::new(
+            "db_pool_max_connections",
+            "Maximum number of connections of the database pool",
+        )?;
+        let commits =
+            IntCounter::new("commits_total", "Number of successful commits")?;
+        let reverts = IntCounterVec::new(
+            Opts::new("reverts_total", "Number of successful revert steps"),
+            &["step"],
+        )?;
+
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(http_duration.clone()))?;
+        registry.register(Box::new(pool_connections.clone()))?;
+        registry.register(Box::new(pool_idle_connections.clone()))?;
+        registry.register(Box::new(pool_max_connections.clone()))?;
+        registry.register(Box::new(commits.clone()))?;
+        registry.register(Box::new(reverts.clone()))?;
+
+        let user_keystrokes = if user_gauges {
+            let gauge = IntGaugeVec::new(
+                Opts::new("user_keystrokes", "Keystrokes of visible users"),
+                &["user"],
+            )?;
+
+            registry.register(Box::new(gauge.clone()))?;
+
+            Some(gauge)
+        } else {
+            None
+        };
+
+        Ok(Metrics {
+            registry,
+            http_requests,
+            http_duration,
+            pool_connections,
+            pool_idle_connections,
+            pool_max_connections,
+            commits,
+            reverts,
+            user_keystrokes,
+        })
+    }
+
+    pub fn observe_request(
+        &self,
+        route : Option<&str>,
+        method : &str,
+        status : u16,
+        duration : Duration,
+    ) {
+        let route = route.unwrap_or(UNMATCHED_ROUTE);
+
+        self.http_requests
+            .with_label_values(&[route, method, &status.to_string()])
+            .inc();
+        self.http_duration
+            .with_label_values(&[route, method])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn observe_commit(&self) {
+        self.commits.inc();
+    }
+
+    pub fn observe_revert(&self, step : &str) {
+        self.reverts.with_label_values(&[step]).inc();
+    }
+
+    // Refresh the gauges which are computed on demand, then encode every
+    // metric in the Prometheus text format.
+    pub fn render<S : HubStore>(&self, store : &S) -> Result<String> {
+        // Stores without a pool of connections leave the pool gauges at zero
+        if let Some(state) = store.pool_state() {
+            self.pool_connections.set(state.connections as i64);
+            self.pool_idle_connections
+                .set(state.idle_connections as i64);
+            self.pool_max_connections.set(state.max_connections as i64);
+        }
+
+        if let Some(gauge) = &self.user_keystrokes {
+            let counts =
+                store.get_leaderboard(Period::All, Utc::now(), u32::MAX)?;
+
+            gauge.reset();
+
+            for entry in counts {
+                gauge
+                    .with_label_values(&[&entry.name])
+                    .set(entry.count as i64);
+            }
+        }
+
+        let mut buffer = vec![];
+
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
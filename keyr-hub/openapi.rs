@@ -0,0 +1,305 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use actix_web::error::ResponseError;
+use actix_web::http::StatusCode;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+use std::collections::BTreeMap;
+
+use keyr_hubstorage::error::KeyrHubstorageError;
+use keyr_hubstorage::store::HubStore;
+
+use crate::error::KeyrHubError;
+use crate::routes::{self, Endpoint};
+
+// The errors of the routes which expect a token
+fn authentication_errors() -> Vec<KeyrHubError> {
+    vec![
+        KeyrHubError::MissingTokenHeader,
+        KeyrHubError::MalformedTokenHeader("Authorization"),
+        KeyrHubError::MalformedTokenHeader("Keyr-Token"),
+        KeyrHubError::ConflictingTokenHeaders,
+        KeyrHubError::Storage(KeyrHubstorageError::InvalidToken),
+    ]
+}
+
+struct OpenApi {
+    gen : SchemaGenerator,
+    paths : BTreeMap<String, Map<String, Value>>,
+}
+
+// An operation of the API being described, registered in its `OpenApi'
+// document with `register'.
+pub(crate) struct Operation<'a> {
+    api : &'a mut OpenApi,
+    method : String,
+    path : String,
+    summary : &'static str,
+    authenticated : bool,
+    parameters : Vec<Value>,
+    request : Option<Schema>,
+    responses : BTreeMap<u16, Value>,
+    errors : Vec<KeyrHubError>,
+}
+
+impl OpenApi {
+    fn new() -> OpenApi {
+        OpenApi {
+            gen : SchemaSettings::openapi3().into_generator(),
+            paths : BTreeMap::new(),
+        }
+    }
+
+    fn operation(
+        &mut self,
+        method : String,
+        path : String,
+        summary : &'static str,
+    ) -> Operation<'_> {
+        Operation {
+            api : self,
+            method,
+            path,
+            summary,
+            authenticated : false,
+            parameters : vec![],
+            request : None,
+            responses : BTreeMap::new(),
+            errors : vec![],
+        }
+    }
+
+    fn describe(&mut self, prefix : &str, endpoints : Vec<Endpoint>) {
+        for endpoint in endpoints {
+            let op = self.operation(
+                endpoint.method.as_str().to_lowercase(),
+                format!("{}{}", prefix, endpoint.path),
+                endpoint.summary,
+            );
+
+            (endpoint.describe)(op).register();
+        }
+    }
+
+    fn finish(mut self) -> Value {
+        let schemas = self.gen.take_definitions();
+
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": "keyr-hub",
+                "description": "Keep track of your keystrokes",
+                "license": { "name": "GPL-3.0-or-later" },
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "paths": self.paths,
+            "components": {
+                "schemas": schemas,
+                "securitySchemes": {
+                    "bearer": { "type": "http", "scheme": "bearer" },
+                    "keyrToken": {
+                        "type": "apiKey",
+                        "in": "header",
+                        "name": "Keyr-Token",
+                    },
+                },
+            },
+        })
+    }
+}
+
+// The parameters of a path template, e.g., `name' for `/badge/{name}.svg'.
+fn path_parameters(path : &str) -> Vec<&str> {
+    path.split('{')
+        .skip(1)
+        .filter_map(|chunk| chunk.split('}').next())
+        .collect()
+}
+
+impl<'a> Operation<'a> {
+    pub(crate) fn authenticated(mut self) -> Self {
+        self.authenticated = true;
+        self.errors.extend(authentication_errors());
+        self
+    }
+
+    // Describe the fields of `Q' as query parameters.
+    pub(crate) fn query<Q : JsonSchema>(mut self) -> Self {
+        let root = self.api.gen.root_schema_for::<Q>();
+
+        if let Some(object) = root.schema.object {
+            for (name, schema) in object.properties {
+                self.parameters.push(json!({
+                    "name": name,
+                    "in": "query",
+                    "required": object.required.contains(&name),
+                    "schema": schema,
+                }));
+            }
+        }
+
+        self
+    }
+
+    pub(crate) fn request<R : JsonSchema>(mut self) -> Self {
+        self.request = Some(self.api.gen.subschema_for::<R>());
+        self
+    }
+
+    pub(crate) fn response<R : JsonSchema>(self, status : StatusCode) -> Self {
+        let schema = self.api.gen.subschema_for::<R>();
+
+        self.content(status, "application/json", json!(schema))
+    }
+
+    // A status can be answered with several content types, e.g., depending
+    // on a query parameter
+    pub(crate) fn content(
+        mut self,
+        status : StatusCode,
+        content_type : &str,
+        schema : Value,
+    ) -> Self {
+        let response =
+            self.responses.entry(status.as_u16()).or_insert_with(|| {
+                json!({
+                    "description": status.canonical_reason().unwrap_or(""),
+                    "content": {},
+                })
+            });
+
+        response["content"][content_type] = json!({ "schema": schema });
+        self
+    }
+
+    pub(crate) fn errors(mut self, errors : Vec<KeyrHubError>) -> Self {
+        self.errors.extend(errors);
+        self
+    }
+
+    fn register(self) {
+        let mut responses = self.responses;
+        let mut errors : BTreeMap<u16, Vec<String>> = BTreeMap::new();
+
+        // The status of an error response is the one the hub actually uses,
+        // so that this document cannot drift from `KeyrHubError'
+        for err in &self.errors {
+            let messages =
+                errors.entry(err.status_code().as_u16()).or_default();
+            let message = err.to_string();
+
+            if !messages.contains(&message) {
+                messages.push(message);
+            }
+        }
+
+        // Every route is subject to rate limiting
+        errors
+            .entry(StatusCode::TOO_MANY_REQUESTS.as_u16())
+            .or_default()
+            .push("Too many requests".to_owned());
+        errors
+            .entry(StatusCode::INTERNAL_SERVER_ERROR.as_u16())
+            .or_default()
+            .push("Internal error".to_owned());
+
+        for (status, messages) in errors {
+            let mut response = json!({
+                "description": messages.join("; "),
+                "content": {
+                    "text/plain": { "schema": { "type": "string" } },
+                },
+            });
+
+            if status == StatusCode::UNAUTHORIZED.as_u16() {
+                response["headers"] = json!({
+                    "WWW-Authenticate": { "schema": { "type": "string" } },
+                });
+            }
+
+            if status == StatusCode::TOO_MANY_REQUESTS.as_u16() {
+                response["headers"] = json!({
+                    "Retry-After": { "schema": { "type": "integer" } },
+                });
+            }
+
+            responses.insert(status, response);
+        }
+
+        let mut parameters = path_parameters(&self.path)
+            .into_iter()
+            .map(|name| {
+                json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" },
+                })
+            })
+            .collect::<Vec<_>>();
+
+        parameters.extend(self.parameters);
+
+        let mut operation = json!({
+            "summary": self.summary,
+            "parameters": parameters,
+            "responses": responses
+                .into_iter()
+                .map(|(status, response)| (status.to_string(), response))
+                .collect::<Map<_, _>>(),
+        });
+
+        if let Some(schema) = self.request {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": { "application/json": { "schema": schema } },
+            });
+        }
+
+        if self.authenticated {
+            operation["security"] =
+                json!([{ "bearer": [] }, { "keyrToken": [] }]);
+        }
+
+        self.api
+            .paths
+            .entry(self.path)
+            .or_default()
+            .insert(self.method, operation);
+    }
+}
+
+// The OpenAPI description of the routes served by the hub, derived from the
+// tables of `routes'. The deprecated unversioned aliases are left out on
+// purpose.
+pub fn document<S : HubStore>() -> Value {
+    let mut api = OpenApi::new();
+
+    api.describe("", routes::root::<S>());
+
+    for version in routes::PROTOCOLS.iter().copied() {
+        api.describe(&format!("/v{}", version), routes::api::<S>(version));
+    }
+
+    api.finish()
+}
@@ -0,0 +1,256 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+use tinytemplate::TinyTemplate;
+
+use keyr_types::{KeystrokesPoint, Summary};
+
+static TEMPLATE : &str = include_str!("templates/profile.html");
+
+const CELL_SIZE : u32 = 11;
+const CELL_STEP : u32 = 13;
+const HEATMAP_LEFT : u32 = 30;
+const HEATMAP_TOP : u32 = 15;
+const HEATMAP_WEEKS : u32 = 53;
+
+const BAR_STEP : u32 = 20;
+const BAR_WIDTH : u32 = 16;
+const HISTOGRAM_HEIGHT : u32 = 100;
+
+const RECENT_DAYS : usize = 14;
+
+// From no keystroke at all to the busiest day
+const LEVELS : [&str; 5] =
+    ["#ebedf0", "#c6e48b", "#7bc96f", "#239a3b", "#196127"];
+
+#[derive(Serialize)]
+struct Cell {
+    x : u32,
+    y : u32,
+    size : u32,
+    color : &'static str,
+    title : String,
+}
+
+#[derive(Serialize)]
+struct Label {
+    x : u32,
+    y : u32,
+    text : String,
+}
+
+#[derive(Serialize)]
+struct Heatmap {
+    width : u32,
+    height : u32,
+    cells : Vec<Cell>,
+    labels : Vec<Label>,
+}
+
+#[derive(Serialize)]
+struct Bar {
+    x : u32,
+    y : u32,
+    width : u32,
+    height : u32,
+    title : String,
+}
+
+#[derive(Serialize)]
+struct Histogram {
+    width : u32,
+    height : u32,
+    bars : Vec<Bar>,
+    labels : Vec<Label>,
+}
+
+#[derive(Serialize)]
+struct Day {
+    date : String,
+    count : String,
+}
+
+#[derive(Serialize)]
+struct Page<'a> {
+    name : &'a str,
+    timezone : &'a str,
+    global_count : String,
+    year_count : String,
+    today_count : String,
+    heatmap : Heatmap,
+    histogram : Histogram,
+    recent : Vec<Day>,
+}
+
+// Format a number with a comma every three digits.
+fn format_count(count : u64) -> String {
+    let digits = count.to_string();
+    let mut groups = digits
+        .as_bytes()
+        .rchunks(3)
+        // unwrap is valid since digits are ASCII characters
+        .map(|group| std::str::from_utf8(group).unwrap())
+        .collect::<Vec<_>>();
+
+    groups.reverse();
+    groups.join(",")
+}
+
+fn level(count : u64, max : u64) -> &'static str {
+    if count == 0 || max == 0 {
+        LEVELS[0]
+    } else {
+        // Non-empty days get one of the four remaining levels
+        LEVELS[1 + ((count - 1) * 4 / max) as usize]
+    }
+}
+
+// The first day of the heatmap of `today', that is the Monday of the week
+// which started 52 weeks before the current one.
+pub fn heatmap_start(today : NaiveDate) -> NaiveDate {
+    today
+        - Duration::days(today.weekday().num_days_from_monday() as i64)
+        - Duration::weeks(HEATMAP_WEEKS as i64 - 1)
+}
+
+fn heatmap(daily : &[(NaiveDate, u64)]) -> Heatmap {
+    let max = daily.iter().map(|(_, c)| *c).max().unwrap_or(0);
+    let mut cells = vec![];
+    let mut labels = vec![];
+
+    for (i, (date, count)) in daily.iter().enumerate() {
+        let week = i as u32 / 7;
+        let x = HEATMAP_LEFT + week * CELL_STEP;
+
+        if date.day() == 1 {
+            labels.push(Label {
+                x,
+                y : HEATMAP_TOP - 4,
+                text : date.format("%b").to_string(),
+            });
+        }
+
+        cells.push(Cell {
+            x,
+            y : HEATMAP_TOP + date.weekday().num_days_from_monday() * CELL_STEP,
+            size : CELL_SIZE,
+            color : level(*count, max),
+            title : format!("{}: {}", date, format_count(*count)),
+        });
+    }
+
+    for (row, text) in [(0, "Mon"), (2, "Wed"), (4, "Fri")].iter() {
+        labels.push(Label {
+            x : 0,
+            y : HEATMAP_TOP + row * CELL_STEP + CELL_SIZE - 1,
+            text : text.to_string(),
+        });
+    }
+
+    Heatmap {
+        width : HEATMAP_LEFT + HEATMAP_WEEKS * CELL_STEP,
+        height : HEATMAP_TOP + 7 * CELL_STEP,
+        cells,
+        labels,
+    }
+}
+
+fn histogram(hourly : &[u64; 24]) -> Histogram {
+    let max = hourly.iter().copied().max().unwrap_or(0);
+    let mut bars = vec![];
+    let mut labels = vec![];
+
+    for (hour, count) in hourly.iter().enumerate() {
+        let hour = hour as u32;
+        let height = (*count * HISTOGRAM_HEIGHT as u64)
+            .checked_div(max)
+            .unwrap_or(0) as u32;
+
+        bars.push(Bar {
+            x : hour * BAR_STEP,
+            y : HISTOGRAM_HEIGHT - height,
+            width : BAR_WIDTH,
+            height,
+            title : format!("{:02}:00: {}", hour, format_count(*count)),
+        });
+    }
+
+    for hour in (0..24).step_by(3) {
+        labels.push(Label {
+            x : hour * BAR_STEP,
+            y : HISTOGRAM_HEIGHT + 14,
+            text : format!("{:02}h", hour),
+        });
+    }
+
+    Histogram {
+        width : 24 * BAR_STEP,
+        height : HISTOGRAM_HEIGHT + 20,
+        bars,
+        labels,
+    }
+}
+
+// Render the public profile page of a user. `daily' is expected to be the
+// daily keystrokes series starting at `heatmap_start(today)', in the timezone
+// `tz'.
+pub fn render(
+    name : &str,
+    tz : Tz,
+    summary : &Summary,
+    daily : &[KeystrokesPoint],
+    hourly : &[u64; 24],
+) -> Result<String, tinytemplate::error::Error> {
+    let daily = daily
+        .iter()
+        .map(|p| {
+            let date : DateTime<Utc> = Utc.timestamp(p.timestamp, 0);
+            (date.with_timezone(&tz).date().naive_local(), p.count)
+        })
+        .collect::<Vec<_>>();
+
+    let recent = daily
+        .iter()
+        .rev()
+        .take(RECENT_DAYS)
+        .map(|(date, count)| Day {
+            date : date.format("%a %-d %b %Y").to_string(),
+            count : format_count(*count),
+        })
+        .collect();
+
+    let page = Page {
+        name,
+        timezone : tz.name(),
+        global_count : format_count(summary.global_count),
+        year_count : format_count(daily.iter().map(|(_, c)| *c).sum()),
+        today_count : format_count(summary.today_count),
+        heatmap : heatmap(&daily),
+        histogram : histogram(hourly),
+        recent,
+    };
+
+    let mut tt = TinyTemplate::new();
+    tt.add_template("profile", TEMPLATE)?;
+
+    tt.render("profile", &page)
+}
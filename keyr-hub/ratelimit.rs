@@ -0,0 +1,301 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use futures::future::{ready, Either};
+use futures::{Future, FutureExt};
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use keyr_hubstorage::error::KeyrHubstorageError;
+
+use crate::auth::token_from_headers;
+use crate::config::RateLimitConfig;
+use crate::error::KeyrHubError;
+
+const X_FORWARDED_FOR : &str = "x-forwarded-for";
+
+// A fixed window counting the requests of a client
+struct Window {
+    start : Instant,
+    count : u32,
+}
+
+impl Window {
+    fn new(now : Instant) -> Window {
+        Window {
+            start : now,
+            count : 0,
+        }
+    }
+
+    fn is_expired(&self, now : Instant, length : Duration) -> bool {
+        now.duration_since(self.start) >= length
+    }
+
+    // Count one more event, unless the window is already full, in which case
+    // the time left before it expires is returned.
+    fn hit(
+        &mut self,
+        now : Instant,
+        length : Duration,
+        max : u32,
+    ) -> Option<Duration> {
+        if self.is_expired(now, length) {
+            *self = Window::new(now);
+        }
+
+        if self.count >= max {
+            Some(length - now.duration_since(self.start))
+        } else {
+            self.count += 1;
+            None
+        }
+    }
+}
+
+struct Failures {
+    window : Window,
+    locked_until : Option<Instant>,
+}
+
+struct State {
+    ips : HashMap<IpAddr, Window>,
+    tokens : HashMap<String, Window>,
+    failures : HashMap<IpAddr, Failures>,
+    last_prune : Instant,
+}
+
+pub struct RateLimiter {
+    conf : RateLimitConfig,
+    state : Mutex<State>,
+}
+
+fn parse_ip(entry : &str) -> Option<IpAddr> {
+    entry
+        .parse::<SocketAddr>()
+        .map(|addr| addr.ip())
+        .or_else(|_| entry.parse::<IpAddr>())
+        .ok()
+}
+
+// The key the requests of a client are counted with. An IPv6 client
+// usually gets a whole /64, and could otherwise pick a new address for
+// every request.
+fn client_key(ip : IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => {
+                IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !(u64::MAX as u128)))
+            }
+        },
+    }
+}
+
+// `Retry-After' is expressed in seconds, and a client should never be told
+// to retry right away
+fn seconds(d : Duration) -> u64 {
+    d.as_secs() + if d.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+impl RateLimiter {
+    pub fn new(conf : RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            conf,
+            state : Mutex::new(State {
+                ips : HashMap::new(),
+                tokens : HashMap::new(),
+                failures : HashMap::new(),
+                last_prune : Instant::now(),
+            }),
+        }
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.conf.window)
+    }
+
+    fn lockout(&self) -> Duration {
+        Duration::from_secs(self.conf.lockout)
+    }
+
+    // The address of the client. Behind `trusted_proxies' reverse proxies,
+    // it is the entry of `X-Forwarded-For' added by the outermost of them,
+    // that is the `trusted_proxies'-th one starting from the right: the
+    // entries on its left are set by the client, and cannot be trusted. A
+    // request which did not go through every proxy is attributed to its
+    // peer.
+    fn client_ip(&self, req : &ServiceRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        let hops = self.conf.trusted_proxies as usize;
+
+        if hops == 0 {
+            return peer;
+        }
+
+        let entries = req
+            .headers()
+            .get_all(X_FORWARDED_FOR)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>();
+
+        entries
+            .len()
+            .checked_sub(hops)
+            .and_then(|i| parse_ip(entries[i]))
+            .or(peer)
+    }
+
+    // Forget about the clients whose windows have expired, so that the
+    // memory used by the limiter does not grow forever.
+    fn prune(&self, state : &mut State, now : Instant) {
+        let window = self.window();
+
+        if now.duration_since(state.last_prune) < window {
+            return;
+        }
+
+        state.ips.retain(|_, w| !w.is_expired(now, window));
+        state.tokens.retain(|_, w| !w.is_expired(now, window));
+        state.failures.retain(|_, f| {
+            !f.window.is_expired(now, window)
+                || f.locked_until.map(|t| t > now).unwrap_or(false)
+        });
+        state.last_prune = now;
+    }
+
+    pub fn check(
+        &self,
+        ip : Option<IpAddr>,
+        token : Option<&str>,
+    ) -> Result<(), KeyrHubError> {
+        let now = Instant::now();
+        let window = self.window();
+        // unwrap is valid since the lock is never held by a panicking thread
+        let mut state = self.state.lock().unwrap();
+
+        self.prune(&mut state, now);
+
+        if let Some(ip) = ip {
+            if let Some(until) =
+                state.failures.get(&ip).and_then(|f| f.locked_until)
+            {
+                if until > now {
+                    return Err(KeyrHubError::LockedOut(seconds(until - now)));
+                }
+            }
+
+            if let Some(wait) = state
+                .ips
+                .entry(ip)
+                .or_insert_with(|| Window::new(now))
+                .hit(now, window, self.conf.requests_per_ip)
+            {
+                return Err(KeyrHubError::RateLimited(seconds(wait)));
+            }
+        }
+
+        if let Some(token) = token {
+            if let Some(wait) = state
+                .tokens
+                .entry(token.to_owned())
+                .or_insert_with(|| Window::new(now))
+                .hit(now, window, self.conf.requests_per_token)
+            {
+                return Err(KeyrHubError::RateLimited(seconds(wait)));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Count a request carrying an invalid token, and lock the client out once
+    // it has sent too many of them.
+    pub fn record_failure(&self, ip : IpAddr) {
+        let now = Instant::now();
+        let window = self.window();
+        // unwrap is valid since the lock is never held by a panicking thread
+        let mut state = self.state.lock().unwrap();
+
+        let failures = state.failures.entry(ip).or_insert_with(|| Failures {
+            window : Window::new(now),
+            locked_until : None,
+        });
+
+        failures.window.hit(now, window, u32::MAX);
+
+        if failures.window.count >= self.conf.failed_lookups {
+            failures.locked_until = Some(now + self.lockout());
+            failures.window = Window::new(now);
+        }
+    }
+}
+
+fn is_invalid_token(res : &ServiceResponse) -> bool {
+    matches!(
+        res.response()
+            .error()
+            .and_then(|e| e.as_error::<KeyrHubError>()),
+        Some(KeyrHubError::Storage(KeyrHubstorageError::InvalidToken))
+    )
+}
+
+// The middleware rejecting the requests of the clients which exceed their
+// quota, and counting the failed token lookups.
+pub fn limit<S>(
+    limiter : Arc<RateLimiter>,
+    req : ServiceRequest,
+    srv : &mut S,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>>
+where
+    S : Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+    >,
+{
+    if !limiter.conf.enabled {
+        return Either::Left(srv.call(req));
+    }
+
+    let ip = limiter.client_ip(&req).map(client_key);
+    let token = token_from_headers(req.headers()).ok();
+
+    match limiter.check(ip, token.as_ref().map(|t| t.0.as_str())) {
+        Err(err) => {
+            Either::Right(Either::Left(ready(Ok(req.error_response(err)))))
+        }
+        Ok(()) => Either::Right(Either::Right(srv.call(req).map(move |res| {
+            if let (Ok(res), Some(ip)) = (&res, ip) {
+                if is_invalid_token(res) {
+                    limiter.record_failure(ip);
+                }
+            }
+
+            res
+        }))),
+    }
+}
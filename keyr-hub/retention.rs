@@ -0,0 +1,83 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use actix_web::rt::time::interval;
+use actix_web::web::{self, Data};
+use anyhow::bail;
+use chrono::Utc;
+use tracing::{error, info};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use keyr_hubstorage as khs;
+use khs::error::Result;
+use khs::retention::{CompactionReport, Retention};
+use khs::store::HubStore;
+
+// Apply the retention policy of every user once
+pub fn compact<S : HubStore>(
+    store : &S,
+    retention : &Retention,
+) -> Result<CompactionReport> {
+    let report = store.compact(retention, Utc::now())?;
+
+    info!(
+        rolled_up = report.rolled_up,
+        deleted = report.deleted,
+        skipped = report.skipped,
+        "statistics compacted"
+    );
+
+    Ok(report)
+}
+
+// Apply the retention policy of every user every `every' seconds, in the
+// background. The compaction runs in the thread pool of actix, so that the
+// database is not queried from the event loop.
+pub fn schedule<S : HubStore>(
+    store : Data<S>,
+    retention : Retention,
+    every : u64,
+) -> anyhow::Result<()> {
+    if every == 0 {
+        bail!("`interval' in the `[retention]' section cannot be 0");
+    }
+
+    let retention = Arc::new(retention);
+
+    actix_web::rt::spawn(async move {
+        let mut ticks = interval(Duration::from_secs(every));
+
+        loop {
+            ticks.tick().await;
+
+            let store = store.clone();
+            let retention = retention.clone();
+
+            if let Err(err) =
+                web::block(move || compact(store.get_ref(), &retention)).await
+            {
+                error!(error = %err, "compaction failed");
+            }
+        }
+    });
+
+    Ok(())
+}
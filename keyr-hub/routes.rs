@@ -0,0 +1,419 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::{resource, to, ServiceConfig};
+use actix_web::Route;
+use serde_json::json;
+
+use std::sync::Arc;
+
+use keyr_hubstorage::error::KeyrHubstorageError;
+use keyr_hubstorage::store::HubStore;
+use keyr_types::{
+    KeystrokesSeries, KeystrokesStats, Leaderboard, Liveness, Readiness,
+    RevertPage, RevertRequest, RevertSession, Summary, SynchronizeRequest,
+    UserCalendar, UserExport, VersionInfo, PROTOCOL_VERSION,
+};
+
+use crate::cors::{self, Cors};
+use crate::error::KeyrHubError;
+use crate::openapi::Operation;
+use crate::{
+    commit, delete_user, export_user, get_calendar, healthz, leaderboard,
+    openapi_json, readyz, revert_cancel, revert_cancel_v1, revert_initiate,
+    revert_initiate_v1, revert_statistics, revert_terminate,
+    revert_terminate_v1, set_calendar, summary, version, view_badge,
+    view_profile, view_stats, BadgeQuery, ExportQuery, LeaderboardQuery,
+    ProfileQuery, RevertPageQuery, ViewQuery,
+};
+
+// A route served by the hub, along with its description in the OpenAPI
+// document. Both are derived from the same tables, so that the document
+// cannot drift from the routes.
+pub(crate) struct Endpoint {
+    pub(crate) method : Method,
+    pub(crate) path : &'static str,
+    pub(crate) summary : &'static str,
+    // Whether the route can be called from other origins
+    pub(crate) public : bool,
+    pub(crate) route : Route,
+    pub(crate) describe : for<'a> fn(Operation<'a>) -> Operation<'a>,
+}
+
+impl Endpoint {
+    fn new(
+        method : Method,
+        path : &'static str,
+        summary : &'static str,
+        route : Route,
+    ) -> Endpoint {
+        Endpoint {
+            method,
+            path,
+            summary,
+            public : false,
+            route,
+            describe : |op| op,
+        }
+    }
+
+    fn public(mut self) -> Endpoint {
+        self.public = true;
+        self
+    }
+
+    fn describe(
+        mut self,
+        describe : for<'a> fn(Operation<'a>) -> Operation<'a>,
+    ) -> Endpoint {
+        self.describe = describe;
+        self
+    }
+}
+
+// The errors of the routes which expose the statistics of a visible user
+fn visibility_errors() -> Vec<KeyrHubError> {
+    vec![
+        KeyrHubError::Storage(KeyrHubstorageError::UnknownUser),
+        KeyrHubError::PrivateData,
+    ]
+}
+
+fn unknown_revert_session() -> KeyrHubError {
+    KeyrHubError::Storage(KeyrHubstorageError::UnknownRevertSession)
+}
+
+fn unknown_timezone() -> KeyrHubError {
+    KeyrHubError::UnknownTimezone("<tz>".to_owned())
+}
+
+fn frozen_user() -> KeyrHubError {
+    KeyrHubError::Storage(KeyrHubstorageError::FrozenUser)
+}
+
+// The versions of the API served by the hub
+pub const PROTOCOLS : [u32; 2] = [1, PROTOCOL_VERSION];
+
+// The routes served at the root of the hub, which are not versioned
+pub(crate) fn root<S : HubStore>() -> Vec<Endpoint> {
+    vec![
+        Endpoint::new(
+            Method::GET,
+            "/version",
+            "Fetch the supported protocol versions",
+            to(version),
+        )
+        .describe(|op| op.response::<VersionInfo>(StatusCode::OK)),
+        Endpoint::new(
+            Method::GET,
+            "/healthz",
+            "Check that the hub is alive",
+            to(healthz),
+        )
+        .describe(|op| op.response::<Liveness>(StatusCode::OK)),
+        Endpoint::new(
+            Method::GET,
+            "/readyz",
+            "Check that the hub can serve requests",
+            to(readyz::<S>),
+        )
+        .describe(|op| {
+            op.response::<Readiness>(StatusCode::OK)
+                .response::<Readiness>(StatusCode::SERVICE_UNAVAILABLE)
+        }),
+    ]
+}
+
+// The routes of the API, served under `/v{version}'. The routes of the first
+// version are also served at the root of the hub, for compatibility.
+pub(crate) fn api<S : HubStore>(version : u32) -> Vec<Endpoint> {
+    let mut endpoints = vec![
+        Endpoint::new(
+            Method::POST,
+            "/commit",
+            "Commit keystrokes statistics",
+            to(commit::<S>),
+        )
+        .describe(|op| {
+            op.authenticated()
+                .request::<SynchronizeRequest>()
+                .response::<Summary>(StatusCode::OK)
+                .errors(vec![frozen_user()])
+        }),
+        Endpoint::new(
+            Method::GET,
+            "/summary",
+            "Fetch the summary of the user",
+            to(summary::<S>),
+        )
+        .describe(|op| op.authenticated().response::<Summary>(StatusCode::OK)),
+        Endpoint::new(
+            Method::GET,
+            "/me/calendar",
+            "Fetch the calendar of the user",
+            to(get_calendar::<S>),
+        )
+        .describe(|op| {
+            op.authenticated().response::<UserCalendar>(StatusCode::OK)
+        }),
+        Endpoint::new(
+            Method::POST,
+            "/me/calendar",
+            "Set the calendar of the user",
+            to(set_calendar::<S>),
+        )
+        .describe(|op| {
+            op.authenticated()
+                .request::<UserCalendar>()
+                .response::<UserCalendar>(StatusCode::OK)
+                .errors(vec![
+                    unknown_timezone(),
+                    KeyrHubError::InvalidDayStart(24),
+                ])
+        }),
+        Endpoint::new(
+            Method::GET,
+            "/me/export",
+            "Export everything about the user",
+            to(export_user::<S>),
+        )
+        .describe(|op| {
+            op.authenticated()
+                .query::<ExportQuery>()
+                .response::<UserExport>(StatusCode::OK)
+                .content(
+                    StatusCode::OK,
+                    "text/csv",
+                    json!({ "type": "string" }),
+                )
+        }),
+        Endpoint::new(
+            Method::DELETE,
+            "/me",
+            "Erase the user and their statistics",
+            to(delete_user::<S>),
+        )
+        .describe(|op| op.authenticated().response::<()>(StatusCode::OK)),
+        Endpoint::new(
+            Method::GET,
+            "/view/{name}",
+            "Fetch the statistics of a user",
+            to(view_stats::<S>),
+        )
+        .public()
+        .describe(|op| {
+            op.query::<ViewQuery>()
+                .response::<KeystrokesSeries>(StatusCode::OK)
+                .errors(visibility_errors())
+                .errors(vec![
+                    unknown_timezone(),
+                    KeyrHubError::Storage(KeyrHubstorageError::UnknownMachine),
+                    KeyrHubError::InvalidTimestamp(0),
+                    KeyrHubError::InvalidRange,
+                    KeyrHubError::Storage(KeyrHubstorageError::TooLongSeries(
+                        keyr_hubstorage::stats::MAX_SERIES_LENGTH,
+                    )),
+                ])
+        }),
+        Endpoint::new(
+            Method::GET,
+            "/u/{name}",
+            "Render the profile page of a user",
+            to(view_profile::<S>),
+        )
+        .public()
+        .describe(|op| {
+            op.query::<ProfileQuery>()
+                .content(
+                    StatusCode::OK,
+                    "text/html",
+                    json!({ "type": "string" }),
+                )
+                .errors(visibility_errors())
+                .errors(vec![unknown_timezone()])
+        }),
+        Endpoint::new(
+            Method::GET,
+            "/badge/{name}.svg",
+            "Render the badge of a user",
+            to(view_badge::<S>),
+        )
+        .public()
+        .describe(|op| {
+            op.query::<BadgeQuery>()
+                .content(
+                    StatusCode::OK,
+                    "image/svg+xml",
+                    json!({ "type": "string" }),
+                )
+                .errors(visibility_errors())
+        }),
+        Endpoint::new(
+            Method::GET,
+            "/leaderboard",
+            "Rank the visible users",
+            to(leaderboard::<S>),
+        )
+        .public()
+        .describe(|op| {
+            op.query::<LeaderboardQuery>()
+                .response::<Leaderboard>(StatusCode::OK)
+        }),
+        Endpoint::new(
+            Method::GET,
+            "/openapi.json",
+            "Describe the API of the hub",
+            to(openapi_json::<S>),
+        )
+        .public()
+        .describe(|op| {
+            op.content(
+                StatusCode::OK,
+                "application/json",
+                json!({ "type": "object" }),
+            )
+        }),
+    ];
+
+    endpoints.extend(reverts::<S>(version));
+    endpoints
+}
+
+// The first version of the API handed out the whole statistics of the user
+// when initiating a revert. They are fetched page by page since the second
+// one.
+fn reverts<S : HubStore>(version : u32) -> Vec<Endpoint> {
+    if version == 1 {
+        return vec![
+            Endpoint::new(
+                Method::POST,
+                "/revert/initiate",
+                "Freeze the user and fetch their statistics",
+                to(revert_initiate_v1::<S>),
+            )
+            .describe(|op| {
+                op.authenticated()
+                    .response::<KeystrokesStats>(StatusCode::OK)
+                    .errors(vec![frozen_user()])
+            }),
+            Endpoint::new(
+                Method::POST,
+                "/revert/terminate",
+                "Delete the statistics of the user and unfreeze them",
+                to(revert_terminate_v1::<S>),
+            )
+            .describe(|op| {
+                op.authenticated()
+                    .response::<()>(StatusCode::OK)
+                    .errors(vec![unknown_revert_session()])
+            }),
+            Endpoint::new(
+                Method::POST,
+                "/revert/cancel",
+                "Unfreeze the user",
+                to(revert_cancel_v1::<S>),
+            )
+            .describe(|op| {
+                op.authenticated()
+                    .response::<()>(StatusCode::OK)
+                    .errors(vec![unknown_revert_session()])
+            }),
+        ];
+    }
+
+    vec![
+        Endpoint::new(
+            Method::POST,
+            "/revert/initiate",
+            "Freeze the user",
+            to(revert_initiate::<S>),
+        )
+        .describe(|op| {
+            op.authenticated()
+                .response::<RevertSession>(StatusCode::OK)
+                .errors(vec![frozen_user()])
+        }),
+        Endpoint::new(
+            Method::GET,
+            "/revert/statistics",
+            "Fetch a page of the statistics of the frozen user",
+            to(revert_statistics::<S>),
+        )
+        .describe(|op| {
+            op.authenticated()
+                .query::<RevertPageQuery>()
+                .response::<RevertPage>(StatusCode::OK)
+                .errors(vec![
+                    unknown_revert_session(),
+                    KeyrHubError::InvalidTimestamp(0),
+                ])
+        }),
+        Endpoint::new(
+            Method::POST,
+            "/revert/terminate",
+            "Delete the statistics of the user and unfreeze them",
+            to(revert_terminate::<S>),
+        )
+        .describe(|op| {
+            op.authenticated()
+                .request::<RevertRequest>()
+                .response::<()>(StatusCode::OK)
+                .errors(vec![unknown_revert_session()])
+        }),
+        Endpoint::new(
+            Method::POST,
+            "/revert/cancel",
+            "Unfreeze the user",
+            to(revert_cancel::<S>),
+        )
+        .describe(|op| {
+            op.authenticated()
+                .request::<RevertRequest>()
+                .response::<()>(StatusCode::OK)
+                .errors(vec![unknown_revert_session()])
+        }),
+    ]
+}
+
+// Register `endpoints'. The public ones are wrapped with the CORS policy of
+// the hub, which answers their preflight requests.
+pub(crate) fn configure(
+    cfg : &mut ServiceConfig,
+    endpoints : Vec<Endpoint>,
+    cors : &Option<Arc<Cors>>,
+) {
+    for endpoint in endpoints {
+        let route = endpoint.route.method(endpoint.method);
+
+        if endpoint.public {
+            let cors = cors.clone();
+
+            cfg.service(
+                resource(endpoint.path)
+                    .wrap_fn(move |req, srv| {
+                        cors::apply(cors.clone(), req, srv)
+                    })
+                    .route(route),
+            );
+        } else {
+            cfg.route(endpoint.path, route);
+        }
+    }
+}
@@ -0,0 +1,1007 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// The HTTP layer of the hub is tested end-to-end against an in-memory store,
+// so that no database server is needed.
+
+use actix_web::error::ResponseError;
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::test::{self, TestRequest};
+use actix_web::web::Data;
+use chrono::{DateTime, TimeZone, Timelike, Utc};
+use serde_json::json;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use keyr_hub::config::{
+    CorsConfig, RateLimitConfig, RetentionConfig, RevertConfig,
+};
+use keyr_hub::cors::Cors;
+use keyr_hub::error::KeyrHubError;
+use keyr_hub::metrics::Metrics;
+use keyr_hub::ratelimit::RateLimiter;
+use keyr_hub::{app, Hub};
+use keyr_hubstorage::error::KeyrHubstorageError;
+use keyr_hubstorage::memory::MemoryStore;
+use keyr_hubstorage::store::HubStore;
+use keyr_hubstorage::time::to_local;
+use keyr_types::{
+    KeystrokesSeries, KeystrokesStats, Leaderboard, Readiness, RevertPage,
+    RevertSession, Summary, SynchronizeRequest, UserCalendar, UserExport,
+    VersionInfo,
+};
+
+fn hub(store : MemoryStore) -> Hub<MemoryStore> {
+    Hub {
+        store : Data::new(store),
+        metrics : Data::new(Metrics::new(false).unwrap()),
+        limiter : Arc::new(RateLimiter::new(RateLimitConfig::default())),
+        cors : None,
+        revert : RevertConfig::default(),
+        max_request_size : 65_536,
+    }
+}
+
+// Create a user with one machine, and return the token of this machine
+fn register(store : &MemoryStore, name : &str, visible : bool) -> String {
+    let user = store.create_user(name.to_owned()).unwrap();
+    let machine = store.create_machine(user, "laptop".to_owned()).unwrap();
+
+    store.set_visible(user, visible).unwrap();
+
+    store.generate_token(user, machine).unwrap().0
+}
+
+// The hub decides which day it is, so the tests follow the clock
+fn today() -> DateTime<Utc> {
+    Utc::today().and_hms(0, 0, 0)
+}
+
+fn commit_request(token : &str, counts : &[(i64, u32)]) -> TestRequest {
+    let staging_area = counts
+        .iter()
+        .map(|(hour, count)| (today().timestamp() + hour * 3600, *count))
+        .collect();
+
+    TestRequest::post()
+        .uri("/v1/commit")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token))
+        .set_json(&SynchronizeRequest { staging_area })
+}
+
+#[actix_rt::test]
+async fn commit_returns_the_summary() {
+    let store = MemoryStore::new();
+    let token = register(&store, "alice", false);
+    let mut app = test::init_service(app(hub(store))).await;
+
+    let resp = test::call_service(
+        &mut app,
+        commit_request(&token, &[(1, 10), (2, 5)]).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let summary : Summary = test::read_body_json(resp).await;
+    assert_eq!(summary.global_count, 15);
+    assert_eq!(summary.today_count, 15);
+    assert_eq!(summary.machines.len(), 1);
+    assert_eq!(summary.machines[0].name, "laptop");
+
+    let req = TestRequest::get()
+        .uri("/v1/summary")
+        .header("Keyr-Token", token.as_str());
+    let summary : Summary = test::read_body_json(
+        test::call_service(&mut app, req.to_request()).await,
+    )
+    .await;
+    assert_eq!(summary.global_count, 15);
+}
+
+#[actix_rt::test]
+async fn invalid_tokens_are_challenged() {
+    let mut app = test::init_service(app(hub(MemoryStore::new()))).await;
+
+    let resp = test::call_service(
+        &mut app,
+        commit_request("unknown", &[(0, 1)]).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(resp.headers().contains_key(header::WWW_AUTHENTICATE));
+}
+
+#[actix_rt::test]
+async fn only_visible_users_can_be_viewed() {
+    let store = MemoryStore::new();
+    let alice = register(&store, "alice", true);
+    let bob = register(&store, "bob", false);
+    let mut app = test::init_service(app(hub(store))).await;
+
+    test::call_service(
+        &mut app,
+        commit_request(&alice, &[(1, 10), (25, 5)]).to_request(),
+    )
+    .await;
+    test::call_service(&mut app, commit_request(&bob, &[(1, 10)]).to_request())
+        .await;
+
+    let req = TestRequest::get().uri(&format!(
+        "/v1/view/alice?from={}&to={}",
+        today().timestamp(),
+        today().timestamp() + 3 * 86400,
+    ));
+    let resp = test::call_service(&mut app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let series : KeystrokesSeries = test::read_body_json(resp).await;
+    let counts = series.iter().map(|p| p.count).collect::<Vec<_>>();
+    assert_eq!(counts, vec![10, 5, 0]);
+
+    let resp = test::call_service(
+        &mut app,
+        TestRequest::get().uri("/v1/view/bob").to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert!(resp.headers().get(header::WWW_AUTHENTICATE).is_none());
+
+    let resp = test::call_service(
+        &mut app,
+        TestRequest::get().uri("/v1/u/alice").to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = TestRequest::get().uri("/v1/badge/alice.svg");
+    let resp = test::call_service(&mut app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "image/svg+xml; charset=utf-8"
+    );
+}
+
+#[actix_rt::test]
+async fn invalid_ranges_are_rejected() {
+    let store = MemoryStore::new();
+    register(&store, "alice", true);
+    let mut app = test::init_service(app(hub(store))).await;
+
+    let queries = [
+        format!("from={}", i64::MAX),
+        format!("to={}", i64::MIN),
+        format!(
+            "from={}&to={}",
+            today().timestamp() + 1,
+            today().timestamp()
+        ),
+        // Far too many points
+        "from=0&granularity=hour".to_owned(),
+        format!("from=0&to={}&granularity=month", i64::from(i32::MAX) * 1000),
+    ];
+
+    for query in queries.iter() {
+        let req = TestRequest::get().uri(&format!("/v1/view/alice?{}", query));
+        assert_eq!(
+            test::call_service(&mut app, req.to_request())
+                .await
+                .status(),
+            StatusCode::BAD_REQUEST,
+            "{}",
+            query
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn users_choose_their_calendar() {
+    let store = MemoryStore::new();
+    let token = register(&store, "alice", false);
+    let mut app = test::init_service(app(hub(store))).await;
+
+    let set_calendar = |timezone : &str, day_start : u32| {
+        TestRequest::post()
+            .uri("/v1/me/calendar")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .set_json(&UserCalendar {
+                timezone : timezone.to_owned(),
+                day_start,
+            })
+            .to_request()
+    };
+
+    let resp =
+        test::call_service(&mut app, set_calendar("Mars/Olympus", 0)).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let resp =
+        test::call_service(&mut app, set_calendar("Europe/Paris", 24)).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let resp =
+        test::call_service(&mut app, set_calendar("Europe/Paris", 4)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = TestRequest::get()
+        .uri("/v1/me/calendar")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token));
+    let calendar : UserCalendar = test::read_body_json(
+        test::call_service(&mut app, req.to_request()).await,
+    )
+    .await;
+    assert_eq!(
+        calendar,
+        UserCalendar {
+            timezone : "Europe/Paris".to_owned(),
+            day_start : 4,
+        }
+    );
+
+    // The day of the user is decided by the hub, whatever the agent says
+    let summary : Summary = test::read_body_json(
+        test::call_service(&mut app, commit_request(&token, &[]).to_request())
+            .await,
+    )
+    .await;
+    let today = Utc.timestamp(summary.today_timestamp, 0);
+    assert_eq!(to_local(chrono_tz::Europe::Paris, today).hour(), 4);
+}
+
+#[actix_rt::test]
+async fn users_export_their_data() {
+    let store = MemoryStore::new();
+    let token = register(&store, "alice", true);
+    let mut app = test::init_service(app(hub(store))).await;
+
+    test::call_service(
+        &mut app,
+        commit_request(&token, &[(1, 10), (2, 5)]).to_request(),
+    )
+    .await;
+
+    let export = |format : &str| {
+        TestRequest::get()
+            .uri(&format!("/v1/me/export{}", format))
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .to_request()
+    };
+
+    let resp = test::call_service(&mut app, export("")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CACHE_CONTROL).unwrap(),
+        "no-store"
+    );
+
+    let data : UserExport = test::read_body_json(resp).await;
+    assert_eq!(data.name, "alice");
+    assert!(data.visible);
+    assert_eq!(data.machines, vec!["laptop"]);
+    assert_eq!(data.tokens.len(), 1);
+    assert!(token.ends_with(&data.tokens[0].hint));
+    assert_eq!(data.statistics.len(), 2);
+    assert_eq!(data.statistics[0].timestamp, today().timestamp() + 3600);
+    assert_eq!(data.statistics[0].count, 10);
+
+    let resp = test::call_service(&mut app, export("?format=csv")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "text/csv; charset=utf-8"
+    );
+
+    let body = test::read_body(resp).await;
+    let csv = std::str::from_utf8(&body).unwrap();
+    let lines = csv.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "record,field,value,machine,timestamp,count");
+    assert!(lines.contains(&"profile,name,alice,,,"));
+    assert!(lines.contains(&"machine,,,laptop,,"));
+    assert!(lines.contains(
+        &format!("statistics,,,laptop,{},5", today().timestamp() + 7200)
+            .as_str()
+    ));
+    assert!(!csv.contains(&token));
+}
+
+#[actix_rt::test]
+async fn users_delete_their_account() {
+    let store = MemoryStore::new();
+    let alice = register(&store, "alice", true);
+    let bob = register(&store, "bob", true);
+    let mut app = test::init_service(app(hub(store))).await;
+
+    for token in &[&alice, &bob] {
+        test::call_service(
+            &mut app,
+            commit_request(token, &[(1, 10)]).to_request(),
+        )
+        .await;
+    }
+
+    let req = TestRequest::delete()
+        .uri("/v1/me")
+        .header(header::AUTHORIZATION, format!("Bearer {}", alice));
+    assert_eq!(
+        test::call_service(&mut app, req.to_request())
+            .await
+            .status(),
+        StatusCode::OK
+    );
+
+    // The token of the user is gone with them
+    let req = TestRequest::get()
+        .uri("/v1/summary")
+        .header(header::AUTHORIZATION, format!("Bearer {}", alice));
+    assert_eq!(
+        test::call_service(&mut app, req.to_request())
+            .await
+            .status(),
+        StatusCode::UNAUTHORIZED
+    );
+
+    let req = TestRequest::get().uri("/v1/leaderboard?period=all");
+    let leaderboard : Leaderboard = test::read_body_json(
+        test::call_service(&mut app, req.to_request()).await,
+    )
+    .await;
+    assert_eq!(leaderboard.len(), 1);
+    assert_eq!(leaderboard[0].name, "bob");
+}
+
+#[actix_rt::test]
+async fn leaderboard_ranks_visible_users() {
+    let store = MemoryStore::new();
+    let alice = register(&store, "alice", true);
+    let bob = register(&store, "bob", true);
+    let carol = register(&store, "carol", false);
+    let mut app = test::init_service(app(hub(store))).await;
+
+    test::call_service(
+        &mut app,
+        commit_request(&alice, &[(0, 10)]).to_request(),
+    )
+    .await;
+    test::call_service(&mut app, commit_request(&bob, &[(0, 20)]).to_request())
+        .await;
+    test::call_service(
+        &mut app,
+        commit_request(&carol, &[(0, 30)]).to_request(),
+    )
+    .await;
+
+    let req = TestRequest::get().uri("/v1/leaderboard?period=all");
+    let leaderboard : Leaderboard = test::read_body_json(
+        test::call_service(&mut app, req.to_request()).await,
+    )
+    .await;
+    let ranks = leaderboard
+        .iter()
+        .map(|e| (e.rank, e.name.as_str(), e.count))
+        .collect::<Vec<_>>();
+
+    assert_eq!(ranks, vec![(1, "bob", 20), (2, "alice", 10)]);
+}
+
+#[actix_rt::test]
+async fn revert_hands_out_every_page() {
+    let store = MemoryStore::new();
+    let token = register(&store, "alice", false);
+    let mut app = test::init_service(app(hub(store))).await;
+
+    test::call_service(
+        &mut app,
+        commit_request(&token, &[(0, 1), (1, 2), (2, 3)]).to_request(),
+    )
+    .await;
+
+    let req = TestRequest::post()
+        .uri("/v2/revert/initiate")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token));
+    let session : RevertSession = test::read_body_json(
+        test::call_service(&mut app, req.to_request()).await,
+    )
+    .await;
+
+    // The user is frozen until the end of the revert
+    let resp = test::call_service(
+        &mut app,
+        commit_request(&token, &[(3, 1)]).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+    let mut statistics = HashMap::new();
+    let mut after = None;
+
+    loop {
+        let mut uri = format!(
+            "/v2/revert/statistics?session={}&limit=2",
+            session.session
+        );
+
+        if let Some(after) = after {
+            uri = format!("{}&after={}", uri, after);
+        }
+
+        let req = TestRequest::get()
+            .uri(&uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", token));
+        let page : RevertPage = test::read_body_json(
+            test::call_service(&mut app, req.to_request()).await,
+        )
+        .await;
+
+        statistics.extend(page.statistics);
+
+        match page.next {
+            Some(next) => after = Some(next),
+            None => break,
+        }
+    }
+
+    assert_eq!(statistics.values().sum::<u32>(), 6);
+    assert_eq!(statistics.len(), 3);
+
+    let req = TestRequest::post()
+        .uri("/v2/revert/terminate")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token))
+        .set_json(&json!({ "session": session.session }));
+    assert_eq!(
+        test::call_service(&mut app, req.to_request())
+            .await
+            .status(),
+        StatusCode::OK
+    );
+
+    let resp =
+        test::call_service(&mut app, commit_request(&token, &[]).to_request())
+            .await;
+    let summary : Summary = test::read_body_json(resp).await;
+    assert_eq!(summary.global_count, 0);
+}
+
+#[actix_rt::test]
+async fn unknown_revert_sessions_are_not_found() {
+    let store = MemoryStore::new();
+    let token = register(&store, "alice", false);
+    let mut app = test::init_service(app(hub(store))).await;
+
+    let req = TestRequest::post()
+        .uri("/v2/revert/cancel")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token))
+        .set_json(&json!({ "session": "unknown" }));
+    let resp = test::call_service(&mut app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // The agent tells an expired session from any other 404 by its body
+    let body = test::read_body(resp).await;
+    assert_eq!(body, "Unknown or expired revert session");
+
+    let req = TestRequest::get()
+        .uri(&format!(
+            "/v2/revert/statistics?session=unknown&after={}",
+            i64::MAX
+        ))
+        .header(header::AUTHORIZATION, format!("Bearer {}", token));
+    let resp = test::call_service(&mut app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+async fn first_protocol_reverts_at_once() {
+    let store = MemoryStore::new();
+    let token = register(&store, "alice", false);
+    let mut app = test::init_service(app(hub(store))).await;
+
+    test::call_service(
+        &mut app,
+        commit_request(&token, &[(0, 1), (1, 2), (2, 3)]).to_request(),
+    )
+    .await;
+
+    let version : VersionInfo = test::read_body_json(
+        test::call_service(
+            &mut app,
+            TestRequest::get().uri("/version").to_request(),
+        )
+        .await,
+    )
+    .await;
+    assert_eq!(version.protocols, vec![1, 2]);
+
+    for prefix in &["/v1", ""] {
+        let req = |route : &str| {
+            TestRequest::post()
+                .uri(&format!("{}/revert/{}", prefix, route))
+                .header("Keyr-Token", token.as_str())
+                .to_request()
+        };
+
+        let resp = test::call_service(&mut app, req("initiate")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let statistics : KeystrokesStats = test::read_body_json(resp).await;
+        assert_eq!(statistics.values().sum::<u32>(), 6);
+
+        let resp = test::call_service(&mut app, req("cancel")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // The session is gone
+        let resp = test::call_service(&mut app, req("cancel")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    // The statistics are only paginated since the second version
+    let req = TestRequest::get()
+        .uri("/v1/revert/statistics?session=unknown")
+        .header("Keyr-Token", token.as_str());
+    let resp = test::call_service(&mut app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let initiate = TestRequest::post()
+        .uri("/v1/revert/initiate")
+        .header("Keyr-Token", token.as_str());
+    test::call_service(&mut app, initiate.to_request()).await;
+
+    let terminate = TestRequest::post()
+        .uri("/v1/revert/terminate")
+        .header("Keyr-Token", token.as_str());
+    let resp = test::call_service(&mut app, terminate.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp =
+        test::call_service(&mut app, commit_request(&token, &[]).to_request())
+            .await;
+    let summary : Summary = test::read_body_json(resp).await;
+    assert_eq!(summary.global_count, 0);
+}
+
+#[actix_rt::test]
+async fn retention_policies_apply_per_user() {
+    let store = MemoryStore::new();
+    let alice = register(&store, "alice", false);
+    let bob = register(&store, "bob", false);
+    let hub = hub(store);
+    let store = hub.store.clone();
+    let mut app = test::init_service(app(hub)).await;
+
+    for token in &[&alice, &bob] {
+        let req = commit_request(token, &[(-240, 10), (-239, 5), (1, 1)]);
+        assert_eq!(
+            test::call_service(&mut app, req.to_request())
+                .await
+                .status(),
+            StatusCode::OK
+        );
+    }
+
+    // `bob' inherits `hourly_days' from the section
+    let conf : RetentionConfig = toml::from_str(
+        r#"
+            hourly_days = 2
+
+            [users.bob]
+            max_age_days = 5
+        "#,
+    )
+    .unwrap();
+
+    let report = store.compact(&conf.retention(), Utc::now()).unwrap();
+    assert_eq!(report.rolled_up, 2);
+    assert_eq!(report.deleted, 2);
+
+    let summary = |name : &str| {
+        let user = store.find_user_by_name(name).unwrap();
+
+        store.get_summary(user, Utc::now()).unwrap().global_count
+    };
+    assert_eq!(summary("alice"), 16);
+    assert_eq!(summary("bob"), 1);
+}
+
+#[actix_rt::test]
+async fn memory_store_is_ready() {
+    let mut app = test::init_service(app(hub(MemoryStore::new()))).await;
+
+    let resp = test::call_service(
+        &mut app,
+        TestRequest::get().uri("/readyz").to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let readiness : Readiness = test::read_body_json(resp).await;
+    assert!(readiness.migrations.pending.is_empty());
+}
+
+#[actix_rt::test]
+async fn oversized_requests_are_rejected() {
+    let store = MemoryStore::new();
+    let token = register(&store, "alice", false);
+    let mut app = test::init_service(app(hub(store))).await;
+
+    let counts = (0..10_000).map(|hour| (hour, 1)).collect::<Vec<_>>();
+    let req = commit_request(&token, &counts);
+
+    let resp = test::call_service(&mut app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[actix_rt::test]
+async fn documented_routes_are_served() {
+    let store = MemoryStore::new();
+    register(&store, "alice", true);
+    let mut app = test::init_service(app(hub(store))).await;
+
+    let req = TestRequest::get().uri("/v1/openapi.json").to_request();
+    let document : serde_json::Value =
+        test::read_body_json(test::call_service(&mut app, req).await).await;
+    let paths = document["paths"].as_object().unwrap();
+    assert!(paths.contains_key("/v1/commit"));
+
+    for (path, operations) in paths {
+        let uri = path.replace("{name}", "alice");
+
+        for method in operations.as_object().unwrap().keys() {
+            let method =
+                Method::from_bytes(method.to_uppercase().as_bytes()).unwrap();
+            let req = TestRequest::default()
+                .method(method.clone())
+                .uri(&uri)
+                .to_request();
+
+            let status = test::call_service(&mut app, req).await.status();
+            assert_ne!(status, StatusCode::NOT_FOUND, "{} {}", method, path);
+            assert_ne!(
+                status,
+                StatusCode::METHOD_NOT_ALLOWED,
+                "{} {}",
+                method,
+                path
+            );
+        }
+    }
+}
+
+#[actix_rt::test]
+async fn documented_errors_use_the_hub_statuses() {
+    let mut app = test::init_service(app(hub(MemoryStore::new()))).await;
+
+    let req = TestRequest::get().uri("/v1/openapi.json").to_request();
+    let document : serde_json::Value =
+        test::read_body_json(test::call_service(&mut app, req).await).await;
+    assert_eq!(document["openapi"], "3.0.3");
+
+    let responses = |path : &str, method : &str| {
+        document["paths"][path][method]["responses"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let status = |err : KeyrHubError| err.status_code().as_u16().to_string();
+
+    let commit = responses("/v1/commit", "post");
+    assert!(commit.contains(&status(KeyrHubError::MissingTokenHeader)));
+    assert!(commit.contains(&status(KeyrHubError::ConflictingTokenHeaders)));
+    assert!(commit.contains(&status(KeyrHubError::Storage(
+        KeyrHubstorageError::FrozenUser
+    ))));
+    assert!(commit.contains(&status(KeyrHubError::RateLimited(1))));
+
+    let view = responses("/v1/view/{name}", "get");
+    assert!(view.contains(&status(KeyrHubError::PrivateData)));
+    assert!(view.contains(&status(KeyrHubError::InvalidRange)));
+    assert!(!view.contains(&status(KeyrHubError::MissingTokenHeader)));
+}
+
+fn limited_hub(store : MemoryStore, trusted_proxies : u32) -> Hub<MemoryStore> {
+    let conf = RateLimitConfig {
+        enabled : true,
+        trusted_proxies,
+        requests_per_ip : 3,
+        requests_per_token : 2,
+        failed_lookups : 2,
+        ..RateLimitConfig::default()
+    };
+
+    Hub {
+        limiter : Arc::new(RateLimiter::new(conf)),
+        ..hub(store)
+    }
+}
+
+fn from_ip(req : TestRequest, ip : &str) -> TestRequest {
+    req.peer_addr(format!("{}:4242", ip).parse().unwrap())
+}
+
+fn retry_after(resp : &actix_web::dev::ServiceResponse) -> u64 {
+    resp.headers()
+        .get(header::RETRY_AFTER)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[actix_rt::test]
+async fn clients_are_rate_limited() {
+    let mut app =
+        test::init_service(app(limited_hub(MemoryStore::new(), 0))).await;
+
+    for _ in 0..3 {
+        let req = from_ip(TestRequest::get().uri("/healthz"), "10.0.0.1");
+        let resp = test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    let req = from_ip(TestRequest::get().uri("/healthz"), "10.0.0.1");
+    let resp = test::call_service(&mut app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!((1..=60).contains(&retry_after(&resp)));
+
+    // Other clients have their own quota
+    let req = from_ip(TestRequest::get().uri("/healthz"), "10.0.0.2");
+    let resp = test::call_service(&mut app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn tokens_are_rate_limited() {
+    let store = MemoryStore::new();
+    let token = register(&store, "alice", false);
+    let mut app = test::init_service(app(limited_hub(store, 0))).await;
+
+    let summary = |ip : &str| {
+        from_ip(
+            TestRequest::get()
+                .uri("/v1/summary")
+                .header("Keyr-Token", token.as_str()),
+            ip,
+        )
+        .to_request()
+    };
+
+    for ip in &["10.0.0.1", "10.0.0.2"] {
+        let resp = test::call_service(&mut app, summary(ip)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    // Changing of address does not reset the quota of a token
+    let resp = test::call_service(&mut app, summary("10.0.0.3")).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(resp.headers().contains_key(header::RETRY_AFTER));
+}
+
+#[actix_rt::test]
+async fn invalid_tokens_lock_clients_out() {
+    let store = MemoryStore::new();
+    let token = register(&store, "alice", false);
+    let mut app = test::init_service(app(limited_hub(store, 0))).await;
+
+    let summary = |token : &str| {
+        from_ip(
+            TestRequest::get()
+                .uri("/v1/summary")
+                .header("Keyr-Token", token),
+            "10.0.0.1",
+        )
+        .to_request()
+    };
+
+    for guess in &["guess-1", "guess-2"] {
+        let resp = test::call_service(&mut app, summary(guess)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // Even a valid token is refused during the lockout
+    let resp = test::call_service(&mut app, summary(&token)).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(retry_after(&resp) > 60);
+    assert!(retry_after(&resp) <= 900);
+}
+
+#[actix_rt::test]
+async fn forwarded_addresses_cannot_be_spoofed() {
+    let store = MemoryStore::new();
+    let token = register(&store, "alice", false);
+    let mut app = test::init_service(app(limited_hub(store, 1))).await;
+
+    // Every request goes through the same proxy, which appends the address
+    // of the client to the header
+    let summary = |token : &str, forwarded : &str| {
+        from_ip(
+            TestRequest::get()
+                .uri("/v1/summary")
+                .header("Keyr-Token", token)
+                .header("X-Forwarded-For", forwarded),
+            "10.0.0.1",
+        )
+        .to_request()
+    };
+
+    for (i, guess) in ["guess-1", "guess-2"].iter().enumerate() {
+        let forwarded = format!("198.51.100.{}, 192.0.2.1", i);
+        let resp =
+            test::call_service(&mut app, summary(guess, &forwarded)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // A new spoofed address does not reset the lockout
+    let resp = test::call_service(
+        &mut app,
+        summary(&token, "198.51.100.42, 192.0.2.1"),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // The other clients of the proxy are not locked out
+    let resp = test::call_service(&mut app, summary(&token, "192.0.2.2")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn ipv6_clients_are_locked_out_by_prefix() {
+    let store = MemoryStore::new();
+    let token = register(&store, "alice", false);
+    let mut app = test::init_service(app(limited_hub(store, 0))).await;
+
+    let summary = |token : &str, ip : &str| {
+        TestRequest::get()
+            .uri("/v1/summary")
+            .header("Keyr-Token", token)
+            .peer_addr(format!("[{}]:4242", ip).parse().unwrap())
+            .to_request()
+    };
+
+    for (i, guess) in ["guess-1", "guess-2"].iter().enumerate() {
+        let ip = format!("2001:db8:0:1::{}", i + 1);
+        let resp = test::call_service(&mut app, summary(guess, &ip)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let resp =
+        test::call_service(&mut app, summary(&token, "2001:db8:0:1::42")).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    let resp =
+        test::call_service(&mut app, summary(&token, "2001:db8:0:2::1")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+const ORIGIN : &str = "https://example.com";
+
+fn cors_hub(store : MemoryStore) -> Hub<MemoryStore> {
+    let conf = CorsConfig {
+        allowed_origins : vec![ORIGIN.to_owned()],
+        allowed_methods : vec!["get".to_owned()],
+        allowed_headers : vec!["Content-Type".to_owned()],
+        max_age : Some(600),
+    };
+
+    Hub {
+        cors : Some(Arc::new(Cors::new(&conf).unwrap())),
+        ..hub(store)
+    }
+}
+
+fn preflight(uri : &str, origin : &str, method : &str) -> TestRequest {
+    TestRequest::default()
+        .method(Method::OPTIONS)
+        .uri(uri)
+        .header(header::ORIGIN, origin)
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, method)
+}
+
+#[actix_rt::test]
+async fn public_routes_answer_preflight_requests() {
+    let mut app = test::init_service(app(cors_hub(MemoryStore::new()))).await;
+
+    for uri in &["/v1/leaderboard", "/leaderboard", "/v1/view/alice"] {
+        let req = preflight(uri, ORIGIN, "GET")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type");
+        let resp = test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let headers = resp.headers();
+        assert_eq!(
+            headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            ORIGIN
+        );
+        assert_eq!(
+            headers.get(header::ACCESS_CONTROL_ALLOW_METHODS).unwrap(),
+            "GET"
+        );
+        assert_eq!(headers.get(header::ACCESS_CONTROL_MAX_AGE).unwrap(), "600");
+    }
+
+    let rejected = vec![
+        preflight("/v1/leaderboard", "https://evil.example", "GET"),
+        preflight("/v1/leaderboard", ORIGIN, "POST"),
+        preflight("/v1/leaderboard", ORIGIN, "GET")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "Keyr-Token"),
+    ];
+
+    for req in rejected {
+        let resp = test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(!resp
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    let req = TestRequest::get()
+        .uri("/v1/leaderboard")
+        .header(header::ORIGIN, ORIGIN);
+    let resp = test::call_service(&mut app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .unwrap(),
+        ORIGIN
+    );
+}
+
+#[actix_rt::test]
+async fn write_routes_are_not_shared_with_other_origins() {
+    let store = MemoryStore::new();
+    let token = register(&store, "alice", false);
+    let mut app = test::init_service(app(cors_hub(store))).await;
+
+    let writes = vec![
+        (Method::POST, "/v1/commit"),
+        (Method::POST, "/v1/revert/initiate"),
+        (Method::POST, "/v1/revert/cancel"),
+        (Method::POST, "/v1/revert/terminate"),
+        (Method::POST, "/commit"),
+        // Last, since it erases the user
+        (Method::DELETE, "/v1/me"),
+    ];
+
+    for (method, uri) in writes {
+        let req = preflight(uri, ORIGIN, method.as_str());
+        let resp = test::call_service(&mut app, req.to_request()).await;
+        assert!(
+            !resp
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            "OPTIONS {}",
+            uri
+        );
+
+        let req = TestRequest::default()
+            .method(method.clone())
+            .uri(uri)
+            .header(header::ORIGIN, ORIGIN)
+            .header("Keyr-Token", token.as_str());
+        let resp = test::call_service(&mut app, req.to_request()).await;
+        assert!(
+            !resp
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            "{} {}",
+            method,
+            uri
+        );
+    }
+}
@@ -0,0 +1,214 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use keyr_hub::cli;
+use keyr_hub::config::{
+    DatabaseConfig, HubConfig, MetricsConfig, RetentionConfig,
+};
+
+fn metrics(conf : &str) -> Result<MetricsConfig, toml::de::Error> {
+    toml::from_str(conf)
+}
+
+#[test]
+fn metrics_listen_to_a_valid_port() {
+    let conf = metrics("url = \"127.0.0.1\"\nport = 9100").unwrap();
+    assert_eq!(conf.address(), "127.0.0.1:9100");
+
+    let conf = metrics("url = \"::1\"\nport = 9100").unwrap();
+    assert_eq!(conf.address(), "[::1]:9100");
+
+    assert!(metrics("url = \"127.0.0.1\"\nport = -1").is_err());
+    assert!(metrics("url = \"127.0.0.1\"\nport = 65536").is_err());
+}
+
+fn override_metrics(
+    conf : Option<MetricsConfig>,
+    args : &[&str],
+) -> anyhow::Result<Option<MetricsConfig>> {
+    let mut conf = conf;
+    let matches = cli::get_app().get_matches_from(
+        ["keyr-hub", "--config-file", "hub.toml"]
+            .iter()
+            .chain(args.iter()),
+    );
+
+    cli::override_metrics(&matches, &mut conf)?;
+
+    Ok(conf)
+}
+
+#[test]
+fn metrics_listener_can_be_overridden() {
+    let conf = override_metrics(None, &[]).unwrap();
+    assert!(conf.is_none());
+
+    let conf = override_metrics(
+        None,
+        &["--metrics-url", "0.0.0.0", "--metrics-port", "9100"],
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(conf.address(), "0.0.0.0:9100");
+    assert!(!conf.user_gauges);
+
+    let file = metrics("url = \"127.0.0.1\"\nport = 9100\nuser_gauges = true")
+        .unwrap();
+    let conf = override_metrics(Some(file), &["--metrics-port", "9200"])
+        .unwrap()
+        .unwrap();
+    assert_eq!(conf.address(), "127.0.0.1:9200");
+    assert!(conf.user_gauges);
+
+    assert!(override_metrics(None, &["--metrics-port", "9100"]).is_err());
+    assert!(override_metrics(
+        None,
+        &["--metrics-url", "0.0.0.0", "--metrics-port", "65536"],
+    )
+    .is_err());
+}
+
+fn database(conf : &str) -> DatabaseConfig {
+    toml::from_str(conf).unwrap()
+}
+
+#[test]
+fn database_credentials_are_escaped() {
+    let conf = database(
+        r#"
+        url = "localhost/keyr"
+        user = "keyr"
+        password = "p@ss:w/rd%"
+        "#,
+    );
+
+    assert_eq!(
+        conf.database_url().unwrap(),
+        "postgres://localhost/keyr?user=keyr&password=p%40ss%3Aw%2Frd%25"
+    );
+
+    // Only the unreserved characters of RFC 3986 are left as they are
+    let conf = database("password = \"aZ09-._~ é\"");
+    assert_eq!(
+        conf.database_url().unwrap(),
+        "postgres://?password=aZ09-._~%20%C3%A9"
+    );
+}
+
+#[test]
+fn database_password_is_read_from_a_file() {
+    let path = std::env::temp_dir()
+        .join(format!("keyr-hub-password-{}", uuid::Uuid::new_v4()));
+    std::fs::write(&path, "s3cr&t\n").unwrap();
+
+    let conf = database(&format!(
+        "host = \"/run/postgresql\"\npassword_file = {:?}",
+        path
+    ));
+    let url = conf.database_url();
+
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        url.unwrap(),
+        "postgres://?host=%2Frun%2Fpostgresql&password=s3cr%26t"
+    );
+
+    let conf =
+        database(&format!("password = \"a\"\npassword_file = {:?}", path));
+    assert!(conf.database_url().is_err());
+}
+
+#[test]
+fn database_keys_supersede_the_url() {
+    let conf = database(
+        r#"
+        url = "postgresql://keyr@db.example.com/keyr"
+        port = 5433
+        dbname = "stats"
+        "#,
+    );
+
+    assert_eq!(
+        conf.database_url().unwrap(),
+        "postgresql://keyr@db.example.com/keyr?port=5433&dbname=stats"
+    );
+}
+
+#[test]
+fn database_sslmode_extends_the_query_of_the_url() {
+    let conf = database(
+        r#"
+        url = "postgres://keyr@localhost/keyr"
+        sslmode = "verify-full"
+        "#,
+    );
+    assert_eq!(
+        conf.database_url().unwrap(),
+        "postgres://keyr@localhost/keyr?sslmode=verify-full"
+    );
+
+    let conf = database(
+        r#"
+        url = "postgres://keyr@localhost/keyr?connect_timeout=10"
+        sslmode = "require"
+        "#,
+    );
+    assert_eq!(
+        conf.database_url().unwrap(),
+        "postgres://keyr@localhost/keyr?connect_timeout=10&sslmode=require"
+    );
+}
+
+fn retention(conf : &str) -> Result<RetentionConfig, toml::de::Error> {
+    toml::from_str(conf)
+}
+
+#[test]
+fn retention_overrides_can_lift_a_limit() {
+    let policies = retention(
+        "hourly_days = 90\n\
+         max_age_days = 3650\n\
+         [users.alice]\n\
+         max_age_days = \"never\"\n\
+         [users.bob]\n\
+         hourly_days = 365\n",
+    )
+    .unwrap()
+    .retention();
+
+    let alice = policies.policy("alice");
+    assert_eq!(alice.hourly_days, Some(90));
+    assert_eq!(alice.max_age_days, None);
+
+    let bob = policies.policy("bob");
+    assert_eq!(bob.hourly_days, Some(365));
+    assert_eq!(bob.max_age_days, Some(3650));
+
+    assert!(retention("[users.alice]\nhourly_days = \"forever\"").is_err());
+}
+
+#[test]
+fn sample_configuration_is_valid() {
+    let conf : HubConfig =
+        toml::from_str(include_str!("../hub.sample.toml")).unwrap();
+
+    assert_eq!(conf.http.addresses(), vec!["127.0.0.1:8080"]);
+    assert!(!conf.rate_limit.enabled);
+    assert_eq!(conf.rate_limit.trusted_proxies, 0);
+}
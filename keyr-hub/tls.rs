@@ -0,0 +1,70 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use anyhow::{anyhow, Context, Result};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{NoClientAuth, PrivateKey, ServerConfig};
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::config::TlsConfig;
+
+fn open(path : &Path) -> Result<BufReader<File>> {
+    let file = File::open(path)
+        .with_context(|| format!("Cannot open {}", path.display()))?;
+
+    Ok(BufReader::new(file))
+}
+
+// Both PKCS #8 and RSA private keys are accepted
+fn private_key(path : &Path) -> Result<PrivateKey> {
+    let invalid = || anyhow!("{} is not a valid PEM file", path.display());
+
+    let mut keys =
+        pkcs8_private_keys(&mut open(path)?).map_err(|_| invalid())?;
+
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(path)?).map_err(|_| invalid())?;
+    }
+
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("{} contains no private key", path.display()))
+}
+
+pub fn server_config(conf : &TlsConfig) -> Result<ServerConfig> {
+    let chain = certs(&mut open(&conf.certificate)?).map_err(|_| {
+        anyhow!("{} is not a valid PEM file", conf.certificate.display())
+    })?;
+
+    if chain.is_empty() {
+        return Err(anyhow!(
+            "{} contains no certificate",
+            conf.certificate.display()
+        ));
+    }
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+
+    config.set_single_cert(chain, private_key(&conf.key)?)?;
+
+    Ok(config)
+}
@@ -0,0 +1,122 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::r2d2::{Error, ManageConnection, Pool};
+use diesel::result::{ConnectionError, ConnectionResult};
+use diesel::{PgConnection, SqliteConnection};
+
+const SQLITE_PREFIX : &str = "sqlite://";
+
+// A connection to the database of the hub, which is either a PostgreSQL
+// server or an embedded SQLite file.
+pub enum HubConnection {
+    Postgres(PgConnection),
+    Sqlite(SqliteConnection),
+}
+
+// Run `$body' with `$conn' bound to the connection wrapped by a
+// `HubConnection'. `$body' is compiled once per backend, so that the queries
+// which are supported by both can be written only once.
+#[macro_export]
+macro_rules! run {
+    ($hub:expr, $conn:ident => $body:expr) => {
+        match $hub {
+            $crate::connection::HubConnection::Postgres($conn) => $body,
+            $crate::connection::HubConnection::Sqlite($conn) => $body,
+        }
+    };
+}
+
+impl HubConnection {
+    // Connect to the database at `url', which is either a `postgres://' URL,
+    // or `sqlite://' followed by the path to the database file.
+    pub fn establish(url : &str) -> ConnectionResult<HubConnection> {
+        if let Some(path) = url.strip_prefix(SQLITE_PREFIX) {
+            let conn = SqliteConnection::establish(path)?;
+
+            // SQLite does not enforce foreign keys by default, and fails
+            // right away when another connection holds the lock
+            conn.batch_execute(
+                "PRAGMA foreign_keys = ON; PRAGMA busy_timeout = 5000;",
+            )
+            .map_err(ConnectionError::CouldntSetupConfiguration)?;
+
+            Ok(HubConnection::Sqlite(conn))
+        } else if url.starts_with("postgres://")
+            || url.starts_with("postgresql://")
+        {
+            Ok(HubConnection::Postgres(PgConnection::establish(url)?))
+        } else {
+            Err(ConnectionError::InvalidConnectionUrl(format!(
+                "{} is neither a `postgres://' nor a `{}' URL",
+                url, SQLITE_PREFIX
+            )))
+        }
+    }
+
+    pub fn transaction<T, E, F>(&self, f : F) -> Result<T, E>
+    where
+        F : FnOnce() -> Result<T, E>,
+        E : From<diesel::result::Error>,
+    {
+        run!(self, conn => conn.transaction(f))
+    }
+
+    pub fn begin_test_transaction(&self) -> QueryResult<()> {
+        run!(self, conn => conn.begin_test_transaction())
+    }
+
+    pub fn batch_execute(&self, query : &str) -> QueryResult<()> {
+        run!(self, conn => conn.batch_execute(query))
+    }
+}
+
+// The r2d2 manager of the connections to the database of the hub
+pub struct HubConnectionManager {
+    url : String,
+}
+
+impl HubConnectionManager {
+    pub fn new(url : &str) -> HubConnectionManager {
+        HubConnectionManager {
+            url : url.to_owned(),
+        }
+    }
+}
+
+impl ManageConnection for HubConnectionManager {
+    type Connection = HubConnection;
+    type Error = Error;
+
+    fn connect(&self) -> Result<HubConnection, Error> {
+        HubConnection::establish(&self.url).map_err(Error::ConnectionError)
+    }
+
+    fn is_valid(&self, conn : &mut HubConnection) -> Result<(), Error> {
+        conn.batch_execute("SELECT 1").map_err(Error::QueryError)
+    }
+
+    fn has_broken(&self, _conn : &mut HubConnection) -> bool {
+        false
+    }
+}
+
+pub type HubPool = Pool<HubConnectionManager>;
@@ -7,14 +7,24 @@ pub enum KeyrHubstorageError {
     RunMigrations(#[from] dm::RunMigrationsError),
     #[error(transparent)]
     DieselError(#[from] diesel::result::Error),
+    #[error(transparent)]
+    Pool(#[from] diesel::r2d2::PoolError),
     #[error("Not a valid token")]
     InvalidToken,
     #[error("Unknown user")]
     UnknownUser,
     #[error("Nickname {0} is already being used")]
     AlreadyUsedNickname(String),
-    #[error("User is frozen")]
+    #[error("Unknown machine")]
+    UnknownMachine,
+    #[error("Machine name {0} is already being used")]
+    AlreadyUsedMachineName(String),
+    #[error("User is frozen by an ongoing revert")]
     FrozenUser,
+    #[error("Unknown or expired revert session")]
+    UnknownRevertSession,
+    #[error("A series cannot have more than {0} points")]
+    TooLongSeries(i64),
 }
 
 pub type Result<R> = std::result::Result<R, KeyrHubstorageError>;
@@ -22,8 +22,16 @@ extern crate diesel_migrations;
 #[macro_use]
 extern crate diesel;
 
+#[macro_use]
+pub mod connection;
 pub mod error;
+pub mod machines;
+pub mod memory;
 pub mod migrations;
+pub mod retention;
+pub mod reverts;
 pub mod schema;
 pub mod stats;
+pub mod store;
+pub mod time;
 pub mod users;
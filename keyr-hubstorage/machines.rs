@@ -0,0 +1,151 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use diesel::prelude::*;
+use tracing::instrument;
+
+use crate::connection::HubConnection;
+use crate::error::{KeyrHubstorageError, Result};
+use crate::schema::{machines, tokens};
+use crate::users::{MaybeUserId, Token, UserId};
+
+#[derive(Copy, Clone, Debug)]
+pub struct MachineId(pub i32);
+
+// Create a new machine with a given name for a user. Check whether or not the
+// user already has a machine with this name before.
+#[instrument(level = "debug", skip(conn))]
+pub fn create_machine(
+    conn : &HubConnection,
+    user : MaybeUserId,
+    name : String,
+) -> Result<MachineId> {
+    conn.transaction(|| {
+        let id = user.validate(conn)?;
+
+        create_machine_in_transaction(conn, id, name)
+    })
+}
+
+// Create a new machine with a given name for a user. Check whether or not the
+// user already has a machine with this name before. This needs to be called
+// from within a transaction.
+#[instrument(level = "debug", skip(conn))]
+pub fn create_machine_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+    name : String,
+) -> Result<MachineId> {
+    let prev = run!(conn, conn => machines::table
+        .select(machines::id)
+        .filter(machines::user_id.eq(id.0))
+        .filter(machines::name.eq(&name))
+        .get_result::<i32>(conn)
+        .optional())?;
+
+    match prev {
+        None => {
+            // SQLite does not support `RETURNING', so the id of the new
+            // machine is fetched afterwards
+            let id = run!(conn, conn => {
+                diesel::insert_into(machines::table)
+                    .values(vec![(
+                        machines::name.eq(&name),
+                        machines::user_id.eq(id.0),
+                    )])
+                    .execute(conn)?;
+
+                machines::table
+                    .select(machines::id)
+                    .filter(machines::user_id.eq(id.0))
+                    .filter(machines::name.eq(&name))
+                    .get_result::<i32>(conn)
+            })?;
+
+            Ok(MachineId(id))
+        }
+        Some(_) => Err(KeyrHubstorageError::AlreadyUsedMachineName(name)),
+    }
+}
+
+#[instrument(level = "debug", skip(conn))]
+pub fn find_by_name_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+    name : String,
+) -> Result<MachineId> {
+    let id = run!(conn, conn => machines::table
+        .select(machines::id)
+        .filter(machines::user_id.eq(id.0))
+        .filter(machines::name.eq(&name))
+        .get_result::<i32>(conn)
+        .optional())?
+    .ok_or(KeyrHubstorageError::UnknownMachine)?;
+
+    Ok(MachineId(id))
+}
+
+// Check whether or not a machine belongs to a given user. Needs to be called
+// from within a transaction.
+#[instrument(level = "debug", skip(conn))]
+pub fn validate_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+    machine : MachineId,
+) -> Result<MachineId> {
+    let id = run!(conn, conn => machines::table
+        .select(machines::id)
+        .filter(machines::id.eq(machine.0))
+        .filter(machines::user_id.eq(id.0))
+        .get_result::<i32>(conn)
+        .optional())?
+    .ok_or(KeyrHubstorageError::UnknownMachine)?;
+
+    Ok(MachineId(id))
+}
+
+// Find the user and the machine a token is bound to. Needs to be called from
+// within a transaction.
+#[instrument(level = "debug", skip(conn, token))]
+pub fn identify_machine_by_token_in_transaction(
+    conn : &HubConnection,
+    token : &Token,
+) -> Result<(UserId, MachineId)> {
+    let res = run!(conn, conn => tokens::table
+        .select((tokens::user_id, tokens::machine_id))
+        .filter(tokens::token.eq(&token.0))
+        .get_result::<(i32, i32)>(conn)
+        .optional())?;
+
+    res.map(|(user, machine)| (UserId(user), MachineId(machine)))
+        .ok_or(KeyrHubstorageError::InvalidToken)
+}
+
+// Find the user and the machine a token is bound to. User existence needs to
+// be asserted again prior to actually using it.
+#[instrument(level = "debug", skip(conn, token))]
+pub fn identify_machine_by_token(
+    conn : &HubConnection,
+    token : &Token,
+) -> Result<(MaybeUserId, MachineId)> {
+    conn.transaction(|| {
+        identify_machine_by_token_in_transaction(conn, token)
+            .map(|(user, machine)| (MaybeUserId(user.0), machine))
+    })
+}
@@ -0,0 +1,802 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use uuid::Uuid;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use keyr_types::{
+    Granularity, KeystrokesSeries, KeystrokesStats, Leaderboard,
+    MachineKeystrokes, MachineSummary, Period, Summary, TokenMetadata,
+    UserCalendar, UserExport,
+};
+
+use crate::error::{KeyrHubstorageError, Result};
+use crate::machines::MachineId;
+use crate::retention::{self, CompactionReport, Retention};
+use crate::reverts::{RevertSession, RevertSessionId};
+use crate::stats::{self, RevertPage};
+use crate::store::{HubStore, PoolState};
+use crate::time::{self, Calendar};
+use crate::users::{self, MaybeUserId, Token};
+
+struct User {
+    name : String,
+    visible : bool,
+    calendar : Calendar,
+}
+
+struct Machine {
+    user : i32,
+    name : String,
+}
+
+struct TokenOwner {
+    user : i32,
+    machine : i32,
+}
+
+struct Session {
+    id : RevertSessionId,
+    token : String,
+    expires_at : DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct State {
+    last_user : i32,
+    last_machine : i32,
+    users : BTreeMap<i32, User>,
+    machines : BTreeMap<i32, Machine>,
+    tokens : HashMap<String, TokenOwner>,
+    // The keystrokes count of a user, hour by hour, machine by machine
+    statistics : BTreeMap<(i32, NaiveDateTime, i32), u64>,
+    // A user has at most one revert session
+    sessions : HashMap<i32, Session>,
+}
+
+// A store keeping everything in memory, which is lost when it is dropped. It
+// behaves like `DieselStore', so that the hub can be run without any database,
+// e.g., in tests and demos.
+#[derive(Default)]
+pub struct MemoryStore {
+    state : Mutex<State>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    // The state is only modified once every check of an operation has
+    // passed, so it is consistent even if a thread panicked while holding
+    // the lock.
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl State {
+    fn validate(&self, user : MaybeUserId) -> Result<i32> {
+        if self.users.contains_key(&user.0) {
+            Ok(user.0)
+        } else {
+            Err(KeyrHubstorageError::UnknownUser)
+        }
+    }
+
+    fn validate_machine(&self, id : i32, machine : MachineId) -> Result<i32> {
+        match self.machines.get(&machine.0) {
+            Some(m) if m.user == id => Ok(machine.0),
+            _ => Err(KeyrHubstorageError::UnknownMachine),
+        }
+    }
+
+    fn find_machine_by_name(&self, id : i32, name : &str) -> Option<i32> {
+        self.machines
+            .iter()
+            .find(|(_, m)| m.user == id && m.name == name)
+            .map(|(mid, _)| *mid)
+    }
+
+    // The keystrokes of a user, hour by hour, regardless of their machine
+    fn hours(&self, id : i32) -> BTreeMap<NaiveDateTime, u64> {
+        let mut res = BTreeMap::new();
+
+        for ((_, hour, _), count) in self
+            .statistics
+            .iter()
+            .filter(|((user, _, _), _)| *user == id)
+        {
+            *res.entry(*hour).or_insert(0) += count;
+        }
+
+        res
+    }
+
+    fn count_since(&self, id : i32, since : Option<DateTime<Utc>>) -> u64 {
+        let since = since.map(|t| t.naive_utc());
+
+        self.statistics
+            .iter()
+            .filter(|((user, hour, _), _)| {
+                *user == id && since.map(|s| *hour >= s).unwrap_or(true)
+            })
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    fn oldest_entry(&self, id : i32) -> Option<NaiveDateTime> {
+        self.hours(id).keys().next().copied()
+    }
+
+    fn summary(&self, id : i32, now : DateTime<Utc>) -> Summary {
+        let today = self.users[&id].calendar.today(now);
+        let oldest_entry =
+            self.oldest_entry(id).unwrap_or_else(|| today.naive_utc());
+        let today_start = today.naive_utc();
+
+        let mut machines = self
+            .machines
+            .iter()
+            .filter(|(_, m)| m.user == id)
+            .map(|(mid, m)| {
+                let counts = self
+                    .statistics
+                    .iter()
+                    .filter(|((_, _, machine), _)| machine == mid);
+
+                MachineSummary {
+                    name : m.name.clone(),
+                    global_count : counts.clone().map(|(_, c)| c).sum(),
+                    today_count : counts
+                        .filter(|((_, hour, _), _)| *hour >= today_start)
+                        .map(|(_, c)| c)
+                        .sum(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        machines.sort_by(|m1, m2| m1.name.cmp(&m2.name));
+
+        Summary {
+            oldest_timestamp : oldest_entry.timestamp(),
+            global_count : self.count_since(id, None),
+            today_count : self.count_since(id, Some(today)),
+            today_timestamp : today_start.timestamp(),
+            machines,
+        }
+    }
+
+    // Forget about the session of a user if it has expired
+    fn release_expired(&mut self, id : i32) {
+        let now = Utc::now();
+
+        if self
+            .sessions
+            .get(&id)
+            .map(|s| s.expires_at <= now)
+            .unwrap_or(false)
+        {
+            self.sessions.remove(&id);
+        }
+    }
+
+    fn validate_token(&self, id : i32, token : &Token) -> Result<()> {
+        match self.tokens.get(&token.0) {
+            Some(owner) if owner.user == id => Ok(()),
+            _ => Err(KeyrHubstorageError::InvalidToken),
+        }
+    }
+
+    fn validate_session(
+        &mut self,
+        id : i32,
+        token : &Token,
+        session : &RevertSessionId,
+    ) -> Result<()> {
+        self.release_expired(id);
+        self.validate_token(id, token)?;
+
+        match self.sessions.get(&id) {
+            Some(s) if &s.id == session && s.token == token.0 => Ok(()),
+            _ => Err(KeyrHubstorageError::UnknownRevertSession),
+        }
+    }
+}
+
+impl HubStore for MemoryStore {
+    fn create_user(&self, name : String) -> Result<MaybeUserId> {
+        let mut state = self.state();
+
+        if state.users.values().any(|u| u.name == name) {
+            return Err(KeyrHubstorageError::AlreadyUsedNickname(name));
+        }
+
+        state.last_user += 1;
+        let id = state.last_user;
+        state.users.insert(
+            id,
+            User {
+                name,
+                visible : false,
+                calendar : Calendar::default(),
+            },
+        );
+
+        Ok(MaybeUserId(id))
+    }
+
+    fn create_machine(
+        &self,
+        user : MaybeUserId,
+        name : String,
+    ) -> Result<MachineId> {
+        let mut state = self.state();
+        let id = state.validate(user)?;
+
+        if state.find_machine_by_name(id, &name).is_some() {
+            return Err(KeyrHubstorageError::AlreadyUsedMachineName(name));
+        }
+
+        state.last_machine += 1;
+        let machine = state.last_machine;
+        state.machines.insert(machine, Machine { user : id, name });
+
+        Ok(MachineId(machine))
+    }
+
+    fn generate_token(
+        &self,
+        user : MaybeUserId,
+        machine : MachineId,
+    ) -> Result<Token> {
+        let mut state = self.state();
+        let id = state.validate(user)?;
+        let machine = state.validate_machine(id, machine)?;
+        let token = Uuid::new_v4().to_simple().to_string();
+
+        state
+            .tokens
+            .insert(token.clone(), TokenOwner { user : id, machine });
+
+        Ok(Token(token))
+    }
+
+    fn set_visible(&self, user : MaybeUserId, visible : bool) -> Result<()> {
+        let mut state = self.state();
+        let id = state.validate(user)?;
+
+        // unwrap is valid since `id' has just been validated
+        state.users.get_mut(&id).unwrap().visible = visible;
+
+        Ok(())
+    }
+
+    fn get_calendar(&self, user : MaybeUserId) -> Result<Calendar> {
+        let state = self.state();
+        let id = state.validate(user)?;
+
+        Ok(state.users[&id].calendar)
+    }
+
+    fn set_calendar(
+        &self,
+        user : MaybeUserId,
+        calendar : Calendar,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let id = state.validate(user)?;
+
+        // unwrap is valid since `id' has just been validated
+        state.users.get_mut(&id).unwrap().calendar = calendar;
+
+        Ok(())
+    }
+
+    fn identify_user_by_token(&self, token : &Token) -> Result<MaybeUserId> {
+        self.state()
+            .tokens
+            .get(&token.0)
+            .map(|owner| MaybeUserId(owner.user))
+            .ok_or(KeyrHubstorageError::InvalidToken)
+    }
+
+    fn identify_machine_by_token(
+        &self,
+        token : &Token,
+    ) -> Result<(MaybeUserId, MachineId)> {
+        self.state()
+            .tokens
+            .get(&token.0)
+            .map(|owner| (MaybeUserId(owner.user), MachineId(owner.machine)))
+            .ok_or(KeyrHubstorageError::InvalidToken)
+    }
+
+    fn find_user_by_name(&self, name : &str) -> Result<MaybeUserId> {
+        self.state()
+            .users
+            .iter()
+            .find(|(_, u)| u.name == name)
+            .map(|(id, _)| MaybeUserId(*id))
+            .ok_or(KeyrHubstorageError::UnknownUser)
+    }
+
+    fn find_machine_by_name(
+        &self,
+        user : MaybeUserId,
+        name : &str,
+    ) -> Result<MachineId> {
+        let state = self.state();
+        let id = state.validate(user)?;
+
+        state
+            .find_machine_by_name(id, name)
+            .map(MachineId)
+            .ok_or(KeyrHubstorageError::UnknownMachine)
+    }
+
+    fn is_visible(&self, user : MaybeUserId) -> Result<bool> {
+        let state = self.state();
+        let id = state.validate(user)?;
+
+        Ok(state.users[&id].visible)
+    }
+
+    fn commit(
+        &self,
+        user : MaybeUserId,
+        machine : MachineId,
+        now : DateTime<Utc>,
+        sa : &KeystrokesStats,
+    ) -> Result<Summary> {
+        let mut state = self.state();
+        let id = state.validate(user)?;
+        let machine = state.validate_machine(id, machine)?;
+
+        state.release_expired(id);
+
+        if state.sessions.contains_key(&id) {
+            return Err(KeyrHubstorageError::FrozenUser);
+        }
+
+        for (t, v) in sa.iter() {
+            let hour = time::truncate(
+                Utc.timestamp(*t, 0).naive_utc(),
+                Granularity::Hour,
+            );
+
+            *state.statistics.entry((id, hour, machine)).or_insert(0) +=
+                *v as u64;
+        }
+
+        Ok(state.summary(id, now))
+    }
+
+    fn get_summary(
+        &self,
+        user : MaybeUserId,
+        now : DateTime<Utc>,
+    ) -> Result<Summary> {
+        let state = self.state();
+        let id = state.validate(user)?;
+
+        Ok(state.summary(id, now))
+    }
+
+    fn get_count_since(
+        &self,
+        user : MaybeUserId,
+        since : Option<DateTime<Utc>>,
+    ) -> Result<u64> {
+        let state = self.state();
+        let id = state.validate(user)?;
+
+        Ok(state.count_since(id, since))
+    }
+
+    fn get_oldest_entry(
+        &self,
+        user : MaybeUserId,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let state = self.state();
+        let id = state.validate(user)?;
+
+        Ok(state.oldest_entry(id).map(|t| DateTime::from_utc(t, Utc)))
+    }
+
+    fn get_keystrokes_series(
+        &self,
+        user : MaybeUserId,
+        machine : Option<MachineId>,
+        from : DateTime<Utc>,
+        to : DateTime<Utc>,
+        granularity : Granularity,
+        calendar : Calendar,
+    ) -> Result<KeystrokesSeries> {
+        let state = self.state();
+        let id = state.validate(user)?;
+        let (from_hour, to_hour) = (from.naive_utc(), to.naive_utc());
+
+        stats::check_series_length(from, to, granularity)?;
+
+        let hours = state
+            .statistics
+            .iter()
+            .filter(|((u, hour, m), _)| {
+                *u == id
+                    && from_hour <= *hour
+                    && *hour < to_hour
+                    && machine.map(|machine| machine.0 == *m).unwrap_or(true)
+            })
+            .map(|((_, hour, _), count)| (*hour, *count));
+
+        let buckets = stats::bucket_hours(hours, granularity, calendar);
+
+        Ok(stats::fill_series(
+            &buckets,
+            from,
+            to,
+            granularity,
+            calendar,
+        ))
+    }
+
+    fn get_hourly_distribution(
+        &self,
+        user : MaybeUserId,
+        tz : Tz,
+    ) -> Result<[u64; 24]> {
+        let state = self.state();
+        let id = state.validate(user)?;
+        let mut res = [0; 24];
+
+        for (hour, count) in state.hours(id) {
+            let local = time::to_local(tz, DateTime::from_utc(hour, Utc));
+
+            res[local.hour() as usize] += count;
+        }
+
+        Ok(res)
+    }
+
+    fn get_leaderboard(
+        &self,
+        period : Period,
+        now : DateTime<Utc>,
+        limit : u32,
+    ) -> Result<Leaderboard> {
+        let state = self.state();
+        let mut counts : BTreeMap<i32, u64> = BTreeMap::new();
+
+        // The beginning of the period for each visible user
+        let starts = state
+            .users
+            .iter()
+            .filter(|(_, u)| u.visible)
+            .map(|(id, u)| {
+                let since = u.calendar.period_start(period, now);
+
+                (*id, since.map(|t| t.naive_utc()))
+            })
+            .collect::<HashMap<_, _>>();
+
+        for ((user, hour, _), count) in state.statistics.iter() {
+            if let Some(since) = starts.get(user) {
+                if since.map(|s| *hour >= s).unwrap_or(true) {
+                    *counts.entry(*user).or_insert(0) += count;
+                }
+            }
+        }
+
+        Ok(stats::rank(
+            counts
+                .into_iter()
+                .map(|(user, count)| (state.users[&user].name.clone(), count)),
+            limit,
+        ))
+    }
+
+    fn initiate_revert(
+        &self,
+        user : MaybeUserId,
+        token : &Token,
+        lease : Duration,
+    ) -> Result<RevertSession> {
+        let mut state = self.state();
+        let id = state.validate(user)?;
+
+        state.release_expired(id);
+        state.validate_token(id, token)?;
+
+        if let Some(session) = state.sessions.get(&id) {
+            if session.token != token.0 {
+                return Err(KeyrHubstorageError::FrozenUser);
+            }
+        }
+
+        let session = RevertSession {
+            id : RevertSessionId(Uuid::new_v4().to_simple().to_string()),
+            expires_at : Utc::now() + lease,
+        };
+
+        state.sessions.insert(
+            id,
+            Session {
+                id : session.id.clone(),
+                token : token.0.clone(),
+                expires_at : session.expires_at,
+            },
+        );
+
+        Ok(session)
+    }
+
+    fn find_revert_session(
+        &self,
+        user : MaybeUserId,
+        token : &Token,
+    ) -> Result<RevertSessionId> {
+        let mut state = self.state();
+        let id = state.validate(user)?;
+
+        state.release_expired(id);
+        state.validate_token(id, token)?;
+
+        state
+            .sessions
+            .get(&id)
+            .filter(|session| session.token == token.0)
+            .map(|session| session.id.clone())
+            .ok_or(KeyrHubstorageError::UnknownRevertSession)
+    }
+
+    fn get_revert_page(
+        &self,
+        user : MaybeUserId,
+        token : &Token,
+        session : &RevertSessionId,
+        after : Option<DateTime<Utc>>,
+        limit : u32,
+        lease : Duration,
+    ) -> Result<RevertPage> {
+        let mut state = self.state();
+        let id = state.validate(user)?;
+
+        state.validate_session(id, token, session)?;
+
+        let expires_at = Utc::now() + lease;
+
+        // unwrap is valid since the session has just been validated
+        state.sessions.get_mut(&id).unwrap().expires_at = expires_at;
+
+        let after = after.map(|t| t.naive_utc());
+        let mut hours = state
+            .hours(id)
+            .into_iter()
+            .filter(|(hour, _)| after.map(|a| *hour > a).unwrap_or(true))
+            .take(limit as usize + 1)
+            .collect::<Vec<_>>();
+
+        let next = if hours.len() > limit as usize {
+            hours.truncate(limit as usize);
+            hours.last().map(|(hour, _)| DateTime::from_utc(*hour, Utc))
+        } else {
+            None
+        };
+
+        Ok(RevertPage {
+            session : RevertSession {
+                id : session.clone(),
+                expires_at,
+            },
+            statistics : hours
+                .into_iter()
+                .map(|(hour, count)| (hour.timestamp(), count as u32))
+                .collect(),
+            next,
+        })
+    }
+
+    fn terminate_revert(
+        &self,
+        user : MaybeUserId,
+        token : &Token,
+        session : &RevertSessionId,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let id = state.validate(user)?;
+
+        state.validate_session(id, token, session)?;
+        state.statistics.retain(|(user, _, _), _| *user != id);
+        state.sessions.remove(&id);
+
+        Ok(())
+    }
+
+    fn cancel_revert(
+        &self,
+        user : MaybeUserId,
+        token : &Token,
+        session : &RevertSessionId,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let id = state.validate(user)?;
+
+        state.validate_session(id, token, session)?;
+        state.sessions.remove(&id);
+
+        Ok(())
+    }
+
+    fn export_user(&self, user : MaybeUserId) -> Result<UserExport> {
+        let state = self.state();
+        let id = state.validate(user)?;
+        let user = &state.users[&id];
+        let machine_name =
+            |machine : i32| state.machines[&machine].name.clone();
+
+        let mut machines = state
+            .machines
+            .values()
+            .filter(|m| m.user == id)
+            .map(|m| m.name.clone())
+            .collect::<Vec<_>>();
+
+        machines.sort();
+
+        let mut tokens = state
+            .tokens
+            .iter()
+            .filter(|(_, owner)| owner.user == id)
+            .map(|(token, owner)| TokenMetadata {
+                machine : machine_name(owner.machine),
+                hint : users::token_hint(token),
+            })
+            .collect::<Vec<_>>();
+
+        tokens.sort_by(|t1, t2| {
+            t1.machine
+                .cmp(&t2.machine)
+                .then_with(|| t1.hint.cmp(&t2.hint))
+        });
+
+        Ok(UserExport {
+            name : user.name.clone(),
+            visible : user.visible,
+            calendar : UserCalendar {
+                timezone : user.calendar.tz.name().to_owned(),
+                day_start : user.calendar.day_start,
+            },
+            machines,
+            tokens,
+            // The statistics are sorted by user, then hour, then machine
+            statistics : state
+                .statistics
+                .iter()
+                .filter(|((user, _, _), _)| *user == id)
+                .map(|((_, hour, machine), count)| MachineKeystrokes {
+                    timestamp : hour.timestamp(),
+                    machine : machine_name(*machine),
+                    count : *count,
+                })
+                .collect(),
+        })
+    }
+
+    fn delete_user(&self, user : MaybeUserId) -> Result<()> {
+        let mut state = self.state();
+        let id = state.validate(user)?;
+
+        state.sessions.remove(&id);
+        state.statistics.retain(|(user, _, _), _| *user != id);
+        state.tokens.retain(|_, owner| owner.user != id);
+        state.machines.retain(|_, m| m.user != id);
+        state.users.remove(&id);
+
+        Ok(())
+    }
+
+    fn compact(
+        &self,
+        retention : &Retention,
+        now : DateTime<Utc>,
+    ) -> Result<CompactionReport> {
+        let mut state = self.state();
+        let mut report = CompactionReport::default();
+
+        let users = state
+            .users
+            .iter()
+            .map(|(id, u)| (*id, u.name.clone(), u.calendar))
+            .collect::<Vec<_>>();
+
+        for (id, name, calendar) in users {
+            state.release_expired(id);
+
+            if state.sessions.contains_key(&id) {
+                report.skipped += 1;
+                continue;
+            }
+
+            let policy = retention.policy(&name);
+
+            if let Some(days) = policy.max_age_days {
+                let cutoff = retention::cutoff(&calendar, now, days);
+                let before = state.statistics.len();
+
+                state.statistics.retain(|(user, hour, _), _| {
+                    *user != id || *hour >= cutoff
+                });
+
+                report.deleted += (before - state.statistics.len()) as u64;
+            }
+
+            if let Some(days) = policy.hourly_days {
+                let cutoff = retention::cutoff(&calendar, now, days);
+                let mut days : BTreeMap<(i32, NaiveDateTime), Vec<_>> =
+                    BTreeMap::new();
+
+                for ((_, hour, machine), count) in
+                    state.statistics.iter().filter(|((user, hour, _), _)| {
+                        *user == id && *hour < cutoff
+                    })
+                {
+                    days.entry((*machine, retention::day_of(&calendar, *hour)))
+                        .or_default()
+                        .push((*hour, *count));
+                }
+
+                for ((machine, day), hours) in days {
+                    // This day has already been compacted
+                    if hours.len() == 1 && hours[0].0 == day {
+                        continue;
+                    }
+
+                    for (hour, _) in &hours {
+                        state.statistics.remove(&(id, *hour, machine));
+                    }
+
+                    state.statistics.insert(
+                        (id, day, machine),
+                        hours.iter().map(|(_, count)| count).sum(),
+                    );
+
+                    report.rolled_up += hours.len() as u64;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn pending_migrations(
+        &self,
+        _timeout : std::time::Duration,
+    ) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    fn pool_state(&self) -> Option<PoolState> {
+        None
+    }
+}
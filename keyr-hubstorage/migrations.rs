@@ -1,13 +1,83 @@
-embed_migrations!();
+use diesel_migrations::MigrationConnection;
 
-use diesel::pg::Pg;
-use diesel::prelude::*;
+use crate::connection::HubConnection;
 
-pub fn run<Conn>(conn : &Conn) -> crate::error::Result<()>
-where
-    Conn : Connection<Backend = Pg>,
-{
-    embedded_migrations::run(conn)?;
+// Each backend has its own migrations, with the same versions. diesel does
+// not expose the list of the migrations it embeds, so it is written down
+// next to them (see `tests/migrations.rs', which checks it is complete).
+mod postgres {
+    embed_migrations!("migrations/postgres");
+
+    pub use embedded_migrations::*;
+
+    pub const MIGRATIONS : &[&str] = &[
+        "00000000000000_diesel_initial_setup",
+        "2020-08-14-195324_users",
+        "2020-08-14-200852_token",
+        "2020-08-17-172853_statistics",
+        "2020-08-21-064543_freeze_users",
+        "2020-08-21-133846_visible_users",
+        "2020-09-01-090000_machines",
+        "2020-09-05-090000_revert_sessions",
+        "2020-09-08-090000_user_calendars",
+    ];
+}
+
+mod sqlite {
+    embed_migrations!("migrations/sqlite");
+
+    pub use embedded_migrations::*;
+
+    pub const MIGRATIONS : &[&str] = &[
+        "2020-09-05-090000_initial_schema",
+        "2020-09-08-090000_user_calendars",
+    ];
+}
+
+pub use postgres::MIGRATIONS as POSTGRES_MIGRATIONS;
+pub use sqlite::MIGRATIONS as SQLITE_MIGRATIONS;
+
+fn run_with_output(
+    conn : &HubConnection,
+    output : &mut dyn std::io::Write,
+) -> crate::error::Result<()> {
+    match conn {
+        HubConnection::Postgres(conn) => {
+            postgres::run_with_output(conn, output)?
+        }
+        HubConnection::Sqlite(conn) => sqlite::run_with_output(conn, output)?,
+    }
 
     Ok(())
 }
+
+pub fn run(conn : &HubConnection) -> crate::error::Result<()> {
+    run_with_output(conn, &mut std::io::sink())
+}
+
+// The version diesel records for the migration `name' (e.g., `20200908090000'
+// for `2020-09-08-090000_user_calendars').
+fn version(name : &str) -> String {
+    name.split('_').next().unwrap_or(name).replace('-', "")
+}
+
+// The names of the embedded migrations which have not been applied to the
+// database yet, according to `__diesel_schema_migrations'. The database is
+// only read.
+pub fn pending(conn : &HubConnection) -> crate::error::Result<Vec<String>> {
+    let (migrations, applied) = match conn {
+        HubConnection::Postgres(conn) => (
+            POSTGRES_MIGRATIONS,
+            conn.previously_run_migration_versions()?,
+        ),
+        HubConnection::Sqlite(conn) => {
+            (SQLITE_MIGRATIONS, conn.previously_run_migration_versions()?)
+        }
+    };
+
+    Ok(migrations
+        .iter()
+        .filter(|name| !applied.contains(&version(name)))
+        .map(|name| (*name).to_owned())
+        .collect())
+}
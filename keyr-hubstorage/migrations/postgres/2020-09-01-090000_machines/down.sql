@@ -0,0 +1,31 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE statistics
+DROP CONSTRAINT unique_timestamp;
+
+-- Merge the statistics of the machines of each user
+UPDATE statistics
+SET count = merged.count
+FROM (
+    SELECT MIN(id) AS id, SUM(count) AS count
+    FROM statistics
+    GROUP BY timestamp, user_id
+) merged
+WHERE statistics.id = merged.id;
+
+DELETE FROM statistics
+WHERE id NOT IN (
+    SELECT MIN(id)
+    FROM statistics
+    GROUP BY timestamp, user_id
+);
+
+ALTER TABLE statistics
+DROP machine_id;
+
+ALTER TABLE statistics
+ADD CONSTRAINT unique_timestamp UNIQUE (timestamp, user_id);
+
+ALTER TABLE tokens
+DROP machine_id;
+
+DROP TABLE machines;
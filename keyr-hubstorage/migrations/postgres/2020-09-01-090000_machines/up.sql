@@ -0,0 +1,41 @@
+-- Your SQL goes here
+CREATE TABLE machines (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+
+    CONSTRAINT unique_machine_name UNIQUE (name, user_id)
+);
+
+-- Tokens and statistics which predate machines are bound to a default
+-- machine
+INSERT INTO machines (name, user_id)
+SELECT 'default', id FROM users;
+
+ALTER TABLE tokens
+ADD machine_id INTEGER REFERENCES machines(id);
+
+UPDATE tokens
+SET machine_id = machines.id
+FROM machines
+WHERE machines.user_id = tokens.user_id;
+
+ALTER TABLE tokens
+ALTER machine_id SET NOT NULL;
+
+ALTER TABLE statistics
+ADD machine_id INTEGER REFERENCES machines(id);
+
+UPDATE statistics
+SET machine_id = machines.id
+FROM machines
+WHERE machines.user_id = statistics.user_id;
+
+ALTER TABLE statistics
+ALTER machine_id SET NOT NULL;
+
+ALTER TABLE statistics
+DROP CONSTRAINT unique_timestamp;
+
+ALTER TABLE statistics
+ADD CONSTRAINT unique_timestamp UNIQUE (timestamp, user_id, machine_id);
@@ -0,0 +1,13 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users
+ADD frozen BOOLEAN DEFAULT false NOT NULL;
+
+UPDATE users
+SET frozen = true
+WHERE id IN (
+    SELECT user_id
+    FROM revert_sessions
+    WHERE expires_at > (now() AT TIME ZONE 'UTC')
+);
+
+DROP TABLE revert_sessions;
@@ -0,0 +1,13 @@
+-- A user is frozen while one of their agents is reverting their statistics,
+-- until the session of this agent is terminated, cancelled, or expires
+CREATE TABLE revert_sessions (
+    id VARCHAR(32) PRIMARY KEY,
+    user_id INTEGER NOT NULL UNIQUE REFERENCES users(id),
+    token_id INTEGER NOT NULL REFERENCES tokens(id) ON DELETE CASCADE,
+    expires_at TIMESTAMP NOT NULL
+);
+
+-- The agents which froze the users cannot know the id of a session, so
+-- these users are released
+ALTER TABLE users
+DROP frozen;
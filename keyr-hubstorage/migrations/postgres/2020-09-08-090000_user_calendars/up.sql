@@ -0,0 +1,7 @@
+-- The days of a user begin at `day_start' o'clock in their timezone, which
+-- is the name of an entry of the IANA database (e.g., `Europe/Paris')
+ALTER TABLE users
+ADD timezone VARCHAR NOT NULL DEFAULT 'UTC';
+
+ALTER TABLE users
+ADD day_start INTEGER NOT NULL DEFAULT 0;
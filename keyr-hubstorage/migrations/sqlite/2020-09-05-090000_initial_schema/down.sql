@@ -0,0 +1,5 @@
+DROP TABLE revert_sessions;
+DROP TABLE statistics;
+DROP TABLE tokens;
+DROP TABLE machines;
+DROP TABLE users;
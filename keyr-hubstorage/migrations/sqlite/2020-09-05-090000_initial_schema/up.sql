@@ -0,0 +1,40 @@
+-- The SQLite backend has been introduced after the PostgreSQL one, hence it
+-- starts from the schema the PostgreSQL migrations had reached by then. New
+-- migrations need to be written for both backends, with the same version.
+CREATE TABLE users (
+    id INTEGER PRIMARY KEY,
+    name VARCHAR NOT NULL UNIQUE,
+    visible BOOLEAN NOT NULL DEFAULT false
+);
+
+CREATE TABLE machines (
+    id INTEGER PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+
+    CONSTRAINT unique_machine_name UNIQUE (name, user_id)
+);
+
+CREATE TABLE tokens (
+    id INTEGER PRIMARY KEY,
+    token VARCHAR(32) NOT NULL UNIQUE,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    machine_id INTEGER NOT NULL REFERENCES machines(id)
+);
+
+CREATE TABLE statistics (
+    id INTEGER PRIMARY KEY,
+    timestamp TIMESTAMP NOT NULL,
+    count INTEGER NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    machine_id INTEGER NOT NULL REFERENCES machines(id),
+
+    CONSTRAINT unique_timestamp UNIQUE (timestamp, user_id, machine_id)
+);
+
+CREATE TABLE revert_sessions (
+    id VARCHAR(32) PRIMARY KEY,
+    user_id INTEGER NOT NULL UNIQUE REFERENCES users(id),
+    token_id INTEGER NOT NULL REFERENCES tokens(id) ON DELETE CASCADE,
+    expires_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users
+DROP COLUMN day_start;
+
+ALTER TABLE users
+DROP COLUMN timezone;
@@ -0,0 +1,212 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use tracing::instrument;
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::AddAssign;
+
+use keyr_types::Granularity;
+
+use crate::connection::HubConnection;
+use crate::error::Result;
+use crate::schema::statistics as stats;
+use crate::time::{self, Calendar};
+use crate::users::{MaybeUserId, UserId};
+
+// What to keep of the statistics of a user. The hourly statistics older than
+// `hourly_days' days are rolled into daily statistics, and the statistics
+// older than `max_age_days' days are deleted. Unset, they are kept as they
+// are forever.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RetentionPolicy {
+    pub hourly_days : Option<u32>,
+    pub max_age_days : Option<u32>,
+}
+
+// The retention policy of the users, with exceptions keyed by their name
+#[derive(Clone, Debug, Default)]
+pub struct Retention {
+    pub default : RetentionPolicy,
+    pub users : HashMap<String, RetentionPolicy>,
+}
+
+impl Retention {
+    pub fn policy(&self, name : &str) -> RetentionPolicy {
+        self.users.get(name).copied().unwrap_or(self.default)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompactionReport {
+    // The number of hourly statistics rolled into daily statistics
+    pub rolled_up : u64,
+    // The number of statistics deleted because of their age
+    pub deleted : u64,
+    // The number of users left untouched because they were being reverted
+    pub skipped : u64,
+}
+
+impl AddAssign for CompactionReport {
+    fn add_assign(&mut self, other : CompactionReport) {
+        self.rolled_up += other.rolled_up;
+        self.deleted += other.deleted;
+        self.skipped += other.skipped;
+    }
+}
+
+// The beginning of the oldest day of `calendar' which is less than `days'
+// days old. The statistics are compacted day by day, hence nothing more
+// recent is touched.
+pub(crate) fn cutoff(
+    calendar : &Calendar,
+    now : DateTime<Utc>,
+    days : u32,
+) -> NaiveDateTime {
+    calendar
+        .today(now - Duration::days(days as i64))
+        .naive_utc()
+}
+
+// The hour the daily statistics of the day `hour' belongs to are saved at,
+// i.e., the first hour which begins within this day. This is not the
+// beginning of the day for the timezones whose offset is not a whole number
+// of hours, and keeps the compaction from moving statistics from one day to
+// another when it is run again.
+pub(crate) fn day_of(
+    calendar : &Calendar,
+    hour : NaiveDateTime,
+) -> NaiveDateTime {
+    let start = calendar.today(DateTime::from_utc(hour, Utc)).naive_utc();
+    let res = time::truncate(start, Granularity::Hour);
+
+    if res < start {
+        res + Duration::hours(1)
+    } else {
+        res
+    }
+}
+
+// A row of the `statistics' table
+struct Hour {
+    id : i32,
+    timestamp : NaiveDateTime,
+    count : i32,
+}
+
+// Delete the statistics of a user older than `max_age_days' days, then roll
+// the hourly statistics older than `hourly_days' days into daily statistics,
+// machine by machine. Days are the ones of the calendar of the user. A user
+// being reverted is left untouched, so that the pages of their statistics do
+// not change under the feet of their agent. Needs to be called from within a
+// transaction.
+#[instrument(level = "debug", skip(conn))]
+pub fn compact_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+    policy : RetentionPolicy,
+    now : DateTime<Utc>,
+) -> Result<CompactionReport> {
+    let mut report = CompactionReport::default();
+
+    if crate::reverts::is_frozen_in_transaction(conn, id)? {
+        report.skipped = 1;
+        return Ok(report);
+    }
+
+    let calendar = crate::users::get_calendar_in_transaction(conn, id)?;
+
+    if let Some(days) = policy.max_age_days {
+        let cutoff = cutoff(&calendar, now, days);
+
+        report.deleted = run!(conn, conn => diesel::delete(
+            stats::table
+                .filter(stats::user_id.eq(id.0))
+                .filter(stats::timestamp.lt(cutoff)),
+        )
+        .execute(conn))? as u64;
+    }
+
+    if let Some(days) = policy.hourly_days {
+        let cutoff = cutoff(&calendar, now, days);
+
+        let rows = run!(conn, conn => stats::table
+            .select((stats::id, stats::machine_id, stats::timestamp, stats::count))
+            .filter(stats::user_id.eq(id.0))
+            .filter(stats::timestamp.lt(cutoff))
+            .load::<(i32, i32, NaiveDateTime, i32)>(conn))?;
+
+        let mut days : BTreeMap<(i32, NaiveDateTime), Vec<Hour>> =
+            BTreeMap::new();
+
+        for (row, machine, timestamp, count) in rows {
+            days.entry((machine, day_of(&calendar, timestamp)))
+                .or_default()
+                .push(Hour {
+                    id : row,
+                    timestamp,
+                    count,
+                });
+        }
+
+        for ((machine, day), hours) in days {
+            // This day has already been compacted
+            if hours.len() == 1 && hours[0].timestamp == day {
+                continue;
+            }
+
+            let ids = hours.iter().map(|h| h.id).collect::<Vec<_>>();
+            let count = hours.iter().map(|h| h.count).sum::<i32>();
+
+            run!(conn, conn => {
+                diesel::delete(stats::table.filter(stats::id.eq_any(&ids)))
+                    .execute(conn)?;
+
+                diesel::insert_into(stats::table)
+                    .values(vec![(
+                        stats::timestamp.eq(day),
+                        stats::count.eq(count),
+                        stats::user_id.eq(id.0),
+                        stats::machine_id.eq(machine),
+                    )])
+                    .execute(conn)
+            })?;
+
+            report.rolled_up += hours.len() as u64;
+        }
+    }
+
+    Ok(report)
+}
+
+#[instrument(level = "debug", skip(conn))]
+pub fn compact(
+    conn : &HubConnection,
+    id : MaybeUserId,
+    policy : RetentionPolicy,
+    now : DateTime<Utc>,
+) -> Result<CompactionReport> {
+    conn.transaction(|| {
+        let id = id.validate(conn)?;
+
+        compact_in_transaction(conn, id, policy, now)
+    })
+}
@@ -0,0 +1,219 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::connection::HubConnection;
+use crate::error::{KeyrHubstorageError, Result};
+use crate::schema::{revert_sessions as sessions, tokens};
+use crate::users::{Token, UserId};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RevertSessionId(pub String);
+
+#[derive(Clone, Debug)]
+pub struct RevertSession {
+    pub id : RevertSessionId,
+    pub expires_at : DateTime<Utc>,
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+// Forget about the sessions of a user which have expired, so that their
+// agents cannot use them anymore, and the user can commit again. Needs to be
+// called from within a transaction.
+fn release_expired_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+) -> Result<()> {
+    run!(conn, conn => diesel::delete(
+        sessions::table
+            .filter(sessions::user_id.eq(id.0))
+            .filter(sessions::expires_at.le(now())),
+    )
+    .execute(conn))?;
+
+    Ok(())
+}
+
+// Check whether or not a user is frozen by an ongoing revert. Needs to be
+// called from within a transaction.
+#[instrument(level = "debug", skip(conn))]
+pub fn is_frozen_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+) -> Result<bool> {
+    release_expired_in_transaction(conn, id)?;
+
+    let res = run!(conn, conn => sessions::table
+        .filter(sessions::user_id.eq(id.0))
+        .count()
+        .get_result::<i64>(conn))?;
+
+    Ok(res > 0)
+}
+
+fn token_id_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+    token : &Token,
+) -> Result<i32> {
+    run!(conn, conn => tokens::table
+        .select(tokens::id)
+        .filter(tokens::token.eq(&token.0))
+        .filter(tokens::user_id.eq(id.0))
+        .get_result::<i32>(conn)
+        .optional())?
+    .ok_or(KeyrHubstorageError::InvalidToken)
+}
+
+// Freeze a user for `lease', on behalf of the agent using `token'. An agent
+// can open a new session while its previous one is still active, e.g., when
+// it retries a revert, but the other agents of the user have to wait for the
+// session to end. Needs to be called from within a transaction.
+#[instrument(level = "debug", skip(conn, token))]
+pub fn open_session_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+    token : &Token,
+    lease : Duration,
+) -> Result<RevertSession> {
+    release_expired_in_transaction(conn, id)?;
+
+    let token_id = token_id_in_transaction(conn, id, token)?;
+
+    let owner = run!(conn, conn => sessions::table
+        .select(sessions::token_id)
+        .filter(sessions::user_id.eq(id.0))
+        .get_result::<i32>(conn)
+        .optional())?;
+
+    match owner {
+        Some(owner) if owner != token_id => {
+            return Err(KeyrHubstorageError::FrozenUser)
+        }
+        Some(_) => {
+            run!(conn, conn => diesel::delete(sessions::table.filter(sessions::user_id.eq(id.0)))
+                .execute(conn))?;
+        }
+        None => (),
+    }
+
+    let session = RevertSessionId(Uuid::new_v4().to_simple().to_string());
+    let expires_at = now() + lease;
+
+    run!(conn, conn => diesel::insert_into(sessions::table)
+        .values(vec![(
+            sessions::id.eq(&session.0),
+            sessions::user_id.eq(id.0),
+            sessions::token_id.eq(token_id),
+            sessions::expires_at.eq(expires_at),
+        )])
+        .execute(conn))?;
+
+    Ok(RevertSession {
+        id : session,
+        expires_at : DateTime::from_utc(expires_at, Utc),
+    })
+}
+
+// Check that a session is still active, and that it belongs to the agent
+// using `token'. Needs to be called from within a transaction.
+#[instrument(level = "debug", skip(conn, token))]
+pub fn validate_session_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+    token : &Token,
+    session : &RevertSessionId,
+) -> Result<()> {
+    release_expired_in_transaction(conn, id)?;
+
+    let token_id = token_id_in_transaction(conn, id, token)?;
+
+    run!(conn, conn => sessions::table
+        .select(sessions::id)
+        .filter(sessions::id.eq(&session.0))
+        .filter(sessions::user_id.eq(id.0))
+        .filter(sessions::token_id.eq(token_id))
+        .get_result::<String>(conn)
+        .optional())?
+    .ok_or(KeyrHubstorageError::UnknownRevertSession)?;
+
+    Ok(())
+}
+
+// The active session opened by the agent using `token', for the agents
+// which do not keep track of the identifier of their session. Needs to be
+// called from within a transaction.
+#[instrument(level = "debug", skip(conn, token))]
+pub fn find_session_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+    token : &Token,
+) -> Result<RevertSessionId> {
+    release_expired_in_transaction(conn, id)?;
+
+    let token_id = token_id_in_transaction(conn, id, token)?;
+
+    run!(conn, conn => sessions::table
+        .select(sessions::id)
+        .filter(sessions::user_id.eq(id.0))
+        .filter(sessions::token_id.eq(token_id))
+        .get_result::<String>(conn)
+        .optional())?
+    .map(RevertSessionId)
+    .ok_or(KeyrHubstorageError::UnknownRevertSession)
+}
+
+// Push back the expiration of an active session to `lease' from now. Needs
+// to be called from within a transaction.
+#[instrument(level = "debug", skip(conn))]
+pub fn renew_session_in_transaction(
+    conn : &HubConnection,
+    session : RevertSessionId,
+    lease : Duration,
+) -> Result<RevertSession> {
+    let expires_at = now() + lease;
+
+    run!(conn, conn => diesel::update(sessions::table.find(&session.0))
+        .set(sessions::expires_at.eq(expires_at))
+        .execute(conn))?;
+
+    Ok(RevertSession {
+        id : session,
+        expires_at : DateTime::from_utc(expires_at, Utc),
+    })
+}
+
+// Unfreeze the user of a session. Needs to be called from within a
+// transaction.
+#[instrument(level = "debug", skip(conn))]
+pub fn close_session_in_transaction(
+    conn : &HubConnection,
+    session : &RevertSessionId,
+) -> Result<()> {
+    run!(conn, conn => diesel::delete(sessions::table.find(&session.0)).execute(conn))?;
+
+    Ok(())
+}
@@ -1,9 +1,27 @@
+table! {
+    machines (id) {
+        id -> Int4,
+        name -> Varchar,
+        user_id -> Int4,
+    }
+}
+
+table! {
+    revert_sessions (id) {
+        id -> Varchar,
+        user_id -> Int4,
+        token_id -> Int4,
+        expires_at -> Timestamp,
+    }
+}
+
 table! {
     statistics (id) {
         id -> Int4,
         timestamp -> Timestamp,
         count -> Int4,
         user_id -> Int4,
+        machine_id -> Int4,
     }
 }
 
@@ -12,6 +30,7 @@ table! {
         id -> Int4,
         token -> Varchar,
         user_id -> Int4,
+        machine_id -> Int4,
     }
 }
 
@@ -19,12 +38,24 @@ table! {
     users (id) {
         id -> Int4,
         name -> Varchar,
-        frozen -> Bool,
         visible -> Bool,
+        timezone -> Varchar,
+        day_start -> Int4,
     }
 }
 
+joinable!(machines -> users (user_id));
+joinable!(revert_sessions -> tokens (token_id));
+joinable!(revert_sessions -> users (user_id));
+joinable!(statistics -> machines (machine_id));
 joinable!(statistics -> users (user_id));
+joinable!(tokens -> machines (machine_id));
 joinable!(tokens -> users (user_id));
 
-allow_tables_to_appear_in_same_query!(statistics, tokens, users,);
+allow_tables_to_appear_in_same_query!(
+    machines,
+    revert_sessions,
+    statistics,
+    tokens,
+    users,
+);
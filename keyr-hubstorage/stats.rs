@@ -1,40 +1,51 @@
-use chrono::{DateTime, NaiveDateTime, TimeZone, Timelike, Utc};
-use diesel::pg::Pg;
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
 use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Integer, Nullable, Text, Timestamp};
+use tracing::instrument;
 
 use std::collections::HashMap;
 
-use keyr_types::{KeystrokesStats, Summary};
+use keyr_types::{
+    Granularity, KeystrokesPoint, KeystrokesSeries, KeystrokesStats,
+    Leaderboard, LeaderboardEntry, MachineSummary, Period, Summary,
+};
 
+use crate::connection::HubConnection;
 use crate::error::{KeyrHubstorageError, Result};
+use crate::machines::MachineId;
+use crate::reverts::{RevertSession, RevertSessionId};
 use crate::schema::statistics as stats;
-use crate::users::{MaybeUserId, UserId};
+use crate::schema::users;
+use crate::time::{self, Calendar};
+use crate::users::{MaybeUserId, Token, UserId};
 
-pub fn upsert_keystrokes_count<Conn>(
-    conn : &Conn,
+#[instrument(level = "trace", skip(conn))]
+pub fn upsert_keystrokes_count(
+    conn : &HubConnection,
     mid : MaybeUserId,
+    machine : MachineId,
     date : &DateTime<Utc>,
     count : i32,
-) -> Result<()>
-where
-    Conn : Connection<Backend = Pg>,
-{
+) -> Result<()> {
     conn.transaction(|| {
         let id = mid.validate(conn)?;
-        upsert_keystrokes_count_in_transaction(conn, id, &date, count)
+        let machine =
+            crate::machines::validate_in_transaction(conn, id, machine)?;
+
+        upsert_keystrokes_count_in_transaction(conn, id, machine, &date, count)
     })
 }
 
-pub fn upsert_keystrokes_count_in_transaction<Conn>(
-    conn : &Conn,
+#[instrument(level = "trace", skip(conn))]
+pub fn upsert_keystrokes_count_in_transaction(
+    conn : &HubConnection,
     id : UserId,
+    machine : MachineId,
     date : &DateTime<Utc>,
     count : i32,
-) -> Result<()>
-where
-    Conn : Connection<Backend = Pg>,
-{
-    if crate::users::is_frozen_in_transaction(conn, id)? {
+) -> Result<()> {
+    if crate::reverts::is_frozen_in_transaction(conn, id)? {
         return Err(KeyrHubstorageError::FrozenUser);
     }
 
@@ -47,161 +58,747 @@ where
         .unwrap()
         .naive_utc();
 
-    let prev = stats::table
+    let prev = run!(conn, conn => stats::table
         .select((stats::id, stats::count))
         .filter(stats::timestamp.eq(&date))
         .filter(stats::user_id.eq(id.0))
+        .filter(stats::machine_id.eq(machine.0))
         .get_result::<(i32, i32)>(conn)
-        .optional()?;
+        .optional())?;
 
     match prev {
         Some((id, prev_count)) => {
-            diesel::update(stats::table.find(id))
+            run!(conn, conn => diesel::update(stats::table.find(id))
                 .set(stats::count.eq(prev_count + count))
-                .execute(conn)?;
+                .execute(conn))?;
         }
         None => {
-            diesel::insert_into(stats::table)
+            run!(conn, conn => diesel::insert_into(stats::table)
                 .values(vec![(
                     stats::timestamp.eq(&date),
                     stats::count.eq(count),
                     stats::user_id.eq(id.0),
+                    stats::machine_id.eq(machine.0),
                 )])
-                .execute(conn)?;
+                .execute(conn))?;
         }
     }
 
     Ok(())
 }
 
-pub fn commit<Conn>(
-    conn : &Conn,
+// Save the keystrokes of a user, and return their summary. The day of the
+// summary is the one `now' belongs to, according to the calendar of the
+// user.
+#[instrument(level = "debug", skip(conn, sa))]
+pub fn commit(
+    conn : &HubConnection,
     id : MaybeUserId,
-    today : DateTime<Utc>,
+    machine : MachineId,
+    now : DateTime<Utc>,
     sa : &KeystrokesStats,
-) -> Result<Summary>
-where
-    Conn : Connection<Backend = Pg>,
-{
+) -> Result<Summary> {
     conn.transaction(|| {
         let id = id.validate(conn)?;
+        let machine =
+            crate::machines::validate_in_transaction(conn, id, machine)?;
 
         for (t, v) in sa.iter() {
             let date = Utc.timestamp(*t, 0);
 
-            upsert_keystrokes_count_in_transaction(conn, id, &date, *v as i32)?;
+            upsert_keystrokes_count_in_transaction(
+                conn, id, machine, &date, *v as i32,
+            )?;
         }
 
+        let today =
+            crate::users::get_calendar_in_transaction(conn, id)?.today(now);
         let s = get_summary_in_transaction(conn, id, today)?;
 
         Ok(s)
     })
 }
 
-pub fn get_summary_in_transaction<Conn>(
-    conn : &Conn,
+#[instrument(level = "debug", skip(conn))]
+pub fn get_summary_in_transaction(
+    conn : &HubConnection,
     id : UserId,
     today : DateTime<Utc>,
-) -> Result<Summary>
-where
-    Conn : Connection<Backend = Pg>,
-{
-    let oldest_entry = stats::table
-        .select(stats::timestamp)
-        .filter(stats::user_id.eq(id.0))
-        .order(stats::timestamp.asc())
-        .first::<NaiveDateTime>(conn)
-        .optional()?
-        .unwrap_or(today.naive_utc());
+) -> Result<Summary> {
+    let oldest_entry =
+        get_oldest_entry_in_transaction(conn, id)?.unwrap_or(today.naive_utc());
 
-    let today_count = stats::table
-        .select(diesel::dsl::sum(stats::count))
-        .filter(stats::timestamp.ge(today.naive_utc()))
-        .first::<Option<i64>>(conn)?
-        .unwrap_or(0);
+    let today_count = get_count_since_in_transaction(conn, id, Some(today))?;
+    let global_count = get_count_since_in_transaction(conn, id, None)?;
 
-    let global_count = stats::table
-        .select(diesel::dsl::sum(stats::count))
-        .first::<Option<i64>>(conn)?
-        .unwrap_or(0);
+    let machines = get_machines_summary_in_transaction(conn, id, today)?;
 
     Ok(Summary {
         oldest_timestamp : oldest_entry.timestamp(),
-        global_count : global_count as u64,
-        today_count : today_count as u64,
+        global_count,
+        today_count,
         today_timestamp : today.naive_utc().timestamp(),
+        machines,
     })
 }
 
-pub fn get_keystrokes_stats_in_transaction<Conn>(
-    conn : &Conn,
+#[derive(QueryableByName)]
+struct MachineCounts {
+    #[sql_type = "Text"]
+    name : String,
+    #[sql_type = "BigInt"]
+    global_count : i64,
+    #[sql_type = "BigInt"]
+    today_count : i64,
+}
+
+// Break the keystrokes count of a user down by machine. Machines without any
+// keystrokes are part of the result.
+#[instrument(level = "debug", skip(conn))]
+pub fn get_machines_summary_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+    today : DateTime<Utc>,
+) -> Result<Vec<MachineSummary>> {
+    // SQLite names the `$N' parameters in the order they appear, so they
+    // have to appear in increasing order
+    let counts = run!(conn, conn => diesel::sql_query(
+        "SELECT m.name AS name, \
+                CAST(COALESCE(SUM(s.count), 0) AS BIGINT) AS global_count, \
+                CAST(COALESCE(SUM(CASE WHEN s.timestamp >= $1 \
+                                       THEN s.count ELSE 0 END), 0) \
+                     AS BIGINT) AS today_count \
+         FROM machines m LEFT JOIN statistics s ON s.machine_id = m.id \
+         WHERE m.user_id = $2 \
+         GROUP BY m.id, m.name \
+         ORDER BY m.name",
+    )
+    .bind::<Timestamp, _>(today.naive_utc())
+    .bind::<Integer, _>(id.0)
+    .load::<MachineCounts>(conn))?;
+
+    Ok(counts
+        .into_iter()
+        .map(|c| MachineSummary {
+            name : c.name,
+            global_count : c.global_count as u64,
+            today_count : c.today_count as u64,
+        })
+        .collect())
+}
+
+// The summary of a user for the day `now' belongs to, according to their
+// calendar
+#[instrument(level = "debug", skip(conn))]
+pub fn get_summary(
+    conn : &HubConnection,
+    id : MaybeUserId,
+    now : DateTime<Utc>,
+) -> Result<Summary> {
+    conn.transaction(|| {
+        let id = id.validate(conn)?;
+        let today =
+            crate::users::get_calendar_in_transaction(conn, id)?.today(now);
+
+        get_summary_in_transaction(conn, id, today)
+    })
+}
+
+// Count the keystrokes of a user since a given date, or since the beginning of
+// time.
+#[instrument(level = "debug", skip(conn))]
+pub fn get_count_since_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+    since : Option<DateTime<Utc>>,
+) -> Result<u64> {
+    let res = run!(conn, conn => {
+        let mut query = stats::table
+            .select(diesel::dsl::sum(stats::count))
+            .filter(stats::user_id.eq(id.0))
+            .into_boxed();
+
+        if let Some(since) = since {
+            query = query.filter(stats::timestamp.ge(since.naive_utc()));
+        }
+
+        query.first::<Option<i64>>(conn)
+    })?
+    .unwrap_or(0);
+
+    Ok(res as u64)
+}
+
+#[instrument(level = "debug", skip(conn))]
+pub fn get_oldest_entry_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+) -> Result<Option<NaiveDateTime>> {
+    let res = run!(conn, conn => stats::table
+        .select(stats::timestamp)
+        .filter(stats::user_id.eq(id.0))
+        .order(stats::timestamp.asc())
+        .first::<NaiveDateTime>(conn)
+        .optional())?;
+
+    Ok(res)
+}
+
+#[derive(QueryableByName)]
+struct Bucket {
+    #[sql_type = "Timestamp"]
+    bucket : NaiveDateTime,
+    #[sql_type = "BigInt"]
+    count : i64,
+}
+
+// The maximum number of points of a series, which are built in memory
+pub const MAX_SERIES_LENGTH : i64 = 10_000;
+
+// Refuse to build a series of more than `MAX_SERIES_LENGTH' points. Buckets
+// are assumed to be as short as their shortest instance, so the few hours
+// lost to the changes to daylight saving time can only let a handful of
+// points through.
+pub(crate) fn check_series_length(
+    from : DateTime<Utc>,
+    to : DateTime<Utc>,
+    granularity : Granularity,
+) -> Result<()> {
+    let bucket = match granularity {
+        Granularity::Hour => Duration::hours(1),
+        Granularity::Day => Duration::days(1),
+        Granularity::Week => Duration::weeks(1),
+        Granularity::Month => Duration::days(28),
+    };
+
+    if (to - from).num_seconds() / bucket.num_seconds() >= MAX_SERIES_LENGTH {
+        return Err(KeyrHubstorageError::TooLongSeries(MAX_SERIES_LENGTH));
+    }
+
+    Ok(())
+}
+
+// Aggregate the keystrokes of a user between `from' (included) and `to'
+// (excluded), bucket by bucket. Buckets are computed with `calendar', and
+// the gaps are filled with zeros. If `machine' is set, only the keystrokes of
+// this machine are considered.
+#[instrument(level = "debug", skip(conn))]
+pub fn get_keystrokes_series_in_transaction(
+    conn : &HubConnection,
     id : UserId,
-) -> Result<KeystrokesStats>
+    machine : Option<MachineId>,
+    from : DateTime<Utc>,
+    to : DateTime<Utc>,
+    granularity : Granularity,
+    calendar : Calendar,
+) -> Result<KeystrokesSeries> {
+    check_series_length(from, to, granularity)?;
+
+    let buckets = match conn {
+        // The days begin at `day_start' o'clock, hence the local dates are
+        // shifted back by that many hours before being truncated
+        HubConnection::Postgres(conn) => diesel::sql_query(
+            "SELECT date_trunc($1, ((timestamp AT TIME ZONE 'UTC') AT TIME ZONE $2) \
+                                   - $3 * INTERVAL '1 hour') \
+                    + $3 * INTERVAL '1 hour' AS bucket, \
+                    CAST(SUM(count) AS BIGINT) AS count \
+             FROM statistics \
+             WHERE user_id = $4 AND timestamp >= $5 AND timestamp < $6 \
+               AND ($7 IS NULL OR machine_id = $7) \
+             GROUP BY bucket \
+             ORDER BY bucket",
+        )
+        .bind::<Text, _>(time::date_trunc_field(granularity))
+        .bind::<Text, _>(calendar.tz.name())
+        .bind::<Integer, _>(calendar.day_start as i32)
+        .bind::<Integer, _>(id.0)
+        .bind::<Timestamp, _>(from.naive_utc())
+        .bind::<Timestamp, _>(to.naive_utc())
+        .bind::<Nullable<Integer>, _>(machine.map(|m| m.0))
+        .load::<Bucket>(conn)?
+        .into_iter()
+        .map(|b| (b.bucket, b.count as u64))
+        .collect::<HashMap<_, _>>(),
+        // SQLite knows nothing about timezones, so the range is split where
+        // the offset of the timezone changes, and each part is grouped with
+        // its own offset. The buckets spanning such a change are grouped
+        // once per part, and summed here.
+        HubConnection::Sqlite(conn) => {
+            let query = format!(
+                "SELECT datetime({}, $1) AS bucket, \
+                        CAST(SUM(count) AS BIGINT) AS count \
+                 FROM (SELECT datetime(timestamp, $2, $3) AS local, count \
+                       FROM statistics \
+                       WHERE user_id = $4 \
+                         AND timestamp >= $5 AND timestamp < $6 \
+                         AND ($7 IS NULL OR machine_id = $7)) \
+                 GROUP BY bucket",
+                time::sqlite_truncate(granularity),
+            );
+
+            let mut buckets = HashMap::new();
+
+            for (start, end, offset) in
+                time::split_by_offset(calendar.tz, from, to)
+            {
+                let parts = diesel::sql_query(&query)
+                    .bind::<Text, _>(format!("+{} hours", calendar.day_start))
+                    .bind::<Text, _>(format!("{:+} seconds", offset))
+                    .bind::<Text, _>(format!("-{} hours", calendar.day_start))
+                    .bind::<Integer, _>(id.0)
+                    .bind::<Timestamp, _>(start.naive_utc())
+                    .bind::<Timestamp, _>(end.naive_utc())
+                    .bind::<Nullable<Integer>, _>(machine.map(|m| m.0))
+                    .load::<Bucket>(conn)?;
+
+                for part in parts {
+                    *buckets.entry(part.bucket).or_insert(0) +=
+                        part.count as u64;
+                }
+            }
+
+            buckets
+        }
+    };
+
+    Ok(fill_series(&buckets, from, to, granularity, calendar))
+}
+
+// Put hourly counts (in UTC) in the buckets of `granularity' they belong to
+// according to `calendar'.
+pub(crate) fn bucket_hours<I>(
+    hours : I,
+    granularity : Granularity,
+    calendar : Calendar,
+) -> HashMap<NaiveDateTime, u64>
+where
+    I : IntoIterator<Item = (NaiveDateTime, u64)>,
+{
+    let mut buckets = HashMap::new();
+
+    for (hour, count) in hours {
+        let local = time::to_local(calendar.tz, DateTime::from_utc(hour, Utc));
+
+        *buckets
+            .entry(calendar.truncate(local, granularity))
+            .or_insert(0) += count;
+    }
+
+    buckets
+}
+
+// Turn buckets (computed with `calendar') into the series of every bucket
+// between `from' (included) and `to' (excluded), filling the gaps with zeros.
+pub(crate) fn fill_series(
+    buckets : &HashMap<NaiveDateTime, u64>,
+    from : DateTime<Utc>,
+    to : DateTime<Utc>,
+    granularity : Granularity,
+    calendar : Calendar,
+) -> KeystrokesSeries {
+    let mut res = vec![];
+
+    let end = time::to_local(calendar.tz, to);
+    let mut current =
+        calendar.truncate(time::to_local(calendar.tz, from), granularity);
+
+    while current < end {
+        res.push(KeystrokesPoint {
+            timestamp : time::to_utc(&calendar.tz, current).timestamp(),
+            count : buckets.get(&current).copied().unwrap_or(0),
+        });
+
+        current = calendar.next(current, granularity);
+    }
+
+    res
+}
+
+#[derive(QueryableByName)]
+struct HourCount {
+    #[sql_type = "Integer"]
+    hour : i32,
+    #[sql_type = "BigInt"]
+    count : i64,
+}
+
+// Sum the keystrokes of a user by hour of the day, in the timezone `tz'.
+#[instrument(level = "debug", skip(conn))]
+pub fn get_hourly_distribution_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+    tz : Tz,
+) -> Result<[u64; 24]> {
+    let mut res = [0; 24];
+
+    match conn {
+        HubConnection::Postgres(conn) => {
+            let counts = diesel::sql_query(
+                "SELECT CAST(EXTRACT(HOUR FROM \
+                            (timestamp AT TIME ZONE 'UTC') AT TIME ZONE $2) \
+                        AS INTEGER) AS hour, \
+                        CAST(SUM(count) AS BIGINT) AS count \
+                 FROM statistics \
+                 WHERE user_id = $1 \
+                 GROUP BY hour",
+            )
+            .bind::<Integer, _>(id.0)
+            .bind::<Text, _>(tz.name())
+            .load::<HourCount>(conn)?;
+
+            for c in counts {
+                res[c.hour as usize] = c.count as u64;
+            }
+        }
+        // SQLite knows nothing about timezones, so the hours are converted
+        // here
+        HubConnection::Sqlite(conn) => {
+            let hours = diesel::sql_query(
+                "SELECT timestamp AS bucket, \
+                        CAST(SUM(count) AS BIGINT) AS count \
+                 FROM statistics \
+                 WHERE user_id = $1 \
+                 GROUP BY timestamp",
+            )
+            .bind::<Integer, _>(id.0)
+            .load::<Bucket>(conn)?;
+
+            for h in hours {
+                let local =
+                    time::to_local(tz, DateTime::from_utc(h.bucket, Utc));
+
+                res[local.hour() as usize] += h.count as u64;
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+#[derive(QueryableByName)]
+struct UserCount {
+    #[sql_type = "Integer"]
+    user_id : i32,
+    #[sql_type = "BigInt"]
+    count : i64,
+}
+
+#[derive(QueryableByName)]
+struct UserHour {
+    #[sql_type = "Integer"]
+    user_id : i32,
+    #[sql_type = "Timestamp"]
+    bucket : NaiveDateTime,
+    #[sql_type = "BigInt"]
+    count : i64,
+}
+
+// Rank the visible users by their keystrokes count over the `period' which
+// contains `now', according to the calendar of each user. Tied users share
+// the same rank, and every user tied with the last ranked one is returned,
+// hence the result can have more than `limit' entries.
+#[instrument(level = "debug", skip(conn))]
+pub fn get_leaderboard_in_transaction(
+    conn : &HubConnection,
+    period : Period,
+    now : DateTime<Utc>,
+    limit : u32,
+) -> Result<Leaderboard> {
+    let visible = run!(conn, conn => users::table
+        .filter(users::visible)
+        .select((users::id, users::name, users::timezone, users::day_start))
+        .load::<(i32, String, String, i32)>(conn))?;
+
+    let starts = visible
+        .iter()
+        .map(|(id, _, tz, day_start)| {
+            let calendar = crate::users::to_calendar(tz, *day_start);
+
+            (*id, calendar.period_start(period, now))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut counts = HashMap::new();
+
+    match starts.values().flatten().min() {
+        // The period begins at a different instant for each user, so their
+        // keystrokes are fetched hour by hour since the earliest one, and
+        // summed here
+        Some(earliest) => {
+            let hours = run!(conn, conn => diesel::sql_query(
+                "SELECT s.user_id AS user_id, s.timestamp AS bucket, \
+                        CAST(SUM(s.count) AS BIGINT) AS count \
+                 FROM statistics s INNER JOIN users u ON u.id = s.user_id \
+                 WHERE u.visible AND s.timestamp >= $1 \
+                 GROUP BY s.user_id, s.timestamp",
+            )
+            .bind::<Timestamp, _>(earliest.naive_utc())
+            .load::<UserHour>(conn))?;
+
+            for h in hours {
+                let start = starts.get(&h.user_id).copied().flatten();
+
+                if start.map(|s| h.bucket >= s.naive_utc()).unwrap_or(false) {
+                    *counts.entry(h.user_id).or_insert(0) += h.count as u64;
+                }
+            }
+        }
+        None => {
+            let totals = run!(conn, conn => diesel::sql_query(
+                "SELECT s.user_id AS user_id, \
+                        CAST(SUM(s.count) AS BIGINT) AS count \
+                 FROM statistics s INNER JOIN users u ON u.id = s.user_id \
+                 WHERE u.visible \
+                 GROUP BY s.user_id",
+            )
+            .load::<UserCount>(conn))?;
+
+            for t in totals {
+                counts.insert(t.user_id, t.count as u64);
+            }
+        }
+    }
+
+    let names = visible
+        .into_iter()
+        .map(|(id, name, _, _)| (id, name))
+        .collect::<HashMap<_, _>>();
+
+    Ok(rank(
+        counts
+            .into_iter()
+            .filter_map(|(id, count)| Some((names.get(&id)?.clone(), count))),
+        limit,
+    ))
+}
+
+// Rank users by their keystrokes count. Tied users share the same rank, as
+// with `RANK()' in SQL, and are sorted by name.
+pub(crate) fn rank<I>(counts : I, limit : u32) -> Leaderboard
 where
-    Conn : Connection<Backend = Pg>,
+    I : IntoIterator<Item = (String, u64)>,
 {
-    let datas = stats::table
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    let mut res : Leaderboard = vec![];
+
+    counts.sort_by(|(n1, c1), (n2, c2)| c2.cmp(c1).then_with(|| n1.cmp(n2)));
+
+    for (i, (name, count)) in counts.into_iter().enumerate() {
+        let rank = match res.last() {
+            Some(prev) if prev.count == count => prev.rank,
+            _ => i as u64 + 1,
+        };
+
+        if rank > limit as u64 {
+            break;
+        }
+
+        res.push(LeaderboardEntry { rank, name, count });
+    }
+
+    res
+}
+
+#[instrument(level = "debug", skip(conn))]
+pub fn get_keystrokes_stats_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+) -> Result<KeystrokesStats> {
+    let datas = run!(conn, conn => stats::table
         .select((stats::timestamp, stats::count))
         .filter(stats::user_id.eq(id.0))
-        .get_results::<(NaiveDateTime, i32)>(conn)?;
+        .get_results::<(NaiveDateTime, i32)>(conn))?;
 
     let mut sa = HashMap::new();
 
+    // Several machines can have keystrokes for the same hour
     for (t, v) in datas.iter() {
-        sa.insert(t.timestamp(), *v as u32);
+        *sa.entry(t.timestamp()).or_insert(0) += *v as u32;
     }
 
     Ok(sa)
 }
 
-pub fn initiate_revert_in_transaction<Conn>(
-    conn : &Conn,
+// Freeze a user, so that their statistics can be fetched page by page with
+// `get_revert_page'. The returned session needs to be terminated or cancelled
+// before `lease' expires. Needs to be called from within a transaction.
+#[instrument(level = "debug", skip(conn, token))]
+pub fn initiate_revert_in_transaction(
+    conn : &HubConnection,
     id : UserId,
-) -> Result<KeystrokesStats>
-where
-    Conn : Connection<Backend = Pg>,
-{
-    crate::users::freeze_user_in_transaction(conn, id)?;
+    token : &Token,
+    lease : Duration,
+) -> Result<RevertSession> {
+    crate::reverts::open_session_in_transaction(conn, id, token, lease)
+}
+
+#[instrument(level = "debug", skip(conn, token))]
+pub fn initiate_revert(
+    conn : &HubConnection,
+    id : MaybeUserId,
+    token : &Token,
+    lease : Duration,
+) -> Result<RevertSession> {
+    conn.transaction(|| {
+        let id = id.validate(conn)?;
 
-    get_keystrokes_stats_in_transaction(conn, id)
+        initiate_revert_in_transaction(conn, id, token, lease)
+    })
 }
 
-pub fn initiate_revert<Conn>(
-    conn : &Conn,
+#[instrument(level = "debug", skip(conn, token))]
+pub fn find_revert_session(
+    conn : &HubConnection,
     id : MaybeUserId,
-) -> Result<KeystrokesStats>
-where
-    Conn : Connection<Backend = Pg>,
-{
+    token : &Token,
+) -> Result<RevertSessionId> {
     conn.transaction(|| {
         let id = id.validate(conn)?;
-        let res = initiate_revert_in_transaction(conn, id)?;
 
-        Ok(res)
+        crate::reverts::find_session_in_transaction(conn, id, token)
     })
 }
 
-pub fn terminate_revert_in_transaction<Conn>(
-    conn : &Conn,
+// A page of the statistics of a frozen user
+pub struct RevertPage {
+    pub session : RevertSession,
+    pub statistics : KeystrokesStats,
+    // The cursor to fetch the next page with, if any
+    pub next : Option<DateTime<Utc>>,
+}
+
+// Fetch at most `limit' hours of the statistics of a frozen user, starting
+// right after the cursor `after' (or from the oldest hour), in chronological
+// order. Fetching a page renews the lease of the session, so that a revert
+// can last longer than `lease' as long as it makes progress. Needs to be
+// called from within a transaction.
+#[instrument(level = "debug", skip(conn, token))]
+pub fn get_revert_page_in_transaction(
+    conn : &HubConnection,
     id : UserId,
-) -> Result<()>
-where
-    Conn : Connection<Backend = Pg>,
-{
-    diesel::delete(stats::table.filter(stats::user_id.eq(id.0)))
-        .execute(conn)?;
+    token : &Token,
+    session : &RevertSessionId,
+    after : Option<DateTime<Utc>>,
+    limit : u32,
+    lease : Duration,
+) -> Result<RevertPage> {
+    crate::reverts::validate_session_in_transaction(conn, id, token, session)?;
+
+    let session = crate::reverts::renew_session_in_transaction(
+        conn,
+        session.clone(),
+        lease,
+    )?;
+
+    // One more hour than requested is fetched, to know whether or not
+    // another page follows
+    let mut hours = run!(conn, conn => diesel::sql_query(
+        "SELECT timestamp AS bucket, CAST(SUM(count) AS BIGINT) AS count \
+         FROM statistics \
+         WHERE user_id = $1 AND ($2 IS NULL OR timestamp > $2) \
+         GROUP BY timestamp \
+         ORDER BY timestamp \
+         LIMIT $3",
+    )
+    .bind::<Integer, _>(id.0)
+    .bind::<Nullable<Timestamp>, _>(after.map(|after| after.naive_utc()))
+    .bind::<BigInt, _>(limit as i64 + 1)
+    .load::<Bucket>(conn))?;
 
-    crate::users::unfreeze_user_in_transaction(conn, id)?;
+    let next = if hours.len() > limit as usize {
+        hours.truncate(limit as usize);
+        hours.last().map(|h| DateTime::from_utc(h.bucket, Utc))
+    } else {
+        None
+    };
+
+    Ok(RevertPage {
+        session,
+        statistics : hours
+            .into_iter()
+            .map(|h| (h.bucket.timestamp(), h.count as u32))
+            .collect(),
+        next,
+    })
+}
+
+#[instrument(level = "debug", skip(conn, token))]
+pub fn get_revert_page(
+    conn : &HubConnection,
+    id : MaybeUserId,
+    token : &Token,
+    session : &RevertSessionId,
+    after : Option<DateTime<Utc>>,
+    limit : u32,
+    lease : Duration,
+) -> Result<RevertPage> {
+    conn.transaction(|| {
+        let id = id.validate(conn)?;
+
+        get_revert_page_in_transaction(
+            conn, id, token, session, after, limit, lease,
+        )
+    })
+}
+
+// Delete the statistics of a user, and unfreeze them. Needs to be called
+// from within a transaction.
+#[instrument(level = "debug", skip(conn, token))]
+pub fn terminate_revert_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+    token : &Token,
+    session : &RevertSessionId,
+) -> Result<()> {
+    crate::reverts::validate_session_in_transaction(conn, id, token, session)?;
+
+    run!(conn, conn => diesel::delete(stats::table.filter(stats::user_id.eq(id.0)))
+        .execute(conn))?;
+
+    crate::reverts::close_session_in_transaction(conn, session)?;
 
     Ok(())
 }
 
-pub fn terminate_revert<Conn>(conn : &Conn, id : MaybeUserId) -> Result<()>
-where
-    Conn : Connection<Backend = Pg>,
-{
+#[instrument(level = "debug", skip(conn, token))]
+pub fn terminate_revert(
+    conn : &HubConnection,
+    id : MaybeUserId,
+    token : &Token,
+    session : &RevertSessionId,
+) -> Result<()> {
+    conn.transaction(|| {
+        let id = id.validate(conn)?;
+
+        terminate_revert_in_transaction(conn, id, token, session)
+    })
+}
+
+// Unfreeze a user without deleting their statistics. Needs to be called from
+// within a transaction.
+#[instrument(level = "debug", skip(conn, token))]
+pub fn cancel_revert_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+    token : &Token,
+    session : &RevertSessionId,
+) -> Result<()> {
+    crate::reverts::validate_session_in_transaction(conn, id, token, session)?;
+    crate::reverts::close_session_in_transaction(conn, session)?;
+
+    Ok(())
+}
+
+#[instrument(level = "debug", skip(conn, token))]
+pub fn cancel_revert(
+    conn : &HubConnection,
+    id : MaybeUserId,
+    token : &Token,
+    session : &RevertSessionId,
+) -> Result<()> {
     conn.transaction(|| {
         let id = id.validate(conn)?;
-        terminate_revert_in_transaction(conn, id)
+
+        cancel_revert_in_transaction(conn, id, token, session)
     })
 }
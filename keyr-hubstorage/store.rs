@@ -0,0 +1,487 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+
+use keyr_types::{
+    Granularity, KeystrokesSeries, KeystrokesStats, Leaderboard, Period,
+    Summary, UserExport,
+};
+
+use crate::connection::HubPool;
+use crate::error::{KeyrHubstorageError, Result};
+use crate::machines::{self, MachineId};
+use crate::retention::{self, CompactionReport, Retention};
+use crate::reverts::{RevertSession, RevertSessionId};
+use crate::stats::{self, RevertPage};
+use crate::time::Calendar;
+use crate::users::{self, MaybeUserId, Token};
+
+// The usage of the pool of connections of a store, if it has one
+pub struct PoolState {
+    pub connections : u32,
+    pub idle_connections : u32,
+    pub max_connections : u32,
+}
+
+// The operations the hub needs from its storage. Every operation is atomic,
+// and the users it is given are validated beforehand, as with the functions
+// of `users' and `stats' which are not suffixed by `_in_transaction'. Days,
+// weeks and months are the ones of the calendar of each user.
+pub trait HubStore: Send + Sync + 'static {
+    fn create_user(&self, name : String) -> Result<MaybeUserId>;
+
+    fn create_machine(
+        &self,
+        user : MaybeUserId,
+        name : String,
+    ) -> Result<MachineId>;
+
+    fn generate_token(
+        &self,
+        user : MaybeUserId,
+        machine : MachineId,
+    ) -> Result<Token>;
+
+    fn set_visible(&self, user : MaybeUserId, visible : bool) -> Result<()>;
+
+    fn get_calendar(&self, user : MaybeUserId) -> Result<Calendar>;
+
+    fn set_calendar(
+        &self,
+        user : MaybeUserId,
+        calendar : Calendar,
+    ) -> Result<()>;
+
+    fn identify_user_by_token(&self, token : &Token) -> Result<MaybeUserId>;
+
+    fn identify_machine_by_token(
+        &self,
+        token : &Token,
+    ) -> Result<(MaybeUserId, MachineId)>;
+
+    fn find_user_by_name(&self, name : &str) -> Result<MaybeUserId>;
+
+    fn find_machine_by_name(
+        &self,
+        user : MaybeUserId,
+        name : &str,
+    ) -> Result<MachineId>;
+
+    fn is_visible(&self, user : MaybeUserId) -> Result<bool>;
+
+    fn commit(
+        &self,
+        user : MaybeUserId,
+        machine : MachineId,
+        now : DateTime<Utc>,
+        sa : &KeystrokesStats,
+    ) -> Result<Summary>;
+
+    fn get_summary(
+        &self,
+        user : MaybeUserId,
+        now : DateTime<Utc>,
+    ) -> Result<Summary>;
+
+    fn get_count_since(
+        &self,
+        user : MaybeUserId,
+        since : Option<DateTime<Utc>>,
+    ) -> Result<u64>;
+
+    fn get_oldest_entry(
+        &self,
+        user : MaybeUserId,
+    ) -> Result<Option<DateTime<Utc>>>;
+
+    #[allow(clippy::too_many_arguments)]
+    fn get_keystrokes_series(
+        &self,
+        user : MaybeUserId,
+        machine : Option<MachineId>,
+        from : DateTime<Utc>,
+        to : DateTime<Utc>,
+        granularity : Granularity,
+        calendar : Calendar,
+    ) -> Result<KeystrokesSeries>;
+
+    fn get_hourly_distribution(
+        &self,
+        user : MaybeUserId,
+        tz : Tz,
+    ) -> Result<[u64; 24]>;
+
+    fn get_leaderboard(
+        &self,
+        period : Period,
+        now : DateTime<Utc>,
+        limit : u32,
+    ) -> Result<Leaderboard>;
+
+    fn initiate_revert(
+        &self,
+        user : MaybeUserId,
+        token : &Token,
+        lease : Duration,
+    ) -> Result<RevertSession>;
+
+    // The active session opened with `token', if any
+    fn find_revert_session(
+        &self,
+        user : MaybeUserId,
+        token : &Token,
+    ) -> Result<RevertSessionId>;
+
+    fn get_revert_page(
+        &self,
+        user : MaybeUserId,
+        token : &Token,
+        session : &RevertSessionId,
+        after : Option<DateTime<Utc>>,
+        limit : u32,
+        lease : Duration,
+    ) -> Result<RevertPage>;
+
+    fn terminate_revert(
+        &self,
+        user : MaybeUserId,
+        token : &Token,
+        session : &RevertSessionId,
+    ) -> Result<()>;
+
+    fn cancel_revert(
+        &self,
+        user : MaybeUserId,
+        token : &Token,
+        session : &RevertSessionId,
+    ) -> Result<()>;
+
+    fn export_user(&self, user : MaybeUserId) -> Result<UserExport>;
+
+    // Erase a user and everything they own, even if they are being reverted
+    fn delete_user(&self, user : MaybeUserId) -> Result<()>;
+
+    // Apply the retention policy of every user, as of `now'. The users are
+    // compacted one after the other, each one atomically.
+    fn compact(
+        &self,
+        retention : &Retention,
+        now : DateTime<Utc>,
+    ) -> Result<CompactionReport>;
+
+    // The migrations which have not been applied to the store yet. A store
+    // which needs to reach a database gives up after `timeout'.
+    fn pending_migrations(
+        &self,
+        timeout : std::time::Duration,
+    ) -> Result<Vec<String>>;
+
+    fn pool_state(&self) -> Option<PoolState>;
+}
+
+// A store backed by a PostgreSQL or SQLite database
+pub struct DieselStore {
+    pool : HubPool,
+}
+
+impl DieselStore {
+    pub fn new(pool : HubPool) -> DieselStore {
+        DieselStore { pool }
+    }
+}
+
+impl HubStore for DieselStore {
+    fn create_user(&self, name : String) -> Result<MaybeUserId> {
+        users::create_user(&*self.pool.get()?, name)
+    }
+
+    fn create_machine(
+        &self,
+        user : MaybeUserId,
+        name : String,
+    ) -> Result<MachineId> {
+        machines::create_machine(&*self.pool.get()?, user, name)
+    }
+
+    fn generate_token(
+        &self,
+        user : MaybeUserId,
+        machine : MachineId,
+    ) -> Result<Token> {
+        users::generate_token(&*self.pool.get()?, user, machine)
+    }
+
+    fn set_visible(&self, user : MaybeUserId, visible : bool) -> Result<()> {
+        users::set_visible(&*self.pool.get()?, user, visible)
+    }
+
+    fn get_calendar(&self, user : MaybeUserId) -> Result<Calendar> {
+        users::get_calendar(&*self.pool.get()?, user)
+    }
+
+    fn set_calendar(
+        &self,
+        user : MaybeUserId,
+        calendar : Calendar,
+    ) -> Result<()> {
+        users::set_calendar(&*self.pool.get()?, user, calendar)
+    }
+
+    fn identify_user_by_token(&self, token : &Token) -> Result<MaybeUserId> {
+        users::identify_user_by_token(&*self.pool.get()?, token)
+    }
+
+    fn identify_machine_by_token(
+        &self,
+        token : &Token,
+    ) -> Result<(MaybeUserId, MachineId)> {
+        machines::identify_machine_by_token(&*self.pool.get()?, token)
+    }
+
+    fn find_user_by_name(&self, name : &str) -> Result<MaybeUserId> {
+        let conn = self.pool.get()?;
+
+        conn.transaction(|| {
+            users::find_by_name_in_transaction(&conn, name.to_owned())
+                .map(|id| MaybeUserId(id.0))
+        })
+    }
+
+    fn find_machine_by_name(
+        &self,
+        user : MaybeUserId,
+        name : &str,
+    ) -> Result<MachineId> {
+        let conn = self.pool.get()?;
+
+        conn.transaction(|| {
+            let id = user.validate(&conn)?;
+
+            machines::find_by_name_in_transaction(&conn, id, name.to_owned())
+        })
+    }
+
+    fn is_visible(&self, user : MaybeUserId) -> Result<bool> {
+        users::is_visible(&*self.pool.get()?, user)
+    }
+
+    fn commit(
+        &self,
+        user : MaybeUserId,
+        machine : MachineId,
+        now : DateTime<Utc>,
+        sa : &KeystrokesStats,
+    ) -> Result<Summary> {
+        stats::commit(&*self.pool.get()?, user, machine, now, sa)
+    }
+
+    fn get_summary(
+        &self,
+        user : MaybeUserId,
+        now : DateTime<Utc>,
+    ) -> Result<Summary> {
+        stats::get_summary(&*self.pool.get()?, user, now)
+    }
+
+    fn get_count_since(
+        &self,
+        user : MaybeUserId,
+        since : Option<DateTime<Utc>>,
+    ) -> Result<u64> {
+        let conn = self.pool.get()?;
+
+        conn.transaction(|| {
+            let id = user.validate(&conn)?;
+
+            stats::get_count_since_in_transaction(&conn, id, since)
+        })
+    }
+
+    fn get_oldest_entry(
+        &self,
+        user : MaybeUserId,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let conn = self.pool.get()?;
+
+        conn.transaction(|| {
+            let id = user.validate(&conn)?;
+
+            Ok(stats::get_oldest_entry_in_transaction(&conn, id)?
+                .map(|t| DateTime::from_utc(t, Utc)))
+        })
+    }
+
+    fn get_keystrokes_series(
+        &self,
+        user : MaybeUserId,
+        machine : Option<MachineId>,
+        from : DateTime<Utc>,
+        to : DateTime<Utc>,
+        granularity : Granularity,
+        calendar : Calendar,
+    ) -> Result<KeystrokesSeries> {
+        let conn = self.pool.get()?;
+
+        conn.transaction(|| {
+            let id = user.validate(&conn)?;
+
+            stats::get_keystrokes_series_in_transaction(
+                &conn,
+                id,
+                machine,
+                from,
+                to,
+                granularity,
+                calendar,
+            )
+        })
+    }
+
+    fn get_hourly_distribution(
+        &self,
+        user : MaybeUserId,
+        tz : Tz,
+    ) -> Result<[u64; 24]> {
+        let conn = self.pool.get()?;
+
+        conn.transaction(|| {
+            let id = user.validate(&conn)?;
+
+            stats::get_hourly_distribution_in_transaction(&conn, id, tz)
+        })
+    }
+
+    fn get_leaderboard(
+        &self,
+        period : Period,
+        now : DateTime<Utc>,
+        limit : u32,
+    ) -> Result<Leaderboard> {
+        let conn = self.pool.get()?;
+
+        conn.transaction(|| {
+            stats::get_leaderboard_in_transaction(&conn, period, now, limit)
+        })
+    }
+
+    fn initiate_revert(
+        &self,
+        user : MaybeUserId,
+        token : &Token,
+        lease : Duration,
+    ) -> Result<RevertSession> {
+        stats::initiate_revert(&*self.pool.get()?, user, token, lease)
+    }
+
+    fn find_revert_session(
+        &self,
+        user : MaybeUserId,
+        token : &Token,
+    ) -> Result<RevertSessionId> {
+        stats::find_revert_session(&*self.pool.get()?, user, token)
+    }
+
+    fn get_revert_page(
+        &self,
+        user : MaybeUserId,
+        token : &Token,
+        session : &RevertSessionId,
+        after : Option<DateTime<Utc>>,
+        limit : u32,
+        lease : Duration,
+    ) -> Result<RevertPage> {
+        stats::get_revert_page(
+            &*self.pool.get()?,
+            user,
+            token,
+            session,
+            after,
+            limit,
+            lease,
+        )
+    }
+
+    fn terminate_revert(
+        &self,
+        user : MaybeUserId,
+        token : &Token,
+        session : &RevertSessionId,
+    ) -> Result<()> {
+        stats::terminate_revert(&*self.pool.get()?, user, token, session)
+    }
+
+    fn cancel_revert(
+        &self,
+        user : MaybeUserId,
+        token : &Token,
+        session : &RevertSessionId,
+    ) -> Result<()> {
+        stats::cancel_revert(&*self.pool.get()?, user, token, session)
+    }
+
+    fn export_user(&self, user : MaybeUserId) -> Result<UserExport> {
+        users::export_user(&*self.pool.get()?, user)
+    }
+
+    fn delete_user(&self, user : MaybeUserId) -> Result<()> {
+        users::delete_user(&*self.pool.get()?, user)
+    }
+
+    fn compact(
+        &self,
+        retention : &Retention,
+        now : DateTime<Utc>,
+    ) -> Result<CompactionReport> {
+        let conn = self.pool.get()?;
+        let users = conn.transaction(|| users::list_in_transaction(&conn))?;
+        let mut report = CompactionReport::default();
+
+        for (id, name) in users {
+            let policy = retention.policy(&name);
+
+            match retention::compact(&conn, MaybeUserId(id.0), policy, now) {
+                Ok(r) => report += r,
+                // The user has been deleted in the meantime
+                Err(KeyrHubstorageError::UnknownUser) => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn pending_migrations(
+        &self,
+        timeout : std::time::Duration,
+    ) -> Result<Vec<String>> {
+        crate::migrations::pending(&*self.pool.get_timeout(timeout)?)
+    }
+
+    fn pool_state(&self) -> Option<PoolState> {
+        let state = self.pool.state();
+
+        Some(PoolState {
+            connections : state.connections,
+            idle_connections : state.idle_connections,
+            max_connections : self.pool.max_size(),
+        })
+    }
+}
@@ -0,0 +1,154 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// The tests are run against every backend. The PostgreSQL database is read
+// from the `DATABASE_URL' environment variable, and the PostgreSQL tests are
+// skipped when it is not set. The SQLite tests use an in-memory database.
+// Every test runs inside a transaction which is never committed, so the
+// databases are left untouched.
+
+// Not every test uses every fixture
+#![allow(dead_code)]
+
+use chrono::{DateTime, TimeZone, Utc};
+use uuid::Uuid;
+
+use keyr_hubstorage as khs;
+use khs::connection::HubConnection;
+use khs::machines::MachineId;
+use khs::users::{Token, UserId};
+
+pub enum Backend {
+    Postgres,
+    Sqlite,
+}
+
+pub fn connect(backend : Backend) -> Option<HubConnection> {
+    let url = match backend {
+        Backend::Postgres => match std::env::var("DATABASE_URL") {
+            Ok(url) => url,
+            Err(_) => {
+                eprintln!("DATABASE_URL is not set, skipping");
+                return None;
+            }
+        },
+        Backend::Sqlite => "sqlite://:memory:".to_owned(),
+    };
+
+    let conn = HubConnection::establish(&url).unwrap();
+
+    conn.begin_test_transaction().unwrap();
+    khs::migrations::run(&conn).unwrap();
+
+    Some(conn)
+}
+
+// A user, one of their machines, and the token of this machine
+pub struct Agent {
+    pub user : UserId,
+    pub machine : MachineId,
+    pub token : Token,
+}
+
+// Create a user whose name starts with `name', with a first machine called
+// `default'
+pub fn create_user(conn : &HubConnection, name : &str) -> Agent {
+    create_named_user(conn, name).1
+}
+
+// Same as `create_user', but also return the name given to the user
+pub fn create_named_user(
+    conn : &HubConnection,
+    name : &str,
+) -> (String, Agent) {
+    let name = format!("{}-{}", name, Uuid::new_v4().to_simple());
+    let user =
+        khs::users::create_user_in_transaction(conn, name.clone()).unwrap();
+
+    (name, create_machine(conn, user, "default"))
+}
+
+pub fn create_machine(
+    conn : &HubConnection,
+    user : UserId,
+    name : &str,
+) -> Agent {
+    let machine =
+        khs::machines::create_machine_in_transaction(conn, user, name.into())
+            .unwrap();
+    let token =
+        khs::users::generate_token_in_transaction(conn, user, machine).unwrap();
+
+    Agent {
+        user,
+        machine,
+        token,
+    }
+}
+
+pub fn today() -> DateTime<Utc> {
+    Utc.ymd(2020, 9, 3).and_hms(0, 0, 0)
+}
+
+pub fn upsert(
+    conn : &HubConnection,
+    agent : &Agent,
+    date : DateTime<Utc>,
+    count : i32,
+) {
+    khs::stats::upsert_keystrokes_count_in_transaction(
+        conn,
+        agent.user,
+        agent.machine,
+        &date,
+        count,
+    )
+    .unwrap();
+}
+
+// Declare a test per backend for each function taking a connection.
+macro_rules! backend_tests {
+    ($($name:ident),* $(,)?) => {
+        mod postgres {
+            use crate::common::{connect, Backend};
+
+            $(
+                #[test]
+                fn $name() {
+                    if let Some(conn) = connect(Backend::Postgres) {
+                        super::$name(&conn);
+                    }
+                }
+            )*
+        }
+
+        mod sqlite {
+            use crate::common::{connect, Backend};
+
+            $(
+                #[test]
+                fn $name() {
+                    if let Some(conn) = connect(Backend::Sqlite) {
+                        super::$name(&conn);
+                    }
+                }
+            )*
+        }
+    };
+}
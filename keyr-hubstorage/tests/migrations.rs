@@ -0,0 +1,75 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#[macro_use]
+mod common;
+
+use std::path::Path;
+
+use keyr_hubstorage as khs;
+use khs::connection::HubConnection;
+
+// The names of the migrations found in `migrations/<backend>'
+fn migrations_in(backend : &str) -> Vec<String> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("migrations")
+        .join(backend);
+
+    let mut names = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+
+    names.sort();
+    names
+}
+
+#[test]
+fn every_migration_is_listed() {
+    assert_eq!(
+        khs::migrations::POSTGRES_MIGRATIONS,
+        &migrations_in("postgres")[..]
+    );
+    assert_eq!(
+        khs::migrations::SQLITE_MIGRATIONS,
+        &migrations_in("sqlite")[..]
+    );
+}
+
+fn nothing_is_pending_once_migrated(conn : &HubConnection) {
+    assert!(khs::migrations::pending(conn).unwrap().is_empty());
+}
+
+fn forgotten_migrations_are_pending(conn : &HubConnection) {
+    conn.batch_execute(
+        "DELETE FROM __diesel_schema_migrations \
+         WHERE version = '20200908090000'",
+    )
+    .unwrap();
+
+    assert_eq!(
+        khs::migrations::pending(conn).unwrap(),
+        vec!["2020-09-08-090000_user_calendars".to_owned()]
+    );
+}
+
+backend_tests!(
+    nothing_is_pending_once_migrated,
+    forgotten_migrations_are_pending,
+);
@@ -0,0 +1,197 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#[macro_use]
+mod common;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+
+use std::collections::HashMap;
+
+use keyr_hubstorage as khs;
+use khs::connection::HubConnection;
+use khs::retention::{CompactionReport, RetentionPolicy};
+use khs::time::Calendar;
+
+use common::{create_machine, create_user, today, upsert, Agent};
+
+fn now() -> DateTime<Utc> {
+    today() + Duration::hours(12)
+}
+
+fn compact(
+    conn : &HubConnection,
+    agent : &Agent,
+    hourly_days : Option<u32>,
+    max_age_days : Option<u32>,
+) -> CompactionReport {
+    khs::retention::compact_in_transaction(
+        conn,
+        agent.user,
+        RetentionPolicy {
+            hourly_days,
+            max_age_days,
+        },
+        now(),
+    )
+    .unwrap()
+}
+
+fn statistics(conn : &HubConnection, agent : &Agent) -> HashMap<i64, u32> {
+    khs::stats::get_keystrokes_stats_in_transaction(conn, agent.user).unwrap()
+}
+
+fn old_hours_are_rolled_into_days(conn : &HubConnection) {
+    let alice = create_user(conn, "alice");
+    let laptop = create_machine(conn, alice.user, "laptop");
+
+    let five_days_ago = today() - Duration::days(5);
+    let four_days_ago = today() - Duration::days(4);
+    let yesterday = today() - Duration::days(1);
+
+    upsert(conn, &alice, five_days_ago + Duration::hours(3), 10);
+    upsert(conn, &alice, five_days_ago + Duration::hours(15), 20);
+    upsert(conn, &laptop, five_days_ago + Duration::hours(3), 100);
+    upsert(conn, &alice, four_days_ago + Duration::hours(1), 5);
+    upsert(conn, &alice, yesterday + Duration::hours(10), 7);
+    upsert(conn, &alice, today() + Duration::hours(2), 1);
+
+    let report = compact(conn, &alice, Some(2), None);
+    assert_eq!(
+        report,
+        CompactionReport {
+            rolled_up : 4,
+            deleted : 0,
+            skipped : 0,
+        }
+    );
+
+    let mut expected = HashMap::new();
+    expected.insert(five_days_ago.timestamp(), 130);
+    expected.insert(four_days_ago.timestamp(), 5);
+    expected.insert((yesterday + Duration::hours(10)).timestamp(), 7);
+    expected.insert((today() + Duration::hours(2)).timestamp(), 1);
+    assert_eq!(statistics(conn, &alice), expected);
+
+    // The days which have been compacted are left as they are
+    let report = compact(conn, &alice, Some(2), None);
+    assert_eq!(report, CompactionReport::default());
+    assert_eq!(statistics(conn, &alice), expected);
+}
+
+fn days_follow_the_calendar_of_the_user(conn : &HubConnection) {
+    let alice = create_user(conn, "alice");
+
+    // Days begin at 18:30 UTC in Kolkata, so the daily statistics are saved
+    // at 19:00 UTC
+    khs::users::set_calendar_in_transaction(
+        conn,
+        alice.user,
+        Calendar {
+            tz : chrono_tz::Asia::Kolkata,
+            day_start : 0,
+        },
+    )
+    .unwrap();
+
+    let day = Utc.ymd(2020, 8, 28).and_hms(19, 0, 0);
+
+    upsert(conn, &alice, day, 1);
+    upsert(conn, &alice, day + Duration::hours(15), 2);
+    upsert(conn, &alice, day + Duration::hours(23), 4);
+    upsert(conn, &alice, day + Duration::hours(24), 8);
+
+    let report = compact(conn, &alice, Some(2), None);
+    assert_eq!(report.rolled_up, 3);
+
+    let mut expected = HashMap::new();
+    expected.insert(day.timestamp(), 7);
+    expected.insert((day + Duration::hours(24)).timestamp(), 8);
+    assert_eq!(statistics(conn, &alice), expected);
+
+    let report = compact(conn, &alice, Some(2), None);
+    assert_eq!(report, CompactionReport::default());
+}
+
+fn old_statistics_are_deleted(conn : &HubConnection) {
+    let alice = create_user(conn, "alice");
+    let bob = create_user(conn, "bob");
+
+    let three_days_ago = today() - Duration::days(3);
+
+    upsert(conn, &alice, three_days_ago - Duration::hours(1), 10);
+    upsert(conn, &alice, three_days_ago, 20);
+    upsert(conn, &bob, three_days_ago - Duration::hours(1), 30);
+
+    let report = compact(conn, &alice, None, Some(3));
+    assert_eq!(
+        report,
+        CompactionReport {
+            rolled_up : 0,
+            deleted : 1,
+            skipped : 0,
+        }
+    );
+
+    let mut expected = HashMap::new();
+    expected.insert(three_days_ago.timestamp(), 20);
+    assert_eq!(statistics(conn, &alice), expected);
+
+    // The statistics of the other users are left untouched
+    assert_eq!(statistics(conn, &bob).len(), 1);
+}
+
+fn reverting_users_are_skipped(conn : &HubConnection) {
+    let alice = create_user(conn, "alice");
+    let token = khs::users::generate_token_in_transaction(
+        conn,
+        alice.user,
+        alice.machine,
+    )
+    .unwrap();
+
+    upsert(conn, &alice, today() - Duration::days(10), 10);
+    upsert(conn, &alice, today() - Duration::days(5), 20);
+
+    khs::stats::initiate_revert_in_transaction(
+        conn,
+        alice.user,
+        &token,
+        Duration::minutes(15),
+    )
+    .unwrap();
+
+    let report = compact(conn, &alice, Some(2), Some(7));
+    assert_eq!(
+        report,
+        CompactionReport {
+            rolled_up : 0,
+            deleted : 0,
+            skipped : 1,
+        }
+    );
+    assert_eq!(statistics(conn, &alice).len(), 2);
+}
+
+backend_tests!(
+    old_hours_are_rolled_into_days,
+    days_follow_the_calendar_of_the_user,
+    old_statistics_are_deleted,
+    reverting_users_are_skipped,
+);
@@ -0,0 +1,235 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#[macro_use]
+mod common;
+
+use chrono::Duration;
+
+use keyr_hubstorage as khs;
+use khs::connection::HubConnection;
+use khs::error::KeyrHubstorageError;
+use khs::reverts::RevertSessionId;
+
+use common::{create_machine, create_user, today, upsert, Agent};
+
+// Unlike `upsert', let the test check whether the keystrokes are accepted
+fn try_upsert(
+    conn : &HubConnection,
+    agent : &Agent,
+    count : i32,
+) -> khs::error::Result<()> {
+    khs::stats::upsert_keystrokes_count_in_transaction(
+        conn,
+        agent.user,
+        agent.machine,
+        &today(),
+        count,
+    )
+}
+
+fn initiate(
+    conn : &HubConnection,
+    agent : &Agent,
+    lease : Duration,
+) -> khs::error::Result<RevertSessionId> {
+    khs::stats::initiate_revert_in_transaction(
+        conn,
+        agent.user,
+        &agent.token,
+        lease,
+    )
+    .map(|session| session.id)
+}
+
+fn revert_deletes_the_statistics(conn : &HubConnection) {
+    let alice = create_user(conn, "alice");
+
+    upsert(conn, &alice, today(), 10);
+
+    let session = initiate(conn, &alice, Duration::minutes(15)).unwrap();
+    let page = khs::stats::get_revert_page_in_transaction(
+        conn,
+        alice.user,
+        &alice.token,
+        &session,
+        None,
+        100,
+        Duration::minutes(15),
+    )
+    .unwrap();
+
+    assert_eq!(page.statistics.get(&today().timestamp()), Some(&10));
+    assert!(page.next.is_none());
+    assert!(matches!(
+        try_upsert(conn, &alice, 10),
+        Err(KeyrHubstorageError::FrozenUser)
+    ));
+
+    khs::stats::terminate_revert_in_transaction(
+        conn,
+        alice.user,
+        &alice.token,
+        &session,
+    )
+    .unwrap();
+
+    let stats =
+        khs::stats::get_keystrokes_stats_in_transaction(conn, alice.user)
+            .unwrap();
+
+    assert!(stats.is_empty());
+    upsert(conn, &alice, today(), 10);
+}
+
+fn expired_sessions_are_released(conn : &HubConnection) {
+    let alice = create_user(conn, "alice");
+    let session = initiate(conn, &alice, Duration::zero()).unwrap();
+
+    upsert(conn, &alice, today(), 10);
+
+    assert!(matches!(
+        khs::stats::terminate_revert_in_transaction(
+            conn,
+            alice.user,
+            &alice.token,
+            &session,
+        ),
+        Err(KeyrHubstorageError::UnknownRevertSession)
+    ));
+}
+
+fn sessions_are_bound_to_their_token(conn : &HubConnection) {
+    let laptop = create_user(conn, "alice");
+    let desktop = create_machine(conn, laptop.user, "desktop");
+
+    let session = initiate(conn, &laptop, Duration::minutes(15)).unwrap();
+
+    assert!(matches!(
+        initiate(conn, &desktop, Duration::minutes(15)),
+        Err(KeyrHubstorageError::FrozenUser)
+    ));
+    assert_eq!(
+        khs::reverts::find_session_in_transaction(
+            conn,
+            laptop.user,
+            &laptop.token,
+        )
+        .unwrap(),
+        session
+    );
+    assert!(matches!(
+        khs::reverts::find_session_in_transaction(
+            conn,
+            desktop.user,
+            &desktop.token,
+        ),
+        Err(KeyrHubstorageError::UnknownRevertSession)
+    ));
+    assert!(matches!(
+        khs::stats::cancel_revert_in_transaction(
+            conn,
+            desktop.user,
+            &desktop.token,
+            &session,
+        ),
+        Err(KeyrHubstorageError::UnknownRevertSession)
+    ));
+    assert!(matches!(
+        khs::stats::cancel_revert_in_transaction(
+            conn,
+            laptop.user,
+            &laptop.token,
+            &RevertSessionId("unknown".to_owned()),
+        ),
+        Err(KeyrHubstorageError::UnknownRevertSession)
+    ));
+
+    // Initiating a revert again from the same agent replaces its session
+    let session = initiate(conn, &laptop, Duration::minutes(15)).unwrap();
+
+    khs::stats::cancel_revert_in_transaction(
+        conn,
+        laptop.user,
+        &laptop.token,
+        &session,
+    )
+    .unwrap();
+
+    upsert(conn, &desktop, today(), 10);
+}
+
+fn statistics_are_paginated(conn : &HubConnection) {
+    let laptop = create_user(conn, "alice");
+    let desktop = create_machine(conn, laptop.user, "desktop");
+
+    for hour in 0..5 {
+        upsert(conn, &laptop, today() + Duration::hours(hour), 10);
+    }
+    // Both machines count for the same hour
+    upsert(conn, &desktop, today() + Duration::hours(2), 5);
+
+    let session = initiate(conn, &laptop, Duration::minutes(15)).unwrap();
+    let page = |after| {
+        khs::stats::get_revert_page_in_transaction(
+            conn,
+            laptop.user,
+            &laptop.token,
+            &session,
+            after,
+            2,
+            Duration::minutes(15),
+        )
+        .unwrap()
+    };
+
+    let mut cursor = None;
+    let mut pages = vec![];
+
+    loop {
+        let p = page(cursor);
+
+        pages.push(p.statistics);
+
+        match p.next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+
+    assert_eq!(pages.len(), 3);
+    assert_eq!(pages[0].len(), 2);
+    assert_eq!(pages[1].len(), 2);
+    assert_eq!(pages[2].len(), 1);
+    assert_eq!(
+        pages[1].get(&(today() + Duration::hours(2)).timestamp()),
+        Some(&15)
+    );
+    assert_eq!(
+        pages[2].get(&(today() + Duration::hours(4)).timestamp()),
+        Some(&10)
+    );
+}
+
+backend_tests!(
+    revert_deletes_the_statistics,
+    expired_sessions_are_released,
+    sessions_are_bound_to_their_token,
+    statistics_are_paginated,
+);
@@ -0,0 +1,164 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#[macro_use]
+mod common;
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use keyr_hubstorage as khs;
+use keyr_types::Granularity;
+use khs::connection::HubConnection;
+use khs::machines::MachineId;
+use khs::time::Calendar;
+use khs::users::UserId;
+
+use common::{create_machine, create_user, upsert};
+
+// Days begin at 4am in Paris, where the clocks go back from 3am to 2am on
+// 2020-10-25, in the middle of the night
+fn calendar() -> Calendar {
+    Calendar {
+        tz : chrono_tz::Europe::Paris,
+        day_start : 4,
+    }
+}
+
+fn utc(day : u32, hour : u32) -> DateTime<Utc> {
+    Utc.ymd(2020, 10, day).and_hms(hour, 0, 0)
+}
+
+fn series(
+    conn : &HubConnection,
+    user : UserId,
+    machine : Option<MachineId>,
+    from : DateTime<Utc>,
+    to : DateTime<Utc>,
+    granularity : Granularity,
+) -> Vec<(i64, u64)> {
+    khs::stats::get_keystrokes_series_in_transaction(
+        conn,
+        user,
+        machine,
+        from,
+        to,
+        granularity,
+        calendar(),
+    )
+    .unwrap()
+    .into_iter()
+    .map(|point| (point.timestamp, point.count))
+    .collect()
+}
+
+fn series_follow_the_changes_of_offset(conn : &HubConnection) {
+    let alice = create_user(conn, "alice");
+    let laptop = create_machine(conn, alice.user, "laptop");
+    let bob = create_user(conn, "bob");
+
+    // 3am CEST, still on 2020-10-23
+    upsert(conn, &alice, utc(24, 1), 1);
+    // 4am CEST
+    upsert(conn, &alice, utc(24, 2), 2);
+    // 3am CET, still on 2020-10-24
+    upsert(conn, &alice, utc(25, 2), 4);
+    upsert(conn, &laptop, utc(25, 2), 8);
+    // 4am CET
+    upsert(conn, &alice, utc(25, 3), 16);
+    // Monday
+    upsert(conn, &laptop, utc(26, 10), 32);
+    upsert(conn, &bob, utc(25, 3), 64);
+
+    assert_eq!(
+        series(
+            conn,
+            alice.user,
+            None,
+            utc(23, 2),
+            utc(27, 3),
+            Granularity::Day
+        ),
+        vec![
+            (utc(23, 2).timestamp(), 1),
+            (utc(24, 2).timestamp(), 14),
+            (utc(25, 3).timestamp(), 16),
+            (utc(26, 3).timestamp(), 32),
+        ]
+    );
+
+    assert_eq!(
+        series(
+            conn,
+            alice.user,
+            Some(laptop.machine),
+            utc(23, 2),
+            utc(27, 3),
+            Granularity::Day
+        ),
+        vec![
+            (utc(23, 2).timestamp(), 0),
+            (utc(24, 2).timestamp(), 8),
+            (utc(25, 3).timestamp(), 0),
+            (utc(26, 3).timestamp(), 32),
+        ]
+    );
+
+    assert_eq!(
+        series(
+            conn,
+            alice.user,
+            None,
+            utc(19, 2),
+            Utc.ymd(2020, 11, 2).and_hms(3, 0, 0),
+            Granularity::Week
+        ),
+        vec![(utc(19, 2).timestamp(), 31), (utc(26, 3).timestamp(), 32)]
+    );
+
+    assert_eq!(
+        series(
+            conn,
+            alice.user,
+            None,
+            utc(1, 2),
+            Utc.ymd(2020, 11, 1).and_hms(3, 0, 0),
+            Granularity::Month
+        ),
+        vec![(utc(1, 2).timestamp(), 63)]
+    );
+
+    // Buckets are local hours, so 2am happens only once
+    assert_eq!(
+        series(
+            conn,
+            alice.user,
+            None,
+            utc(25, 0),
+            utc(25, 4),
+            Granularity::Hour
+        ),
+        vec![
+            (utc(25, 0).timestamp(), 0),
+            (utc(25, 2).timestamp(), 12),
+            (utc(25, 3).timestamp(), 16),
+        ]
+    );
+}
+
+backend_tests!(series_follow_the_changes_of_offset);
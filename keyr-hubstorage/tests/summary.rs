@@ -0,0 +1,232 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#[macro_use]
+mod common;
+
+use chrono::Duration;
+
+use std::collections::HashMap;
+
+use keyr_hubstorage as khs;
+use keyr_types::Period;
+use khs::connection::HubConnection;
+use khs::time::Calendar;
+use khs::users::MaybeUserId;
+
+use common::{create_machine, create_user, today, upsert};
+
+fn summary_is_scoped_to_the_user(conn : &HubConnection) {
+    let alice = create_user(conn, "alice");
+    let bob = create_user(conn, "bob");
+
+    upsert(conn, &alice, today() - Duration::days(2), 10);
+    upsert(conn, &alice, today() + Duration::hours(9), 20);
+    upsert(conn, &bob, today() - Duration::days(1), 300);
+    upsert(conn, &bob, today() + Duration::hours(10), 400);
+
+    let s = khs::stats::get_summary_in_transaction(conn, alice.user, today())
+        .unwrap();
+
+    assert_eq!(s.global_count, 30);
+    assert_eq!(s.today_count, 20);
+    assert_eq!(
+        s.oldest_timestamp,
+        (today() - Duration::days(2)).timestamp()
+    );
+    assert_eq!(s.today_timestamp, today().timestamp());
+
+    let s = khs::stats::get_summary_in_transaction(conn, bob.user, today())
+        .unwrap();
+
+    assert_eq!(s.global_count, 700);
+    assert_eq!(s.today_count, 400);
+    assert_eq!(
+        s.oldest_timestamp,
+        (today() - Duration::days(1)).timestamp()
+    );
+}
+
+fn summary_of_user_without_statistics(conn : &HubConnection) {
+    let alice = create_user(conn, "alice");
+    let carol = create_user(conn, "carol");
+
+    upsert(conn, &alice, today() + Duration::hours(1), 42);
+
+    let s = khs::stats::get_summary_in_transaction(conn, carol.user, today())
+        .unwrap();
+
+    assert_eq!(s.global_count, 0);
+    assert_eq!(s.today_count, 0);
+    assert_eq!(s.oldest_timestamp, today().timestamp());
+}
+
+fn commit_returns_the_summary_of_the_committer(conn : &HubConnection) {
+    let alice = create_user(conn, "alice");
+    let bob = create_user(conn, "bob");
+
+    upsert(conn, &bob, today() + Duration::hours(2), 1000);
+
+    let mut sa = HashMap::new();
+    sa.insert((today() - Duration::hours(1)).timestamp(), 5);
+    sa.insert((today() + Duration::hours(3)).timestamp(), 7);
+
+    let s = khs::stats::commit(
+        conn,
+        MaybeUserId(alice.user.0),
+        alice.machine,
+        today(),
+        &sa,
+    )
+    .unwrap();
+
+    assert_eq!(s.global_count, 12);
+    assert_eq!(s.today_count, 7);
+
+    let s = khs::stats::commit(
+        conn,
+        MaybeUserId(bob.user.0),
+        bob.machine,
+        today(),
+        &sa,
+    )
+    .unwrap();
+
+    assert_eq!(s.global_count, 1012);
+    assert_eq!(s.today_count, 1007);
+}
+
+fn summary_breaks_counts_down_by_machine(conn : &HubConnection) {
+    let alice = create_user(conn, "alice");
+    let laptop = create_machine(conn, alice.user, "laptop");
+    let desktop = create_machine(conn, alice.user, "desktop");
+    let bob = create_user(conn, "bob");
+
+    upsert(conn, &laptop, today() - Duration::days(1), 10);
+    upsert(conn, &laptop, today() + Duration::hours(1), 20);
+    upsert(conn, &desktop, today() + Duration::hours(1), 300);
+    upsert(conn, &bob, today() + Duration::hours(1), 4000);
+
+    let s = khs::stats::get_summary_in_transaction(conn, alice.user, today())
+        .unwrap();
+
+    assert_eq!(s.global_count, 330);
+    assert_eq!(s.today_count, 320);
+
+    let machines = s
+        .machines
+        .iter()
+        .map(|m| (m.name.as_str(), m.global_count, m.today_count))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        machines,
+        vec![("default", 0, 0), ("desktop", 300, 300), ("laptop", 30, 20)]
+    );
+}
+
+fn summary_follows_the_calendar_of_the_user(conn : &HubConnection) {
+    let alice = create_user(conn, "alice");
+    let calendar = Calendar {
+        tz : chrono_tz::America::New_York,
+        day_start : 4,
+    };
+
+    khs::users::set_calendar_in_transaction(conn, alice.user, calendar)
+        .unwrap();
+
+    // It is 08:00 in New York, and the day of Alice began at 04:00, that is
+    // 08:00 UTC
+    let now = today() + Duration::hours(12);
+
+    upsert(conn, &alice, today() + Duration::hours(7), 10);
+    upsert(conn, &alice, today() + Duration::hours(9), 20);
+
+    let s =
+        khs::stats::get_summary(conn, MaybeUserId(alice.user.0), now).unwrap();
+
+    assert_eq!(s.global_count, 30);
+    assert_eq!(s.today_count, 20);
+    assert_eq!(
+        s.today_timestamp,
+        (today() + Duration::hours(8)).timestamp()
+    );
+    assert_eq!(
+        khs::users::get_calendar_in_transaction(conn, alice.user).unwrap(),
+        calendar
+    );
+}
+
+fn leaderboard_follows_the_calendar_of_each_user(conn : &HubConnection) {
+    let alice = create_user(conn, "alice");
+    let bob = create_user(conn, "bob");
+
+    for agent in &[&alice, &bob] {
+        khs::users::set_visible_in_transaction(conn, agent.user, true).unwrap();
+    }
+
+    // The day of Alice began at 15:00 UTC the day before, the one of Bob at
+    // midnight UTC
+    khs::users::set_calendar_in_transaction(
+        conn,
+        alice.user,
+        Calendar {
+            tz : chrono_tz::Asia::Tokyo,
+            day_start : 0,
+        },
+    )
+    .unwrap();
+
+    upsert(conn, &alice, today() - Duration::hours(8), 10);
+    upsert(conn, &bob, today() - Duration::hours(8), 100);
+    upsert(conn, &bob, today() + Duration::hours(1), 5);
+
+    // The database can hold other visible users, hence the entries are
+    // looked up by user
+    let now = today() + Duration::hours(12);
+    let counts = |period| {
+        khs::stats::get_leaderboard_in_transaction(conn, period, now, u32::MAX)
+            .unwrap()
+            .into_iter()
+            .map(|e| {
+                let id = khs::users::find_by_name_in_transaction(conn, e.name)
+                    .unwrap();
+
+                (id.0, e.count)
+            })
+            .collect::<HashMap<_, _>>()
+    };
+
+    let day = counts(Period::Day);
+    assert_eq!(day.get(&alice.user.0), Some(&10));
+    assert_eq!(day.get(&bob.user.0), Some(&5));
+
+    let all = counts(Period::All);
+    assert_eq!(all.get(&alice.user.0), Some(&10));
+    assert_eq!(all.get(&bob.user.0), Some(&105));
+}
+
+backend_tests!(
+    summary_is_scoped_to_the_user,
+    summary_of_user_without_statistics,
+    commit_returns_the_summary_of_the_committer,
+    summary_breaks_counts_down_by_machine,
+    summary_follows_the_calendar_of_the_user,
+    leaderboard_follows_the_calendar_of_each_user,
+);
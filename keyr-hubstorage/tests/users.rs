@@ -0,0 +1,136 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#[macro_use]
+mod common;
+
+use chrono::Duration;
+
+use keyr_hubstorage as khs;
+use keyr_types::{MachineKeystrokes, UserCalendar};
+use khs::connection::HubConnection;
+use khs::error::KeyrHubstorageError;
+use khs::time::Calendar;
+
+use common::{create_machine, create_named_user, create_user, today, upsert};
+
+fn export_describes_the_user(conn : &HubConnection) {
+    let (name, alice) = create_named_user(conn, "alice");
+    let laptop = create_machine(conn, alice.user, "laptop");
+    let bob = create_user(conn, "bob");
+
+    khs::users::set_visible_in_transaction(conn, alice.user, true).unwrap();
+    khs::users::set_calendar_in_transaction(
+        conn,
+        alice.user,
+        Calendar {
+            tz : chrono_tz::Europe::Paris,
+            day_start : 4,
+        },
+    )
+    .unwrap();
+
+    upsert(conn, &alice, today() + Duration::hours(2), 10);
+    upsert(conn, &laptop, today() + Duration::hours(1), 20);
+    upsert(conn, &laptop, today() + Duration::hours(2), 30);
+    upsert(conn, &bob, today() + Duration::hours(1), 1000);
+
+    let export =
+        khs::users::export_user_in_transaction(conn, alice.user).unwrap();
+
+    assert_eq!(export.name, name);
+    assert!(export.visible);
+    assert_eq!(
+        export.calendar,
+        UserCalendar {
+            timezone : "Europe/Paris".to_owned(),
+            day_start : 4,
+        }
+    );
+    assert_eq!(export.machines, vec!["default", "laptop"]);
+
+    // The tokens themselves are not disclosed
+    assert_eq!(export.tokens.len(), 2);
+    assert_eq!(export.tokens[0].machine, "default");
+    assert!(alice.token.0.ends_with(&export.tokens[0].hint));
+    assert!(export.tokens[0].hint.len() < alice.token.0.len());
+    assert_eq!(export.tokens[1].machine, "laptop");
+
+    let stat = |hour : i64, machine : &str, count : u64| MachineKeystrokes {
+        timestamp : (today() + Duration::hours(hour)).timestamp(),
+        machine : machine.to_owned(),
+        count,
+    };
+
+    assert_eq!(
+        export.statistics,
+        vec![
+            stat(1, "laptop", 20),
+            stat(2, "default", 10),
+            stat(2, "laptop", 30),
+        ]
+    );
+}
+
+fn deleting_a_user_erases_everything(conn : &HubConnection) {
+    let (name, alice) = create_named_user(conn, "alice");
+    let bob = create_user(conn, "bob");
+
+    upsert(conn, &alice, today() + Duration::hours(1), 10);
+    upsert(conn, &bob, today() + Duration::hours(1), 20);
+
+    // Being reverted does not keep a user from leaving
+    khs::stats::initiate_revert_in_transaction(
+        conn,
+        alice.user,
+        &alice.token,
+        Duration::minutes(15),
+    )
+    .unwrap();
+
+    khs::users::delete_user_in_transaction(conn, alice.user).unwrap();
+
+    assert!(matches!(
+        khs::users::identify_user_by_token_in_transaction(conn, &alice.token),
+        Err(KeyrHubstorageError::InvalidToken)
+    ));
+    assert!(matches!(
+        khs::users::find_by_name_in_transaction(conn, name.clone()),
+        Err(KeyrHubstorageError::UnknownUser)
+    ));
+    assert!(
+        khs::stats::get_keystrokes_stats_in_transaction(conn, alice.user)
+            .unwrap()
+            .is_empty()
+    );
+
+    // The name is available again
+    khs::users::create_user_in_transaction(conn, name).unwrap();
+
+    // The other users are left untouched
+    assert_eq!(
+        khs::stats::get_count_since_in_transaction(conn, bob.user, None)
+            .unwrap(),
+        20
+    );
+    khs::users::identify_user_by_token_in_transaction(conn, &bob.token)
+        .unwrap();
+}
+
+backend_tests!(export_describes_the_user, deleting_a_user_erases_everything);
@@ -0,0 +1,210 @@
+/* keyr -- keep track of your keystrokes
+ * Copyright (c) 2020 Thomas Letan
+ *
+ * This file is part of keyr.
+ *
+ * keyr is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * keyr is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use chrono::{
+    DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, Offset, Timelike,
+    Utc,
+};
+use chrono_tz::Tz;
+
+pub use keyr_types::to_utc;
+use keyr_types::{Granularity, Period};
+
+// The name of the field to give to PostgreSQL `date_trunc' for a given
+// granularity.
+pub fn date_trunc_field(granularity : Granularity) -> &'static str {
+    match granularity {
+        Granularity::Hour => "hour",
+        Granularity::Day => "day",
+        Granularity::Week => "week",
+        Granularity::Month => "month",
+    }
+}
+
+// The SQLite expression truncating the local date `local' to the beginning
+// of its bucket, as `date_trunc' does with PostgreSQL (`weekday 0' moves to
+// the next Sunday, unless the date already is one).
+pub fn sqlite_truncate(granularity : Granularity) -> &'static str {
+    match granularity {
+        Granularity::Hour => "strftime('%Y-%m-%d %H:00:00', local)",
+        Granularity::Day => "datetime(local, 'start of day')",
+        Granularity::Week => {
+            "datetime(local, 'start of day', 'weekday 0', '-6 days')"
+        }
+        Granularity::Month => "datetime(local, 'start of month')",
+    }
+}
+
+// The offset of `tz' from UTC at `date', in seconds
+fn utc_offset(tz : Tz, date : DateTime<Utc>) -> i32 {
+    date.with_timezone(&tz).offset().fix().local_minus_utc()
+}
+
+// Split the range between `from' (included) and `to' (excluded) where the
+// offset of `tz' from UTC changes, and return each part along with its
+// offset in seconds. The statistics being hourly, the parts begin on whole
+// hours, and the offset is looked for day by day, which assumes it does not
+// change twice in a day.
+pub fn split_by_offset(
+    tz : Tz,
+    from : DateTime<Utc>,
+    to : DateTime<Utc>,
+) -> Vec<(DateTime<Utc>, DateTime<Utc>, i32)> {
+    let mut res = vec![];
+    let mut start = from;
+    let mut offset = utc_offset(tz, from);
+    let mut date = from;
+
+    while date < to {
+        let next = std::cmp::min(date + Duration::days(1), to);
+
+        if utc_offset(tz, next) != offset {
+            let mut hour = date.date().and_hms(date.hour(), 0, 0);
+
+            while hour <= date || utc_offset(tz, hour) == offset {
+                hour += Duration::hours(1);
+            }
+
+            let hour = std::cmp::min(hour, to);
+
+            res.push((start, hour, offset));
+            start = hour;
+            offset = utc_offset(tz, hour);
+        }
+
+        date = next;
+    }
+
+    if start < to {
+        res.push((start, to, offset));
+    }
+
+    res
+}
+
+// Truncate a local date to the beginning of its bucket. This mimics the
+// behavior of PostgreSQL `date_trunc', in particular weeks start on Monday.
+pub fn truncate(
+    date : NaiveDateTime,
+    granularity : Granularity,
+) -> NaiveDateTime {
+    let day = date.date();
+
+    match granularity {
+        Granularity::Hour => day.and_hms(date.time().hour(), 0, 0),
+        Granularity::Day => day.and_hms(0, 0, 0),
+        Granularity::Week => (day
+            - Duration::days(day.weekday().num_days_from_monday() as i64))
+        .and_hms(0, 0, 0),
+        Granularity::Month => {
+            NaiveDate::from_ymd(day.year(), day.month(), 1).and_hms(0, 0, 0)
+        }
+    }
+}
+
+// Compute the beginning of the bucket following the one starting at `date'.
+pub fn next(date : NaiveDateTime, granularity : Granularity) -> NaiveDateTime {
+    match granularity {
+        Granularity::Hour => date + Duration::hours(1),
+        Granularity::Day => date + Duration::days(1),
+        Granularity::Week => date + Duration::weeks(1),
+        Granularity::Month => {
+            let (year, month) = if date.month() == 12 {
+                (date.year() + 1, 1)
+            } else {
+                (date.year(), date.month() + 1)
+            };
+
+            NaiveDate::from_ymd(year, month, 1).and_hms(0, 0, 0)
+        }
+    }
+}
+
+pub fn to_local(tz : Tz, date : DateTime<Utc>) -> NaiveDateTime {
+    date.with_timezone(&tz).naive_local()
+}
+
+// How a user splits time: their days begin at `day_start' o'clock in the
+// timezone `tz', and their weeks and months begin with their first day.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Calendar {
+    pub tz : Tz,
+    pub day_start : u32,
+}
+
+impl Default for Calendar {
+    fn default() -> Calendar {
+        Calendar {
+            tz : Tz::UTC,
+            day_start : 0,
+        }
+    }
+}
+
+impl Calendar {
+    fn shift(&self) -> Duration {
+        Duration::hours(self.day_start as i64)
+    }
+
+    // Truncate a local date to the beginning of its bucket, taking the
+    // beginning of the days into account
+    pub fn truncate(
+        &self,
+        date : NaiveDateTime,
+        granularity : Granularity,
+    ) -> NaiveDateTime {
+        truncate(date - self.shift(), granularity) + self.shift()
+    }
+
+    // Compute the beginning of the bucket following the one starting at
+    // `date', which has been truncated with `Calendar::truncate'
+    pub fn next(
+        &self,
+        date : NaiveDateTime,
+        granularity : Granularity,
+    ) -> NaiveDateTime {
+        next(date - self.shift(), granularity) + self.shift()
+    }
+
+    // Compute the beginning of the period `now' belongs to. Returns `None'
+    // for `Period::All', which has no beginning.
+    pub fn period_start(
+        &self,
+        period : Period,
+        now : DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let granularity = match period {
+            Period::Day => Granularity::Day,
+            Period::Week => Granularity::Week,
+            Period::Month => Granularity::Month,
+            Period::All => return None,
+        };
+
+        Some(to_utc(
+            &self.tz,
+            self.truncate(to_local(self.tz, now), granularity),
+        ))
+    }
+
+    // Compute the beginning of the day `now' belongs to
+    pub fn today(&self, now : DateTime<Utc>) -> DateTime<Utc> {
+        // unwrap is valid since `Period::Day' has a beginning
+        self.period_start(Period::Day, now).unwrap()
+    }
+}
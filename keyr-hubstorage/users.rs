@@ -17,17 +17,26 @@
  * along with keyr.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use diesel::pg::Pg;
+use chrono::NaiveDateTime;
+use chrono_tz::Tz;
 use diesel::prelude::*;
+use tracing::instrument;
 use uuid::Uuid;
 
+use std::collections::HashMap;
+
+use keyr_types::{MachineKeystrokes, TokenMetadata, UserCalendar, UserExport};
+
+use crate::connection::HubConnection;
 use crate::error::{KeyrHubstorageError, Result};
-use crate::schema::{tokens, users};
+use crate::machines::MachineId;
+use crate::schema::{machines, revert_sessions, statistics, tokens, users};
+use crate::time::Calendar;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct UserId(pub i32);
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct MaybeUserId(pub i32);
 
 #[derive(Clone)]
@@ -41,15 +50,12 @@ impl Into<UserId> for i32 {
 
 impl MaybeUserId {
     // Needs to be run in a transaction. Ensure the user exists.
-    pub fn validate<Conn>(&self, conn : &Conn) -> Result<UserId>
-    where
-        Conn : Connection<Backend = Pg>,
-    {
-        let id = users::table
+    pub fn validate(&self, conn : &HubConnection) -> Result<UserId> {
+        let id = run!(conn, conn => users::table
             .select(users::id)
             .filter(users::id.eq(self.0))
             .get_result::<i32>(conn)
-            .optional()?;
+            .optional())?;
 
         id.map(|x| UserId(x))
             .ok_or(KeyrHubstorageError::UnknownUser)
@@ -58,10 +64,11 @@ impl MaybeUserId {
 
 // Create a new user with a given name. Check whether or not the name is
 // available before.
-pub fn create_user<Conn>(conn : &Conn, name : String) -> Result<MaybeUserId>
-where
-    Conn : Connection<Backend = Pg>,
-{
+#[instrument(level = "debug", skip(conn))]
+pub fn create_user(
+    conn : &HubConnection,
+    name : String,
+) -> Result<MaybeUserId> {
     conn.transaction(|| {
         create_user_in_transaction(conn, name).map(|x| MaybeUserId(x.0))
     })
@@ -69,23 +76,29 @@ where
 
 // Create a new user with a given name. Check whether or not the name is
 // available before. This needs to be called from within a transaction.
-pub fn create_user_in_transaction<Conn>(
-    conn : &Conn,
+#[instrument(level = "debug", skip(conn))]
+pub fn create_user_in_transaction(
+    conn : &HubConnection,
     name : String,
-) -> Result<UserId>
-where
-    Conn : Connection<Backend = Pg>,
-{
-    let prev = users::table
+) -> Result<UserId> {
+    let prev = run!(conn, conn => users::table
         .select(users::id)
         .filter(users::name.eq(&name))
-        .get_results::<i32>(conn)?;
+        .get_results::<i32>(conn))?;
 
     if prev.len() == 0 {
-        let id = diesel::insert_into(users::table)
-            .values(vec![users::name.eq(&name)])
-            .returning(users::id)
-            .get_result::<i32>(conn)?;
+        // SQLite does not support `RETURNING', so the id of the new user is
+        // fetched afterwards
+        let id = run!(conn, conn => {
+            diesel::insert_into(users::table)
+                .values(vec![users::name.eq(&name)])
+                .execute(conn)?;
+
+            users::table
+                .select(users::id)
+                .filter(users::name.eq(&name))
+                .get_result::<i32>(conn)
+        })?;
 
         Ok(UserId(id))
     } else {
@@ -93,51 +106,58 @@ where
     }
 }
 
-// Generate a token for a user identified by a potential id. Returns None if the
-// user does not exists.
-pub fn generate_token<Conn>(conn : &Conn, user : MaybeUserId) -> Result<Token>
-where
-    Conn : Connection<Backend = Pg>,
-{
+// Generate a token for a user identified by a potential id, bound to one of
+// their machines. Returns an error if the user does not exists, or if the
+// machine belongs to someone else.
+#[instrument(level = "debug", skip(conn))]
+pub fn generate_token(
+    conn : &HubConnection,
+    user : MaybeUserId,
+    machine : MachineId,
+) -> Result<Token> {
     conn.transaction(|| {
         let id = user.validate(conn)?;
+        let machine =
+            crate::machines::validate_in_transaction(conn, id, machine)?;
 
-        Ok(generate_token_in_transaction(conn, id)?)
+        Ok(generate_token_in_transaction(conn, id, machine)?)
     })
 }
 
 // Generate a token for a user identified by an id whose existence has been
-// previously asserted. Needs to be called from within a transaction.
-pub fn generate_token_in_transaction<Conn>(
-    conn : &Conn,
+// previously asserted, bound to one of their machines. Needs to be called from
+// within a transaction.
+#[instrument(level = "debug", skip(conn))]
+pub fn generate_token_in_transaction(
+    conn : &HubConnection,
     id : UserId,
-) -> Result<Token>
-where
-    Conn : Connection<Backend = Pg>,
-{
+    machine : MachineId,
+) -> Result<Token> {
     let token = Uuid::new_v4().to_simple().to_string();
 
-    diesel::insert_into(tokens::table)
-        .values(vec![(tokens::user_id.eq(id.0), tokens::token.eq(&token))])
-        .execute(conn)?;
+    run!(conn, conn => diesel::insert_into(tokens::table)
+        .values(vec![(
+            tokens::user_id.eq(id.0),
+            tokens::machine_id.eq(machine.0),
+            tokens::token.eq(&token),
+        )])
+        .execute(conn))?;
 
     Ok(Token(token))
 }
 
 // Check whether or not a token is associated by a valid user. Needs to be
 // called from within a transaction.
-pub fn identify_user_by_token_in_transaction<Conn>(
-    conn : &Conn,
+#[instrument(level = "debug", skip(conn, token))]
+pub fn identify_user_by_token_in_transaction(
+    conn : &HubConnection,
     token : &Token,
-) -> Result<UserId>
-where
-    Conn : Connection<Backend = Pg>,
-{
-    let id = tokens::table
+) -> Result<UserId> {
+    let id = run!(conn, conn => tokens::table
         .select(tokens::user_id)
         .filter(tokens::token.eq(&token.0))
         .get_result::<i32>(conn)
-        .optional()?;
+        .optional())?;
 
     id.map(|x| UserId(x))
         .ok_or(KeyrHubstorageError::InvalidToken)
@@ -145,127 +165,284 @@ where
 
 // Check whether or not a token is associated by a valid user. User existence
 // needs to be asserted again prior to actually using it.
-pub fn identify_user_by_token<Conn>(
-    conn : &Conn,
+#[instrument(level = "debug", skip(conn, token))]
+pub fn identify_user_by_token(
+    conn : &HubConnection,
     token : &Token,
-) -> Result<MaybeUserId>
-where
-    Conn : Connection<Backend = Pg>,
-{
+) -> Result<MaybeUserId> {
     conn.transaction(|| {
         identify_user_by_token_in_transaction(conn, token)
             .map(|x| MaybeUserId(x.0))
     })
 }
 
-pub fn find_by_name_in_transaction<Conn>(
-    conn : &Conn,
+// Every user, along with their name. Needs to be called from within a
+// transaction.
+#[instrument(level = "debug", skip(conn))]
+pub fn list_in_transaction(
+    conn : &HubConnection,
+) -> Result<Vec<(UserId, String)>> {
+    let users = run!(conn, conn => users::table
+        .select((users::id, users::name))
+        .order(users::id.asc())
+        .load::<(i32, String)>(conn))?;
+
+    Ok(users
+        .into_iter()
+        .map(|(id, name)| (UserId(id), name))
+        .collect())
+}
+
+#[instrument(level = "debug", skip(conn))]
+pub fn find_by_name_in_transaction(
+    conn : &HubConnection,
     name : String,
-) -> Result<UserId>
-where
-    Conn : Connection<Backend = Pg>,
-{
-    let id = users::table
+) -> Result<UserId> {
+    let id = run!(conn, conn => users::table
         .select(users::id)
-        .filter(users::name.eq(name))
+        .filter(users::name.eq(&name))
         .get_result::<i32>(conn)
-        .optional()?
-        .ok_or(KeyrHubstorageError::UnknownUser)?;
+        .optional())?
+    .ok_or(KeyrHubstorageError::UnknownUser)?;
 
     Ok(UserId(id))
 }
 
-pub fn freeze_user_in_transaction<Conn>(conn : &Conn, id : UserId) -> Result<()>
-where
-    Conn : Connection<Backend = Pg>,
-{
-    diesel::update(users::table.find(id.0))
-        .set(users::frozen.eq(true))
-        .execute(conn)?;
+#[instrument(level = "debug", skip(conn))]
+pub fn is_visible_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+) -> Result<bool> {
+    let res = run!(conn, conn => users::table
+        .filter(users::id.eq(id.0))
+        .select(users::visible)
+        .get_result::<bool>(conn))?;
+
+    Ok(res)
+}
+
+#[instrument(level = "debug", skip(conn))]
+pub fn is_visible(conn : &HubConnection, id : MaybeUserId) -> Result<bool> {
+    conn.transaction(|| {
+        let id = id.validate(conn)?;
+
+        is_visible_in_transaction(conn, id)
+    })
+}
+
+// Choose whether or not the statistics of a user can be seen by anyone.
+// Needs to be called from within a transaction.
+#[instrument(level = "debug", skip(conn))]
+pub fn set_visible_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+    visible : bool,
+) -> Result<()> {
+    run!(conn, conn => diesel::update(users::table.find(id.0))
+        .set(users::visible.eq(visible))
+        .execute(conn))?;
 
     Ok(())
 }
 
-pub fn is_frozen_in_transaction<Conn>(conn : &Conn, id : UserId) -> Result<bool>
-where
-    Conn : Connection<Backend = Pg>,
-{
-    let res = users::table
+#[instrument(level = "debug", skip(conn))]
+pub fn set_visible(
+    conn : &HubConnection,
+    id : MaybeUserId,
+    visible : bool,
+) -> Result<()> {
+    conn.transaction(|| {
+        let id = id.validate(conn)?;
+
+        set_visible_in_transaction(conn, id, visible)
+    })
+}
+
+// Turn the `timezone' and `day_start' columns of a user into a calendar
+pub(crate) fn to_calendar(tz : &str, day_start : i32) -> Calendar {
+    Calendar {
+        // The timezones are checked before being saved, but could have been
+        // removed from the IANA database since
+        tz : tz.parse::<Tz>().unwrap_or(Tz::UTC),
+        day_start : day_start as u32,
+    }
+}
+
+// How a user splits time into days, weeks and months. Needs to be called
+// from within a transaction.
+#[instrument(level = "debug", skip(conn))]
+pub fn get_calendar_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+) -> Result<Calendar> {
+    let (tz, day_start) = run!(conn, conn => users::table
         .filter(users::id.eq(id.0))
-        .select(users::frozen)
-        .get_result::<bool>(conn)?;
+        .select((users::timezone, users::day_start))
+        .get_result::<(String, i32)>(conn))?;
 
-    Ok(res)
+    Ok(to_calendar(&tz, day_start))
 }
 
-pub fn is_frozen<Conn>(conn : &Conn, id : MaybeUserId) -> Result<bool>
-where
-    Conn : Connection<Backend = Pg>,
-{
+#[instrument(level = "debug", skip(conn))]
+pub fn get_calendar(
+    conn : &HubConnection,
+    id : MaybeUserId,
+) -> Result<Calendar> {
     conn.transaction(|| {
         let id = id.validate(conn)?;
 
-        is_frozen_in_transaction(conn, id)
+        get_calendar_in_transaction(conn, id)
     })
 }
 
-pub fn is_visible_in_transaction<Conn>(
-    conn : &Conn,
+// Choose how a user splits time into days, weeks and months. Needs to be
+// called from within a transaction.
+#[instrument(level = "debug", skip(conn))]
+pub fn set_calendar_in_transaction(
+    conn : &HubConnection,
     id : UserId,
-) -> Result<bool>
-where
-    Conn : Connection<Backend = Pg>,
-{
-    let res = users::table
-        .filter(users::id.eq(id.0))
-        .select(users::visible)
-        .get_result::<bool>(conn)?;
+    calendar : Calendar,
+) -> Result<()> {
+    run!(conn, conn => diesel::update(users::table.find(id.0))
+        .set((
+            users::timezone.eq(calendar.tz.name()),
+            users::day_start.eq(calendar.day_start as i32),
+        ))
+        .execute(conn))?;
 
-    Ok(res)
+    Ok(())
 }
 
-pub fn is_visible<Conn>(conn : &Conn, id : MaybeUserId) -> Result<bool>
-where
-    Conn : Connection<Backend = Pg>,
-{
+#[instrument(level = "debug", skip(conn))]
+pub fn set_calendar(
+    conn : &HubConnection,
+    id : MaybeUserId,
+    calendar : Calendar,
+) -> Result<()> {
     conn.transaction(|| {
         let id = id.validate(conn)?;
 
-        is_visible_in_transaction(conn, id)
+        set_calendar_in_transaction(conn, id, calendar)
+    })
+}
+
+// The number of characters of a token disclosed by an export
+const TOKEN_HINT_LENGTH : usize = 4;
+
+// The last characters of a token, which are enough for a user to recognize
+// it
+pub(crate) fn token_hint(token : &str) -> String {
+    let skip = token.chars().count().saturating_sub(TOKEN_HINT_LENGTH);
+
+    token.chars().skip(skip).collect()
+}
+
+// Everything the hub knows about a user, i.e., their profile, their machines,
+// their tokens (without the tokens themselves) and their statistics, hour by
+// hour and machine by machine. Needs to be called from within a transaction.
+#[instrument(level = "debug", skip(conn))]
+pub fn export_user_in_transaction(
+    conn : &HubConnection,
+    id : UserId,
+) -> Result<UserExport> {
+    let (name, visible, tz, day_start) = run!(conn, conn => users::table
+        .filter(users::id.eq(id.0))
+        .select((users::name, users::visible, users::timezone, users::day_start))
+        .get_result::<(String, bool, String, i32)>(conn))?;
+
+    let machines = run!(conn, conn => machines::table
+        .filter(machines::user_id.eq(id.0))
+        .select((machines::id, machines::name))
+        .order(machines::name.asc())
+        .load::<(i32, String)>(conn))?;
+
+    let tokens = run!(conn, conn => tokens::table
+        .filter(tokens::user_id.eq(id.0))
+        .select((tokens::token, tokens::machine_id))
+        .order(tokens::id.asc())
+        .load::<(String, i32)>(conn))?;
+
+    let statistics = run!(conn, conn => statistics::table
+        .filter(statistics::user_id.eq(id.0))
+        .select((statistics::timestamp, statistics::machine_id, statistics::count))
+        .order((statistics::timestamp.asc(), statistics::machine_id.asc()))
+        .load::<(NaiveDateTime, i32, i32)>(conn))?;
+
+    let names = machines.iter().cloned().collect::<HashMap<_, _>>();
+    let calendar = to_calendar(&tz, day_start);
+
+    Ok(UserExport {
+        name,
+        visible,
+        calendar : UserCalendar {
+            timezone : calendar.tz.name().to_owned(),
+            day_start : calendar.day_start,
+        },
+        machines : machines.into_iter().map(|(_, name)| name).collect(),
+        tokens : tokens
+            .into_iter()
+            .map(|(token, machine)| TokenMetadata {
+                machine : names[&machine].clone(),
+                hint : token_hint(&token),
+            })
+            .collect(),
+        statistics : statistics
+            .into_iter()
+            .map(|(timestamp, machine, count)| MachineKeystrokes {
+                timestamp : timestamp.timestamp(),
+                machine : names[&machine].clone(),
+                count : count as u64,
+            })
+            .collect(),
     })
 }
 
-pub fn freeze_user<Conn>(conn : &Conn, mid : MaybeUserId) -> Result<()>
-where
-    Conn : Connection<Backend = Pg>,
-{
+#[instrument(level = "debug", skip(conn))]
+pub fn export_user(
+    conn : &HubConnection,
+    id : MaybeUserId,
+) -> Result<UserExport> {
     conn.transaction(|| {
-        let id = mid.validate(conn)?;
+        let id = id.validate(conn)?;
 
-        freeze_user_in_transaction(conn, id)
+        export_user_in_transaction(conn, id)
     })
 }
 
-pub fn unfreeze_user_in_transaction<Conn>(
-    conn : &Conn,
+// Erase a user, along with their machines, their tokens, their statistics
+// and their revert session if they are being reverted. Needs to be called
+// from within a transaction.
+#[instrument(level = "debug", skip(conn))]
+pub fn delete_user_in_transaction(
+    conn : &HubConnection,
     id : UserId,
-) -> Result<()>
-where
-    Conn : Connection<Backend = Pg>,
-{
-    diesel::update(users::table.find(id.0))
-        .set(users::frozen.eq(false))
+) -> Result<()> {
+    run!(conn, conn => {
+        diesel::delete(
+            revert_sessions::table.filter(revert_sessions::user_id.eq(id.0)),
+        )
         .execute(conn)?;
 
+        diesel::delete(statistics::table.filter(statistics::user_id.eq(id.0)))
+            .execute(conn)?;
+
+        diesel::delete(tokens::table.filter(tokens::user_id.eq(id.0)))
+            .execute(conn)?;
+
+        diesel::delete(machines::table.filter(machines::user_id.eq(id.0)))
+            .execute(conn)?;
+
+        diesel::delete(users::table.find(id.0)).execute(conn)
+    })?;
+
     Ok(())
 }
 
-pub fn unfreeze_user<Conn>(conn : &Conn, mid : MaybeUserId) -> Result<()>
-where
-    Conn : Connection<Backend = Pg>,
-{
+#[instrument(level = "debug", skip(conn))]
+pub fn delete_user(conn : &HubConnection, id : MaybeUserId) -> Result<()> {
     conn.transaction(|| {
-        let id = mid.validate(conn)?;
-        unfreeze_user_in_transaction(conn, id)
+        let id = id.validate(conn)?;
+
+        delete_user_in_transaction(conn, id)
     })
 }
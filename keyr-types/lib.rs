@@ -1,21 +1,213 @@
 #[macro_use]
 extern crate serde_derive;
 
+use chrono::{DateTime, Duration, LocalResult, NaiveDateTime, TimeZone, Utc};
+
 use std::collections::HashMap;
 
 pub type Timestamp = i64;
 pub type KeystrokesStats = HashMap<Timestamp, u32>;
 
+// The hub computes the beginning of the day of the user with their calendar,
+// so the `today' field sent by the former agents is ignored.
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct SynchronizeRequest {
     pub staging_area : KeystrokesStats,
-    pub today : Timestamp,
+}
+
+// Convert a local date to UTC. Ambiguous dates (when clocks are turned back)
+// are resolved to their earliest occurrence, and non-existent dates (when
+// clocks are turned forward) are moved to the first valid instant after the
+// gap. Shared by the agent and the hub, so that they agree on when days
+// begin.
+pub fn to_utc<T : TimeZone>(tz : &T, date : NaiveDateTime) -> DateTime<Utc> {
+    let mut date = date;
+
+    loop {
+        match tz.from_local_datetime(&date) {
+            LocalResult::Single(res) | LocalResult::Ambiguous(res, _) => {
+                break res.with_timezone(&Utc)
+            }
+            LocalResult::None => date += Duration::minutes(15),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct MachineSummary {
+    pub name : String,
+    pub global_count : u64,
+    pub today_count : u64,
 }
 
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Summary {
     pub oldest_timestamp : Timestamp,
     pub global_count : u64,
     pub today_timestamp : Timestamp,
     pub today_count : u64,
+    #[serde(default)]
+    pub machines : Vec<MachineSummary>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct KeystrokesPoint {
+    pub timestamp : Timestamp,
+    pub count : u64,
+}
+
+pub type KeystrokesSeries = Vec<KeystrokesPoint>;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+    All,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct LeaderboardEntry {
+    pub rank : u64,
+    pub name : String,
+    pub count : u64,
+}
+
+pub type Leaderboard = Vec<LeaderboardEntry>;
+
+// How a user splits time: their days begin at `day_start' o'clock in
+// `timezone' (e.g., `Europe/Paris'), and their weeks and months begin with
+// their first day
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UserCalendar {
+    pub timezone : String,
+    #[serde(default)]
+    pub day_start : u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    Unavailable,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Liveness {
+    pub status : HealthStatus,
+    pub version : String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DatabaseCheck {
+    pub status : HealthStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error : Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct MigrationsCheck {
+    pub status : HealthStatus,
+    #[serde(default)]
+    pub pending : Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error : Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Readiness {
+    pub status : HealthStatus,
+    pub database : DatabaseCheck,
+    pub migrations : MigrationsCheck,
+}
+
+// The version of the hub API implemented by this crate, i.e., the prefix
+// (`/v1', etc.) of the routes the agent talks to. The second version hands
+// out revert sessions and paginates the statistics being reverted.
+pub const PROTOCOL_VERSION : u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct VersionInfo {
+    pub version : String,
+    pub protocols : Vec<u32>,
+}
+
+// The answer of the hub to an agent initiating a revert. The user is frozen
+// until the session is terminated, cancelled, or expires.
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RevertSession {
+    pub session : String,
+    pub expires_at : Timestamp,
+}
+
+// A page of the statistics of a user being reverted. `next' is the cursor to
+// fetch the following page with, and is missing on the last page. Fetching a
+// page pushes back the expiration of the session.
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RevertPage {
+    pub statistics : KeystrokesStats,
+    pub next : Option<Timestamp>,
+    pub expires_at : Timestamp,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RevertRequest {
+    pub session : String,
+}
+
+// A token of a user, bound to one of their machines. The token itself is not
+// disclosed, only its last characters so that it can be recognized.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TokenMetadata {
+    pub machine : String,
+    pub hint : String,
+}
+
+// The keystrokes count of an hour, on one machine
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct MachineKeystrokes {
+    pub timestamp : Timestamp,
+    pub machine : String,
+    pub count : u64,
+}
+
+// Everything the hub knows about a user
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UserExport {
+    pub name : String,
+    pub visible : bool,
+    pub calendar : UserCalendar,
+    pub machines : Vec<String>,
+    pub tokens : Vec<TokenMetadata>,
+    pub statistics : Vec<MachineKeystrokes>,
 }